@@ -201,8 +201,8 @@ def _command_columns() -> OrderedDict[str, str]:
             ("hash", "BIGINT UNSIGNED PRIMARY KEY"),
             ("unix_timestamp", "INTEGER UNSIGNED"),
             ("command_counter", "TINYINT UNSIGNED"),
-            ("response_sequence", "SMALLINT UNSIGNED"),
-            ("total_packets_in_response", "SMALLINT UNSIGNED"),
+            ("response_sequence", "INTEGER UNSIGNED"),
+            ("total_packets_in_response", "INTEGER UNSIGNED"),
             ("cmd", "MEDIUMBLOB"),
             ("status_code", "TINYINT UNSIGNED"),
             ("stdout", "MEDIUMBLOB"),
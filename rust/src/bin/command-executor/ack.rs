@@ -0,0 +1,195 @@
+use crate::logging::log_warning;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+ * Acknowledgements for --reliable replies.
+ *
+ * The client acknowledges reply packets by sending
+ *     ACK <n> <packet order> [<packet order> ...]
+ * to the executor's port, where n is the command counter
+ * from the trailer of the packets being acknowledged.
+ * Once a reply has been sent, any packets not acknowledged
+ * within --ack-timeout-ms are sent again, up to --max-retransmits times.
+ *
+ * The counter wraps after 256 commands, so replies are tracked
+ * under a key of their own; an ACK goes to the newest reply
+ * being sent with its counter.
+ * */
+
+/// Control message which acknowledges reply packets.
+pub const ACK_PREFIX: &[u8] = b"ACK ";
+
+/// Which packets of the replies being sent have been acknowledged.
+pub struct AckTracker {
+    timeout: Duration,
+    max_retransmits: u32,
+    // Keyed by `next_key` when the reply was expected,
+    // along with its command counter
+    acked: Mutex<HashMap<u64, (u8, HashSet<u32>)>>,
+    next_key: AtomicU64,
+    changed: Condvar,
+}
+
+impl AckTracker {
+    pub fn new(timeout: Duration, max_retransmits: u32) -> AckTracker {
+        AckTracker {
+            timeout,
+            max_retransmits,
+            acked: Mutex::new(HashMap::new()),
+            next_key: AtomicU64::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Start tracking acknowledgements for the reply to a command.
+    /// Call before its first packet goes out, so no ACK is missed.
+    /// Returns the key to wait for its acknowledgements with.
+    pub fn expect(&self, num_cmds_received: u8) -> u64 {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(key, (num_cmds_received, HashSet::new()));
+        key
+    }
+
+    /// Record the ACK in `msg` (with the prefix stripped off).
+    /// Returns false if it is malformed or for a reply we aren't sending.
+    pub fn record(&self, msg: &[u8]) -> bool {
+        let Some((cmd, packets)) = parse_ack(msg) else {
+            return false;
+        };
+        let mut acked = self.lock();
+        let newest = acked
+            .iter_mut()
+            .filter(|(_, (counter, _))| *counter == cmd)
+            .max_by_key(|(key, _)| **key);
+        let Some((_, (_, set))) = newest else {
+            return false;
+        };
+        set.extend(packets);
+        self.changed.notify_all();
+        true
+    }
+
+    /// Wait for every one of `packets` (already sent once) to be acknowledged,
+    /// passing any which aren't to `resend` after each timeout.
+    /// Returns whether they all were; either way, the reply stops being tracked.
+    pub fn wait_for_acks(
+        &self,
+        key: u64,
+        packets: &[Vec<u8>],
+        mut resend: impl FnMut(&[u8]),
+    ) -> bool {
+        let mut retransmits = 0;
+        let all_acked = loop {
+            let missing = self.wait_for_missing(key, packets.len());
+            if missing.is_empty() {
+                break true;
+            }
+            if retransmits == self.max_retransmits {
+                let num_cmds_received = self.lock().get(&key).map_or(0, |(counter, _)| *counter);
+                log_warning!(
+                    "Giving up on reply to command #{num_cmds_received}: {} of {} packets not acknowledged after {retransmits} retransmits",
+                    missing.len(),
+                    packets.len()
+                );
+                break false;
+            }
+            retransmits += 1;
+            for i in missing {
+                resend(&packets[i]);
+            }
+        };
+        self.lock().remove(&key);
+        all_acked
+    }
+
+    /// Wait up to the ACK timeout for the first `total` packets of a reply
+    /// to be acknowledged. Returns the indices of those which weren't.
+    fn wait_for_missing(&self, key: u64, total: usize) -> Vec<usize> {
+        let deadline = Instant::now() + self.timeout;
+        let mut acked = self.lock();
+        loop {
+            let missing: Vec<usize> = (0..total)
+                .filter(|&i| {
+                    !acked
+                        .get(&key)
+                        .is_some_and(|(_, set)| set.contains(&(i as u32)))
+                })
+                .collect();
+            let now = Instant::now();
+            if missing.is_empty() || now >= deadline {
+                return missing;
+            }
+            acked = self
+                .changed
+                .wait_timeout(acked, deadline - now)
+                .expect("ack lock should not be poisoned")
+                .0;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, (u8, HashSet<u32>)>> {
+        self.acked.lock().expect("ack lock should not be poisoned")
+    }
+}
+
+/// Split `<n> <packet order> [<packet order> ...]` into its numbers.
+fn parse_ack(msg: &[u8]) -> Option<(u8, Vec<u32>)> {
+    let mut fields = std::str::from_utf8(msg).ok()?.split_ascii_whitespace();
+    let cmd = fields.next()?.parse().ok()?;
+    let packets = fields
+        .map(|f| f.parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    Some((cmd, packets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unacknowledged_packets_are_resent_until_acked() {
+        let tracker = AckTracker::new(Duration::from_millis(10), 3);
+        let key = tracker.expect(7);
+        assert!(tracker.record(b"7 0 2\n"));
+        assert!(!tracker.record(b"8 1"), "not a reply being sent");
+        assert!(!tracker.record(b"7 one"), "malformed");
+
+        let packets = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        let mut resent = Vec::new();
+        let all_acked = tracker.wait_for_acks(key, &packets, |p| {
+            resent.push(p.to_vec());
+            // The client gets the retransmitted packet this time
+            tracker.record(b"7 1");
+        });
+        assert!(all_acked);
+        assert_eq!(resent, vec![b"b".to_vec()]);
+        assert!(!tracker.record(b"7 1"), "no longer tracked once acked");
+    }
+
+    #[test]
+    fn gives_up_after_max_retransmits() {
+        let tracker = AckTracker::new(Duration::from_millis(1), 2);
+        let key = tracker.expect(0);
+        let packets = vec![b"a".to_vec(), b"b".to_vec()];
+        let mut resends = 0;
+        assert!(!tracker.wait_for_acks(key, &packets, |_| resends += 1));
+        assert_eq!(resends, 4);
+    }
+
+    #[test]
+    fn replies_with_a_wrapped_counter_are_tracked_apart() {
+        let tracker = AckTracker::new(Duration::from_millis(1), 0);
+        let old = tracker.expect(5);
+        let new = tracker.expect(5);
+        // The ACK goes to the newest reply with that counter
+        assert!(tracker.record(b"5 0"));
+        assert!(tracker.wait_for_acks(new, &[b"a".to_vec()], |_| {}));
+        // Finishing one reply leaves the other tracked
+        assert!(tracker.record(b"5 0"));
+        assert!(tracker.wait_for_acks(old, &[b"a".to_vec()], |_| {}));
+        assert!(!tracker.record(b"5 0"));
+    }
+}
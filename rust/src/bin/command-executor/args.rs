@@ -0,0 +1,509 @@
+use crate::framing::Framing;
+use crate::output::ReplyFormat;
+use crate::rate_limit::Rate;
+use crate::semaphore::WhenBusy;
+use crate::user::User;
+use clap::Parser;
+use impish_executables::cidr::Cidr;
+use impish_executables::config::{self, Settings};
+use impish_executables::merge_settings;
+use serde::Deserialize;
+use serde::de::{Deserializer, Error as _};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+/*
+ * Command line args for command-executor.
+ * Every option has a default so that the executor
+ * can be started with no arguments at all.
+ * */
+
+/// Port the executor listens on when neither `--port`
+/// nor `COMMAND_EXECUTOR_PORT` is given.
+pub const DEFAULT_PORT: u16 = 35000;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Receive Linux commands over UDP, execute them, and send back the output.",
+    long_about = None
+)]
+pub struct ProgramArgs {
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Read settings from a TOML file of `option_name = value` lines; options given on the command line or in the environment win"
+    )]
+    pub config: Option<PathBuf>,
+
+    #[arg(
+        short = 'p',
+        long,
+        env = "COMMAND_EXECUTOR_PORT",
+        default_value_t = DEFAULT_PORT,
+        help = "UDP port to listen on for commands"
+    )]
+    pub port: u16,
+
+    #[arg(
+        long,
+        default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        help = "Address to listen on; `::` takes IPv4 and IPv6"
+    )]
+    pub bind: IpAddr,
+
+    #[arg(
+        long,
+        env = "COMMAND_EXECUTOR_AUTH_TOKEN",
+        hide_env_values = true,
+        help = "Shared secret; when set, commands must begin with the line `AUTH <token>`"
+    )]
+    pub auth_token: Option<String>,
+
+    #[arg(
+        long,
+        help = "Commands may span several datagrams, each prefixed by (u8 sequence) + (u8 final flag)"
+    )]
+    pub multipart: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Framing::Newline,
+        help = "What ends the AUTH and REQ-ID header lines of a command; with nul, the command after them is taken verbatim"
+    )]
+    pub framing: Framing,
+
+    #[arg(
+        long,
+        default_value_t = 5,
+        requires = "multipart",
+        help = "Seconds to wait for the rest of a multipart command before dropping it"
+    )]
+    pub reassembly_timeout: u64,
+
+    #[arg(
+        long,
+        requires = "multipart",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Seconds a multipart sender may go quiet before its partial command is dropped [default: no limit]"
+    )]
+    pub recv_timeout: Option<u64>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ReplyFormat::Delimited,
+        help = "How the status code, command, stdout, and stderr are laid out in the reply"
+    )]
+    pub format: ReplyFormat,
+
+    #[arg(
+        long,
+        help = "Truncate stdout and stderr to this many bytes each before replying [default: no limit]"
+    )]
+    pub max_reply_bytes: Option<usize>,
+
+    #[arg(
+        long,
+        requires = "max_reply_bytes",
+        help = "Apply --max-reply-bytes to stdout and stderr together rather than to each"
+    )]
+    pub combined_reply_limit: bool,
+
+    #[arg(
+        long,
+        help = "Gzip replies of at least --compress-min-bytes, when that makes them smaller"
+    )]
+    pub compress: bool,
+
+    #[arg(
+        long,
+        default_value_t = 1024,
+        requires = "compress",
+        help = "Smallest reply, in bytes, worth compressing"
+    )]
+    pub compress_min_bytes: usize,
+
+    #[arg(
+        long,
+        help = "Run commands directly as a NUL- or newline-separated argv array instead of in a shell"
+    )]
+    pub no_shell: bool,
+
+    #[arg(
+        long,
+        default_value = "bash",
+        conflicts_with = "no_shell",
+        help = "Shell to run commands in"
+    )]
+    pub shell: PathBuf,
+
+    #[arg(
+        long,
+        default_value = "-ls",
+        allow_hyphen_values = true,
+        conflicts_with = "no_shell",
+        help = "Whitespace-separated arguments for --shell; if the last is -c, the command is passed after it rather than on stdin"
+    )]
+    pub shell_args: String,
+
+    #[arg(
+        long,
+        help = "Reply with what would be run, and the command as received, instead of running it"
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        long,
+        help = "Send stdout and stderr back as they are produced rather than once the command exits"
+    )]
+    pub stream: bool,
+
+    #[arg(
+        long,
+        requires = "stream",
+        help = "With --stream, send each line of output in a packet of its own, numbered"
+    )]
+    pub line_mode: bool,
+
+    #[arg(
+        long,
+        help = "Send stderr to the same pipe as stdout, so the reply has one stream in the order it was written"
+    )]
+    pub combined: bool,
+
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Maximum number of commands to run at once; more wait their turn. The default runs them one at a time, in the order received"
+    )]
+    pub max_concurrent: u16,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = WhenBusy::Queue,
+        help = "What to do with a command received while --max-concurrent are running"
+    )]
+    pub when_busy: WhenBusy,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 64,
+        help = "With --when-busy queue, most commands waiting for a slot; more get a `server busy` reply"
+    )]
+    pub max_queued: u16,
+
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "On SIGTERM or SIGINT, seconds to let running commands finish before killing them"
+    )]
+    pub shutdown_timeout: u64,
+
+    #[arg(
+        long,
+        help = "Only accept commands from this address or CIDR block (IPv4 or IPv6); may be repeated [default: accept all]"
+    )]
+    pub allow: Vec<Cidr>,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Only run commands which match this extended regular expression as a whole; may be repeated [default: run any]"
+    )]
+    pub allow_pattern: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Never run commands which match this extended regular expression as a whole; may be repeated"
+    )]
+    pub deny_pattern: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "N/SECONDS",
+        help = "Refuse commands from a source IP which has already sent N in the last SECONDS, e.g. 10/60 [default: no limit]"
+    )]
+    pub rate_limit: Option<Rate>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Append a JSON line recording each command run (when, from where, what, status, and how long) to FILE"
+    )]
+    pub audit_log: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "audit_log",
+        help = "Record the SHA-256 of each command in the audit log rather than the command itself"
+    )]
+    pub audit_hash_only: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Also write each command's full stdout, stderr, and status code to a new file in this directory"
+    )]
+    pub tee_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Log counts of commands received, succeeded, failed, and refused, and bytes replied, this often [default: only on SIGUSR1 and exit]"
+    )]
+    pub stats_interval: Option<u64>,
+
+    #[cfg(feature = "metrics")]
+    #[arg(
+        long,
+        value_name = "ADDR:PORT",
+        help = "Serve the stats over HTTP at this address, for Prometheus to scrape"
+    )]
+    pub metrics_addr: Option<std::net::SocketAddr>,
+
+    #[arg(
+        long,
+        help = "Directory to run commands in [default: the executor's working directory]"
+    )]
+    pub workdir: Option<PathBuf>,
+
+    #[arg(
+        long = "env",
+        value_name = "KEY=VALUE",
+        value_parser = parse_env_var,
+        help = "Set an environment variable for commands, overriding any inherited value; may be repeated"
+    )]
+    pub env: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        help = "Start commands with an empty environment, apart from --env"
+    )]
+    pub env_clear: bool,
+
+    #[arg(
+        long,
+        value_name = "USER",
+        help = "Run commands as this user (name or uid) instead of the executor's own user"
+    )]
+    pub run_as: Option<User>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Limit each command's virtual memory (RLIMIT_AS) [default: no limit]"
+    )]
+    pub limit_as: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Limit each command's CPU time (RLIMIT_CPU) [default: no limit]"
+    )]
+    pub limit_cpu: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Limit the processes the commands' user may have (RLIMIT_NPROC) [default: no limit]"
+    )]
+    pub limit_nproc: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Resend reply packets until the client acknowledges them with `ACK <n> <packet order>...`"
+    )]
+    pub reliable: bool,
+
+    #[arg(
+        long,
+        help = "End each reply packet with a CRC-32 of it, and a streamed reply's `finished` packet with one of the whole reply"
+    )]
+    pub checksum: bool,
+
+    #[arg(
+        long,
+        default_value_t = 500,
+        requires = "reliable",
+        help = "Milliseconds to wait for acknowledgements before resending reply packets"
+    )]
+    pub ack_timeout_ms: u64,
+
+    #[arg(
+        long,
+        default_value_t = 5,
+        requires = "reliable",
+        help = "Times to resend unacknowledged reply packets before giving up on them"
+    )]
+    pub max_retransmits: u32,
+}
+
+impl ProgramArgs {
+    /// The smallest reply to compress, or None if replies aren't compressed.
+    pub fn compress_min(&self) -> Option<usize> {
+        self.compress.then_some(self.compress_min_bytes)
+    }
+}
+
+/// Split a `KEY=VALUE` pair at the first `=`.
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some(("", _)) => Err(format!("no variable name before `=` in `{s}`")),
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => Err(format!("expected KEY=VALUE, not `{s}`")),
+    }
+}
+
+/// What a --config file may set: any option but --config itself.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSettings {
+    port: Option<u16>,
+    bind: Option<IpAddr>,
+    auth_token: Option<String>,
+    multipart: Option<bool>,
+    #[serde(deserialize_with = "config::value_enum")]
+    framing: Option<Framing>,
+    reassembly_timeout: Option<u64>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    recv_timeout: Option<u64>,
+    #[serde(deserialize_with = "config::value_enum")]
+    format: Option<ReplyFormat>,
+    max_reply_bytes: Option<usize>,
+    combined_reply_limit: Option<bool>,
+    compress: Option<bool>,
+    compress_min_bytes: Option<usize>,
+    no_shell: Option<bool>,
+    shell: Option<PathBuf>,
+    shell_args: Option<String>,
+    dry_run: Option<bool>,
+    stream: Option<bool>,
+    line_mode: Option<bool>,
+    combined: Option<bool>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    max_concurrent: Option<u16>,
+    #[serde(deserialize_with = "config::value_enum")]
+    when_busy: Option<WhenBusy>,
+    max_queued: Option<u16>,
+    shutdown_timeout: Option<u64>,
+    #[serde(deserialize_with = "config::from_strs")]
+    allow: Option<Vec<Cidr>>,
+    allow_pattern: Option<Vec<String>>,
+    deny_pattern: Option<Vec<String>>,
+    #[serde(deserialize_with = "config::from_str")]
+    rate_limit: Option<Rate>,
+    audit_log: Option<PathBuf>,
+    audit_hash_only: Option<bool>,
+    tee_dir: Option<PathBuf>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    stats_interval: Option<u64>,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<std::net::SocketAddr>,
+    workdir: Option<PathBuf>,
+    #[serde(deserialize_with = "env_setting")]
+    env: Option<Vec<(String, String)>>,
+    env_clear: Option<bool>,
+    #[serde(deserialize_with = "config::from_str")]
+    run_as: Option<User>,
+    limit_as: Option<u64>,
+    limit_cpu: Option<u64>,
+    limit_nproc: Option<u64>,
+    reliable: Option<bool>,
+    checksum: Option<bool>,
+    ack_timeout_ms: Option<u64>,
+    max_retransmits: Option<u32>,
+}
+
+impl Settings for ProgramArgs {
+    type File = FileSettings;
+
+    fn config(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+
+    fn merge(&mut self, file: FileSettings, given: &dyn Fn(&str) -> bool) -> Vec<&'static str> {
+        merge_settings!(self, file, given;
+            port, bind, auth_token, multipart, framing, reassembly_timeout, recv_timeout,
+            format, max_reply_bytes, combined_reply_limit, compress, compress_min_bytes,
+            no_shell, shell, shell_args, dry_run, stream, line_mode, combined,
+            max_concurrent, when_busy, max_queued, shutdown_timeout, allow, allow_pattern,
+            deny_pattern, rate_limit, audit_log, audit_hash_only, tee_dir, stats_interval,
+            #[cfg(feature = "metrics")] metrics_addr, workdir, env, env_clear, run_as,
+            limit_as, limit_cpu, limit_nproc, reliable, checksum, ack_timeout_ms,
+            max_retransmits,
+        )
+    }
+}
+
+/// `--env` in a --config file: an array of `KEY=VALUE` strings.
+fn env_setting<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<(String, String)>>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| parse_env_var(s).map_err(D::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+    use std::ffi::OsString;
+
+    fn parse_with_file(config: &str, cli: &[&str]) -> Result<ProgramArgs, clap::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "command-executor-config-test-{}-{}.toml",
+            std::process::id(),
+            config.len()
+        ));
+        std::fs::write(&path, config).unwrap();
+        let mut args: Vec<OsString> = vec!["command-executor".into(), "--config".into()];
+        args.push(path.clone().into());
+        args.extend(cli.iter().map(OsString::from));
+        let parsed = config::parse_with_config_from(args);
+        std::fs::remove_file(&path).unwrap();
+        parsed
+    }
+
+    #[test]
+    fn file_settings_fill_in_options() {
+        let args = parse_with_file(
+            "port = 35001\n\
+             shell = 'sh'\n\
+             shell_args = '-c'\n\
+             allow = ['10.0.0.0/8']\n\
+             env = ['A=b=c']\n\
+             when_busy = 'reject'\n\
+             multipart = true\n",
+            &["--max-concurrent=2"],
+        )
+        .unwrap();
+        assert_eq!(args.port, 35001);
+        assert_eq!(args.shell, PathBuf::from("sh"));
+        assert_eq!(args.shell_args, "-c");
+        assert!(args.allow[0].contains("10.1.2.3".parse().unwrap()));
+        assert_eq!(args.env, [("A".to_string(), "b=c".to_string())]);
+        assert!(matches!(args.when_busy, WhenBusy::Reject));
+        assert!(args.multipart);
+        assert_eq!(args.max_concurrent, 2);
+    }
+
+    #[test]
+    fn conflicts_with_the_file_are_refused() {
+        let e = parse_with_file("shell = 'sh'", &["--no-shell"])
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::ArgumentConflict);
+        let e = parse_with_file("line_mode = true", &[]).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::MissingRequiredArgument);
+        let e = parse_with_file("env = ['=x']", &[]).err().unwrap();
+        assert!(e.to_string().contains("no variable name"), "{e}");
+    }
+}
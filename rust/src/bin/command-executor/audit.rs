@@ -0,0 +1,162 @@
+use crate::logging::log_error;
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/*
+ * The --audit-log: a record of every command run, one JSON object per line:
+ *     {"time": "2024-04-09T12:00:00.123Z", "source": "10.0.0.5:40000",
+ *      "request_id": "...", "command": "...", "code": 0, "duration_secs": 0.012}
+ * `request_id` is only there if the client sent one.
+ * With --audit-hash-only, `command` is replaced by `command_sha256`,
+ * the hex SHA-256 of the command, so the log doesn't hold its text.
+ * Lines are only ever appended, and each is synced to disk
+ * before the next, so a crash loses at most the entry being written.
+ * */
+
+pub struct AuditLog {
+    file: Mutex<File>,
+    hash_only: bool,
+}
+
+/// What is recorded about a command which was run.
+pub struct Entry<'a> {
+    pub source: SocketAddr,
+    pub request_id: Option<&'a [u8]>,
+    pub cmd: &'a [u8],
+    pub status_code: i32,
+    pub duration: Duration,
+}
+
+/// An entry as it is written to the log.
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    source: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<Cow<'a, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<Cow<'a, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_sha256: Option<String>,
+    code: i32,
+    duration_secs: f64,
+}
+
+impl AuditLog {
+    /// Open the log for appending, creating it if need be.
+    pub fn open(path: &Path, hash_only: bool) -> std::io::Result<AuditLog> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+            hash_only,
+        })
+    }
+
+    /// Append an entry. Failing to is logged, but doesn't stop the reply.
+    pub fn record(&self, entry: &Entry) {
+        let line = self.line(
+            entry,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        );
+        let mut file = self
+            .file
+            .lock()
+            .expect("audit log lock should not be poisoned");
+        // One write per line keeps lines whole if another process appends too
+        let result = file
+            .write_all(line.as_bytes())
+            .and_then(|()| file.sync_data());
+        if let Err(e) = result {
+            log_error!("Cannot write to the audit log: {e}");
+        }
+    }
+
+    fn line(&self, entry: &Entry, time: String) -> String {
+        let command = String::from_utf8_lossy(entry.cmd);
+        let line = Line {
+            time,
+            source: entry.source,
+            request_id: entry.request_id.map(String::from_utf8_lossy),
+            command: (!self.hash_only).then_some(command),
+            command_sha256: self.hash_only.then(|| sha256_hex(entry.cmd)),
+            code: entry.status_code,
+            // To the millisecond
+            duration_secs: entry.duration.as_millis() as f64 / 1000.0,
+        };
+        let mut line = serde_json::to_string(&line).expect("audit entries serialize");
+        line.push('\n');
+        line
+    }
+}
+
+/// The SHA-256 of `data`, as lowercase hex like `sha256sum` prints.
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_json_lines() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let entry = Entry {
+            source: "10.0.0.5:40000".parse().unwrap(),
+            request_id: Some(b"abc-1"),
+            cmd: b"echo \"hi\"\n",
+            status_code: 0,
+            duration: Duration::from_millis(12),
+        };
+        let log = AuditLog::open(&path, false).unwrap();
+        assert_eq!(
+            log.line(&entry, "T".to_string()),
+            "{\"time\":\"T\",\"source\":\"10.0.0.5:40000\",\"request_id\":\"abc-1\",\"command\":\"echo \\\"hi\\\"\\n\",\"code\":0,\"duration_secs\":0.012}\n"
+        );
+        log.record(&entry);
+        log.record(&entry);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        let hashed = AuditLog {
+            hash_only: true,
+            ..log
+        };
+        let line = hashed.line(
+            &Entry {
+                request_id: None,
+                ..entry
+            },
+            "T".to_string(),
+        );
+        assert!(line.contains(&format!(
+            "\"command_sha256\":\"{}\"",
+            sha256_hex(b"echo \"hi\"\n")
+        )));
+        assert!(!line.contains("echo"));
+    }
+
+    #[test]
+    fn sha256_matches_known_digests() {
+        // From FIPS 180-4's examples
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
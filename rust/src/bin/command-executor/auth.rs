@@ -0,0 +1,36 @@
+/*
+ * Shared-secret authentication for incoming commands.
+ *
+ * When a token is configured, the first line of every datagram
+ * must be `AUTH <token>` (ended by a NUL with --framing nul).
+ * That line is stripped off and the remainder of the datagram
+ * is the command to run.
+ * */
+use crate::framing::Framing;
+
+const AUTH_PREFIX: &[u8] = b"AUTH ";
+
+/// Check the `AUTH <token>` line at the start of `datagram`.
+/// Returns the command which follows the auth line if the token matches,
+/// or None if the line is missing or the token is wrong.
+pub fn authenticate(datagram: &[u8], token: &str, framing: Framing) -> Option<Vec<u8>> {
+    let (auth_line, cmd) = framing.split_field(datagram);
+    let given = auth_line.strip_prefix(AUTH_PREFIX)?;
+    if !constant_time_eq(given, token.as_bytes()) {
+        return None;
+    }
+    Some(cmd.to_vec())
+}
+
+/// Compare two byte strings without returning early on the first
+/// mismatch, so the time taken doesn't leak how much of the token
+/// was guessed correctly.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..std::cmp::max(a.len(), b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= (x ^ y) as usize;
+    }
+    diff == 0
+}
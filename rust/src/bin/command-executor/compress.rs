@@ -0,0 +1,74 @@
+use flate2::write::GzEncoder;
+use std::io::Write;
+
+/*
+ * Compressing replies (--compress).
+ * A compressed response is sent as
+ *     GZIP <n>\n(n bytes of gzip data)
+ * in place of the response itself; the client inflates it
+ * to get the response as `OutputWrapper::to_packet` laid it out.
+ * */
+
+const HEADER_PREFIX: &str = "GZIP ";
+
+/// Compress a response if it is at least `min_bytes` long
+/// and compressing actually makes it smaller.
+/// Returns None if the response should be sent as it is.
+pub fn compress_response(res_bytes: &[u8], min_bytes: usize) -> std::io::Result<Option<Vec<u8>>> {
+    if res_bytes.len() < min_bytes {
+        return Ok(None);
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(res_bytes)?;
+    let gzipped = encoder.finish()?;
+    let mut compressed = format!("{HEADER_PREFIX}{}\n", gzipped.len()).into_bytes();
+    compressed.extend(gzipped);
+    Ok((compressed.len() < res_bytes.len()).then_some(compressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn compressible_response_round_trips() {
+        let response: Vec<u8> = b"0\x1dls -lR /\x1d"
+            .iter()
+            .copied()
+            .chain(b"-rw-r--r-- 1 root root 4096 file\n".repeat(1000))
+            .collect();
+        let compressed = compress_response(&response, 1024)
+            .unwrap()
+            .expect("repetitive output should compress");
+        assert!(compressed.len() < response.len() / 10);
+
+        let line_end = compressed.iter().position(|b| *b == b'\n').unwrap();
+        let header = std::str::from_utf8(&compressed[..line_end]).unwrap();
+        let len: usize = header.strip_prefix(HEADER_PREFIX).unwrap().parse().unwrap();
+        let gzipped = &compressed[line_end + 1..];
+        assert_eq!(gzipped.len(), len);
+        let mut inflated = Vec::new();
+        flate2::read::GzDecoder::new(gzipped)
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, response);
+    }
+
+    #[test]
+    fn small_or_incompressible_responses_are_left_alone() {
+        assert_eq!(compress_response(b"0\x1dtrue\x1d\x1d", 1024).unwrap(), None);
+        // Already-random bytes get bigger when gzipped
+        let mut x: u32 = 2463534242;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                // xorshift32
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        assert_eq!(compress_response(&noise, 16).unwrap(), None);
+    }
+}
@@ -0,0 +1,456 @@
+use crate::args::ProgramArgs;
+use crate::logging::log_warning;
+use crate::output::{self, OutputWrapper};
+use crate::reply::{LINE_DATA_SIZE, STREAM_DATA_SIZE, StreamId, Streamer};
+use crate::sys;
+use std::collections::HashMap;
+use std::ffi::{OsStr, c_int};
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread::JoinHandle;
+// Impl's needed for writing onto stdio of process
+use std::io::Write;
+
+/// Execute a command given as a string as a subprocess.
+/// Its stdout and stderr are captured separately.
+///
+/// Normally the command runs in a shell:
+/// by default the shell is invoked as `bash -l -s` and the
+/// command is piped to its stdin (see `shell_command` for others).
+/// In this way, typical shell syntax and nicities
+/// like loops, redirection, and pipes may be used.
+///
+/// With `--no-shell` the command is instead split into
+/// an argv array (see `split_argv`) and run directly.
+///
+/// The command is tracked in `running` under the client's `request_id`
+/// until it exits, so that it can be cancelled.
+///
+/// With `--dry-run` nothing is run; see `dry_run`.
+pub fn execute(
+    cmd: &[u8],
+    args: &ProgramArgs,
+    running: &RunningCommands,
+    request_id: Option<&[u8]>,
+) -> std::io::Result<OutputWrapper> {
+    if args.dry_run {
+        return dry_run(cmd, args);
+    }
+    let command = spawn(cmd, args)?;
+    let _tracked = running.track(request_id, &command);
+    let out = command.wait_with_output()?;
+    Ok(OutputWrapper::from(cmd.to_vec(), out))
+}
+
+/// Execute a command like `execute`, but send its stdout and stderr
+/// off through the streamer as soon as they are read,
+/// rather than waiting for the command to finish.
+/// Each pipe is read on its own thread so neither one
+/// can fill up and block the command while we wait on the other.
+/// With `--line-mode`, output is sent a line at a time (see `read_lines`).
+/// Returns the status code of the command.
+pub fn execute_streaming(
+    cmd: &[u8],
+    args: &ProgramArgs,
+    running: &RunningCommands,
+    request_id: Option<&[u8]>,
+    streamer: &mut Streamer,
+) -> std::io::Result<i32> {
+    if args.dry_run {
+        let out = dry_run(cmd, args)?;
+        if args.line_mode {
+            streamer.send_lines(StreamId::Stdout, &out.stdout);
+        } else {
+            streamer.send(StreamId::Stdout, &out.stdout);
+        }
+        return Ok(out.status_code);
+    }
+    let mut command = spawn(cmd, args)?;
+    let _tracked = running.track(request_id, &command);
+    let (tx, rx) = mpsc::channel();
+    let read = if args.line_mode {
+        read_lines
+    } else {
+        read_pipe
+    };
+    let mut readers = Vec::new();
+    if let Some(stdout) = command.stdout.take() {
+        readers.push(read(Box::new(stdout), StreamId::Stdout, tx.clone()));
+    }
+    if let Some(stderr) = command.stderr.take() {
+        readers.push(read(Box::new(stderr), StreamId::Stderr, tx.clone()));
+    }
+    // Only the readers hold senders now, so the loop below
+    // ends once both pipes are closed.
+    drop(tx);
+
+    for (stream, data) in rx {
+        if args.line_mode {
+            streamer.send_lines(stream, &data);
+        } else {
+            streamer.send(stream, &data);
+        }
+    }
+    for r in readers {
+        let _ = r.join();
+    }
+    let status = command.wait()?;
+    Ok(output::status_code(status))
+}
+
+/// A child's stdout or stderr, read on a thread of its own.
+type Pipe = Box<dyn Read + Send>;
+
+/// Forward everything read from a pipe to the channel, tagged with its stream.
+fn read_pipe(mut pipe: Pipe, stream: StreamId, tx: Sender<(StreamId, Vec<u8>)>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buf = [0u8; STREAM_DATA_SIZE];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if tx.send((stream, buf[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    })
+}
+
+/// Forward each line read from a pipe to the channel, tagged with its stream,
+/// newline and all.
+/// A line is only held until `LINE_DATA_SIZE` bytes of it are read,
+/// so output without newlines is still sent, in pieces of that size.
+fn read_lines(pipe: Pipe, stream: StreamId, tx: Sender<(StreamId, Vec<u8>)>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut pipe = BufReader::new(pipe);
+        loop {
+            let mut line = Vec::with_capacity(LINE_DATA_SIZE);
+            // read_until retries reads which were interrupted
+            match (&mut pipe)
+                .take(LINE_DATA_SIZE as u64)
+                .read_until(b'\n', &mut line)
+            {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if tx.send((stream, line)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// With `--dry-run`: a reply whose stdout says what would have been run,
+/// ending with the exact bytes of the command, with status 0.
+fn dry_run(cmd: &[u8], args: &ProgramArgs) -> std::io::Result<OutputWrapper> {
+    let command = build(cmd, args)?;
+    let mut out = Vec::new();
+    writeln!(out, "program: {:?}", command.get_program())?;
+    writeln!(out, "args: {:?}", command.get_args().collect::<Vec<_>>())?;
+    let on_stdin = !args.no_shell && !shell_takes_command_arg(args);
+    writeln!(
+        out,
+        "command on stdin: {}",
+        if on_stdin { "yes" } else { "no" }
+    )?;
+    if let Some(dir) = command.get_current_dir() {
+        writeln!(out, "workdir: {dir:?}")?;
+    }
+    if let Some(user) = &args.run_as {
+        writeln!(
+            out,
+            "user: {} (uid {}, gid {})",
+            user.name, user.uid, user.gid
+        )?;
+    }
+    if args.env_clear {
+        writeln!(out, "env: cleared")?;
+    }
+    for (key, value) in command.get_envs() {
+        writeln!(out, "env: {key:?}={:?}", value.unwrap_or_default())?;
+    }
+    for (resource, value) in resource_limits(args) {
+        writeln!(out, "limit: {} = {value}", sys::limit_name(resource))?;
+    }
+    writeln!(out, "command ({} bytes):", cmd.len())?;
+    out.extend_from_slice(cmd);
+    Ok(OutputWrapper::from_message(cmd.to_vec(), 0, &out, b""))
+}
+
+/// Start the command with its stdout and stderr piped back to us.
+fn spawn(cmd: &[u8], args: &ProgramArgs) -> std::io::Result<Child> {
+    let mut command = build(cmd, args)?;
+    // With --combined both streams share one pipe, so their output
+    // stays in the order it was written and all of it lands on stdout.
+    let combined = if args.combined {
+        let (reader, writer) = std::io::pipe()?;
+        command.stdout(writer.try_clone()?).stderr(writer);
+        Some(reader)
+    } else {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        None
+    };
+    // Put the command in its own process group so that
+    // cancelling it also kills anything it started.
+    let mut child = command.process_group(0).spawn()?;
+    // Our copies of the write end go with `command`,
+    // so the reader sees EOF once the child is done.
+    drop(command);
+    if let Some(reader) = combined {
+        child.stdout = Some(ChildStdout::from(OwnedFd::from(reader)));
+    }
+    if let Some(stdin) = child.stdin.take() {
+        write_stdin(stdin, cmd.to_vec(), child.id());
+    }
+    Ok(child)
+}
+
+/// Write the command to the shell's stdin on a thread of its own,
+/// then close it. The caller reads the output meanwhile, so a command
+/// which writes before it has read all its input can't fill its
+/// output pipe while we are blocked filling its input pipe.
+/// If the write fails other than by the shell closing its stdin
+/// (having exited, or finished reading), what the shell read may be
+/// a truncated command, so its process group is killed.
+fn write_stdin(mut stdin: ChildStdin, cmd: Vec<u8>, pgid: u32) {
+    std::thread::spawn(move || {
+        if let Err(e) = stdin.write_all(&cmd)
+            && e.kind() != ErrorKind::BrokenPipe
+        {
+            log_warning!("Could not write a command to its shell, so killed it: {e}");
+            let _ = sys::kill_process_group(pgid);
+        }
+    });
+}
+
+/// Set up the command as the options say,
+/// short of where its stdout and stderr go.
+fn build(cmd: &[u8], args: &ProgramArgs) -> std::io::Result<Command> {
+    let mut command = if args.no_shell {
+        direct_command(cmd)?
+    } else {
+        shell_command(cmd, args)?
+    };
+    if let Some(dir) = &args.workdir {
+        command.current_dir(dir);
+    }
+    if args.env_clear {
+        command.env_clear();
+    }
+    if let Some(user) = &args.run_as {
+        // std sets the gid before the uid, and drops
+        // supplementary groups when switching away from root.
+        command
+            .gid(user.gid)
+            .uid(user.uid)
+            .env("HOME", &user.home)
+            .env("USER", &user.name)
+            .env("LOGNAME", &user.name);
+    }
+    command.envs(args.env.iter().map(|(k, v)| (k, v)));
+    let limits = resource_limits(args);
+    if !limits.is_empty() {
+        // Safety: the hook runs in the child between fork and exec,
+        // where only async-signal-safe calls are allowed;
+        // set_limit only makes system calls, and `limits`
+        // was built beforehand so nothing is allocated.
+        unsafe {
+            command.pre_exec(move || {
+                for &(resource, value) in &limits {
+                    sys::set_limit(resource, value)?;
+                }
+                Ok(())
+            });
+        }
+    }
+    Ok(command)
+}
+
+/// The `--limit-*` options as (resource, value) pairs for `sys::set_limit`.
+fn resource_limits(args: &ProgramArgs) -> Vec<(c_int, u64)> {
+    [
+        (sys::RLIMIT_AS, args.limit_as),
+        (sys::RLIMIT_CPU, args.limit_cpu),
+        (sys::RLIMIT_NPROC, args.limit_nproc),
+    ]
+    .into_iter()
+    .filter_map(|(resource, value)| Some((resource, value?)))
+    .collect()
+}
+
+/// The `--shell` with its `--shell-args`.
+/// If the last of those is `-c` (alone or with other single-letter flags,
+/// like `-lc`), the command is passed as the argument after it;
+/// otherwise the shell reads the command from its stdin.
+fn shell_command(cmd: &[u8], args: &ProgramArgs) -> std::io::Result<Command> {
+    let shell_args: Vec<&str> = args.shell_args.split_whitespace().collect();
+    let mut command = Command::new(&args.shell);
+    command.args(&shell_args);
+    if shell_takes_command_arg(args) {
+        if cmd.contains(&0) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "a command containing NUL bytes can't be passed to the shell with -c",
+            ));
+        }
+        command.arg(OsStr::from_bytes(cmd)).stdin(Stdio::null());
+    } else {
+        command.stdin(Stdio::piped());
+    }
+    Ok(command)
+}
+
+/// Whether the last of the `--shell-args` is `-c`, or bundles it with others.
+fn shell_takes_command_arg(args: &ProgramArgs) -> bool {
+    args.shell_args
+        .split_whitespace()
+        .next_back()
+        .and_then(|flag| flag.strip_prefix('-'))
+        .is_some_and(|letters| !letters.starts_with('-') && letters.contains('c'))
+}
+
+/// Run the program named by the first element of the argv array
+/// with the rest as its arguments; no shell is involved.
+fn direct_command(cmd: &[u8]) -> std::io::Result<Command> {
+    let argv = split_argv(cmd);
+    let Some((program, rest)) = argv.split_first() else {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "no program given to run",
+        ));
+    };
+    let mut command = Command::new(OsStr::from_bytes(program));
+    command
+        .args(rest.iter().map(|a| OsStr::from_bytes(a)))
+        .stdin(Stdio::null());
+    Ok(command)
+}
+
+/// Split a command into argv elements.
+/// If the command contains any NUL bytes it is split on those,
+/// otherwise it is split on newlines.
+/// One trailing separator is ignored.
+fn split_argv(cmd: &[u8]) -> Vec<&[u8]> {
+    let sep = if cmd.contains(&0) { 0 } else { b'\n' };
+    let cmd = cmd.strip_suffix(&[sep]).unwrap_or(cmd);
+    if cmd.is_empty() {
+        return Vec::new();
+    }
+    cmd.split(|b| *b == sep).collect()
+}
+
+/// Commands which are currently running, so they can be cancelled
+/// by the request id the client sent with them.
+/// Each is kept under a key of its own, since a command
+/// may have no request id, or the same one as another.
+pub struct RunningCommands {
+    commands: Mutex<HashMap<u64, Running>>,
+    next_key: AtomicU64,
+}
+
+/// A command in `RunningCommands`.
+struct Running {
+    request_id: Option<Vec<u8>>,
+    // The child leads its own process group, so its pid is the group id
+    pgid: u32,
+}
+
+/// Removes a command from `RunningCommands` when dropped.
+pub struct Tracked<'a> {
+    running: &'a RunningCommands,
+    key: u64,
+}
+
+impl RunningCommands {
+    pub fn new() -> RunningCommands {
+        RunningCommands {
+            commands: Mutex::new(HashMap::new()),
+            next_key: AtomicU64::new(0),
+        }
+    }
+
+    fn track(&self, request_id: Option<&[u8]>, child: &Child) -> Tracked<'_> {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        let running = Running {
+            request_id: request_id.map(<[u8]>::to_vec),
+            pgid: child.id(),
+        };
+        self.lock().insert(key, running);
+        Tracked { running: self, key }
+    }
+
+    /// Kill the process group of every running command with this request id.
+    /// Returns false if no such command is running.
+    pub fn cancel(&self, request_id: &[u8]) -> std::io::Result<bool> {
+        let pgids: Vec<u32> = self
+            .lock()
+            .values()
+            .filter(|r| r.request_id.as_deref() == Some(request_id))
+            .map(|r| r.pgid)
+            .collect();
+        for &pgid in &pgids {
+            sys::kill_process_group(pgid)?;
+        }
+        Ok(!pgids.is_empty())
+    }
+
+    /// Kill the process groups of every running command.
+    pub fn cancel_all(&self) {
+        for running in self.lock().values() {
+            let _ = sys::kill_process_group(running.pgid);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Running>> {
+        self.commands
+            .lock()
+            .expect("running commands lock poisoned")
+    }
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.running.lock().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::time::Duration;
+
+    #[test]
+    fn output_is_read_while_a_long_command_is_written() {
+        // The first line prints more than a pipe holds before bash has read
+        // the rest, which is also more than a pipe holds
+        let mut cmd = b"head -c 1000000 /dev/zero\n#".to_vec();
+        cmd.resize(cmd.len() + 1_000_000, b'x');
+        cmd.extend_from_slice(b"\necho done >&2\n");
+        let args = ProgramArgs::parse_from(["command-executor", "--shell-args=-s"]);
+
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let running = RunningCommands::new();
+            tx.send(execute(&cmd, &args, &running, None).unwrap())
+                .unwrap();
+        });
+        let out = rx
+            .recv_timeout(Duration::from_secs(20))
+            .expect("the command and executor are deadlocked");
+        assert_eq!(out.status_code, 0);
+        assert_eq!(out.stdout.len(), 1_000_000);
+        assert_eq!(out.stderr, b"done\n");
+    }
+}
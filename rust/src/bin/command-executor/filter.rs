@@ -0,0 +1,71 @@
+use crate::sys::Regex;
+
+/*
+ * Which commands may run (--allow-pattern and --deny-pattern).
+ * Patterns are POSIX extended regular expressions, as `grep -E` takes,
+ * and must match the whole command, not just part of it;
+ * a trailing newline on the command is ignored.
+ * A command runs if it matches an allow pattern (or there are none)
+ * and matches no deny pattern.
+ * */
+
+pub struct CommandFilter {
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+}
+
+impl CommandFilter {
+    /// Compile the patterns, or say which one is bad and why.
+    pub fn new(allow: &[String], deny: &[String]) -> Result<CommandFilter, String> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| {
+                    // Anchored at both ends, so the whole command has to match
+                    Regex::new(&format!("^({p})$")).map_err(|e| format!("bad pattern {p:?}: {e}"))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(CommandFilter {
+            allow: compile(allow)?,
+            deny: compile(deny)?,
+        })
+    }
+
+    /// Whether a command may be run.
+    pub fn permits(&self, cmd: &[u8]) -> bool {
+        let cmd = cmd.strip_suffix(b"\n").unwrap_or(cmd);
+        (self.allow.is_empty() || self.allow.iter().any(|r| r.is_match(cmd)))
+            && !self.deny.iter().any(|r| r.is_match(cmd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_command_must_match() {
+        let filter = CommandFilter::new(
+            &[
+                "/opt/scripts/toggle\\.sh (on|off)".to_string(),
+                "uptime".to_string(),
+            ],
+            &[".*off".to_string()],
+        )
+        .unwrap();
+        assert!(filter.permits(b"/opt/scripts/toggle.sh on\n"));
+        assert!(filter.permits(b"uptime"));
+        assert!(!filter.permits(b"/opt/scripts/toggle.sh off"), "denied");
+        assert!(!filter.permits(b"uptime; rm -rf /"), "only part matches");
+        assert!(
+            !filter.permits(b"uptime\nrm -rf /"),
+            "only the first line matches"
+        );
+        assert!(!filter.permits(b"uptime\0"), "NUL byte");
+
+        let open = CommandFilter::new(&[], &[]).unwrap();
+        assert!(open.permits(b"anything at all"));
+        assert!(CommandFilter::new(&["(".to_string()], &[]).is_err());
+    }
+}
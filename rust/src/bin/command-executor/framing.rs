@@ -0,0 +1,61 @@
+use clap::ValueEnum;
+
+/*
+ * How the `AUTH` and `REQ-ID` header fields at the start of a command
+ * are ended (--framing). With `newline` they are lines, which is handy
+ * from a shell but leaves a command starting with something like
+ * `REQ-ID x` ambiguous. With `nul` each field ends with a NUL byte,
+ * which no header contains, so whatever follows the headers is the
+ * command, byte for byte: newlines, NULs, and all.
+ * */
+
+/// What ends each header field of a command.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Framing {
+    /// A newline (`\n`, or `\r\n`)
+    Newline,
+    /// A NUL byte (`\0`)
+    Nul,
+}
+
+impl Framing {
+    /// Split the first header field off `data`.
+    /// Returns the field, without what ended it, and the rest of `data`.
+    /// If nothing ends the field, all of `data` is the field.
+    pub fn split_field(self, data: &[u8]) -> (&[u8], &[u8]) {
+        let end = match self {
+            Framing::Newline => b'\n',
+            Framing::Nul => 0,
+        };
+        let (field, rest) = match data.iter().position(|b| *b == end) {
+            Some(i) => (&data[..i], &data[i + 1..]),
+            None => (data, &data[data.len()..]),
+        };
+        match self {
+            // Tolerate clients which send CRLF line endings
+            Framing::Newline => (field.strip_suffix(b"\r").unwrap_or(field), rest),
+            Framing::Nul => (field, rest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nul_framing_leaves_newlines_in_the_command() {
+        let data = b"REQ-ID a\0REQ-ID b\nline 2\0x";
+        let (field, rest) = Framing::Nul.split_field(data);
+        assert_eq!(field, b"REQ-ID a");
+        assert_eq!(rest, b"REQ-ID b\nline 2\0x");
+
+        let (field, rest) = Framing::Newline.split_field(b"REQ-ID a\r\nls\n");
+        assert_eq!(field, b"REQ-ID a");
+        assert_eq!(rest, b"ls\n");
+        assert_eq!(
+            Framing::Nul.split_field(b"AUTH x"),
+            (&b"AUTH x"[..], &b""[..])
+        );
+    }
+}
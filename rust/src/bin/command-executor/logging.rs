@@ -0,0 +1,114 @@
+/*
+ * Minimal levelled logging to stderr, with timestamps.
+ * Named like the log_* functions in impisc.logging.
+ *
+ * The level comes from the RUST_LOG environment variable
+ * (error, warn, info, debug, or off), and defaults to info.
+ * Messages below the level are never formatted, so disabled
+ * logging costs one atomic load.
+ * Messages the library logs through the `log` crate come out here too.
+ * */
+use chrono::Utc;
+use std::fmt::Arguments;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+// 0 means logging is off
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Set the log level from RUST_LOG, and take over the `log` crate's messages.
+pub fn init() {
+    // Only fails if a logger is already set, in which case it can keep them
+    if log::set_logger(&Bridge).is_ok() {
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+    let Ok(var) = std::env::var("RUST_LOG") else {
+        return;
+    };
+    let level = match var.trim().to_ascii_lowercase().as_str() {
+        "off" => 0,
+        "error" => Level::Error as u8,
+        "warn" => Level::Warn as u8,
+        "info" => Level::Info as u8,
+        "debug" | "trace" => Level::Debug as u8,
+        other => {
+            eprintln!("Unknown RUST_LOG level `{other}`; using info");
+            Level::Info as u8
+        }
+    };
+    MAX_LEVEL.store(level, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    (level as u8) <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn log(level: Level, args: Arguments) {
+    let name = match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN",
+        Level::Info => "INFO",
+        Level::Debug => "DEBUG",
+    };
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ");
+    eprintln!("{now} {name:<5} {args}");
+}
+
+/// Hands the `log` crate's messages to `log`, so they look like ours.
+struct Bridge;
+
+impl log::Log for Bridge {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        enabled(level_of(metadata.level()))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            log(level_of(record.level()), *record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn level_of(level: log::Level) -> Level {
+    match level {
+        log::Level::Error => Level::Error,
+        log::Level::Warn => Level::Warn,
+        log::Level::Info => Level::Info,
+        log::Level::Debug | log::Level::Trace => Level::Debug,
+    }
+}
+
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::logging::enabled($level) {
+            $crate::logging::log($level, format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::logging::log_at!($crate::logging::Level::Error, $($arg)*) };
+}
+
+macro_rules! log_warning {
+    ($($arg:tt)*) => { $crate::logging::log_at!($crate::logging::Level::Warn, $($arg)*) };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::logging::log_at!($crate::logging::Level::Info, $($arg)*) };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::logging::log_at!($crate::logging::Level::Debug, $($arg)*) };
+}
+
+pub(crate) use {log_at, log_debug, log_error, log_info, log_warning};
@@ -0,0 +1,727 @@
+/*
+    A command executor program.
+
+    Accepts an arbitrary command via UDP socket.
+    Max length is 8192B long, unless it is sent in
+    several parts (see reassembly.rs).
+
+    Command is executed using `bash -sl` (see man bash) by default,
+    or another shell with `--shell` and `--shell-args`,
+    or directly as an argv array with `--no-shell`.
+
+    Each command runs on its own thread, one at a time by default.
+    With `--max-concurrent` several run at once, so a slow command
+    doesn't hold up the others. Commands beyond the limit wait in
+    a bounded queue, or are refused as busy (`--when-busy`; see semaphore.rs).
+
+    stdout and stderr are captured and sent back separately.
+    The reply is broken into 512B chunks, each followed by a
+    trailer which carries the packet "sequence number"
+    (see reply.rs). With `--reliable`, chunks are resent
+    until the client acknowledges them (see ack.rs).
+
+    `__PING__` or `__VERSION__` gets the version, uptime,
+    and load average back without running anything (see ping.rs).
+
+    On SIGTERM or SIGINT, no new commands are taken, and the
+    executor exits once running commands have finished and replied
+    (or been killed after `--shutdown-timeout`).
+
+    Counts of commands run and failed are logged on SIGUSR1,
+    every `--stats-interval`, and on exit (see stats.rs).
+
+    Logs go to stderr; set RUST_LOG (e.g. `RUST_LOG=debug`)
+    to change how much is logged.
+
+    Run `command-executor --help` for the available options.
+*/
+mod ack;
+mod args;
+mod audit;
+mod auth;
+mod compress;
+mod exec;
+mod filter;
+mod framing;
+mod logging;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod output;
+mod ping;
+mod rate_limit;
+mod reassembly;
+mod reply;
+mod request_id;
+mod semaphore;
+mod stats;
+mod sys;
+mod tee;
+mod user;
+use ack::AckTracker;
+use args::ProgramArgs;
+use audit::AuditLog;
+use exec::RunningCommands;
+use filter::CommandFilter;
+use impish_executables::cidr::Cidr;
+use impish_executables::{config, signals, systemd};
+use logging::{log_debug, log_error, log_info, log_warning};
+use output::OutputWrapper;
+use rate_limit::RateLimiter;
+use reassembly::Reassembler;
+use semaphore::{Semaphore, WhenBusy};
+use socket2::{Domain, Socket, Type};
+use stats::Stats;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tee::TeeDir;
+
+fn main() {
+    let started = Instant::now();
+    let args: ProgramArgs = config::parse_with_config().unwrap_or_else(|e| e.exit());
+    logging::init();
+
+    // A bad working directory would fail every command,
+    // so refuse to start rather than reply with the same error each time.
+    if let Some(dir) = &args.workdir
+        && !dir.is_dir()
+    {
+        log_error!(
+            "--workdir {} does not exist or is not a directory",
+            dir.display()
+        );
+        std::process::exit(1);
+    }
+
+    let filter = match CommandFilter::new(&args.allow_pattern, &args.deny_pattern) {
+        Ok(f) => f,
+        Err(e) => {
+            log_error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let audit = args.audit_log.as_deref().map(|path| {
+        AuditLog::open(path, args.audit_hash_only).unwrap_or_else(|e| {
+            log_error!("Cannot open audit log {}: {e}", path.display());
+            std::process::exit(1);
+        })
+    });
+
+    let tee = args.tee_dir.as_deref().map(|dir| {
+        TeeDir::open(dir).unwrap_or_else(|e| {
+            log_error!("Cannot use --tee-dir {}: {e}", dir.display());
+            std::process::exit(1);
+        })
+    });
+
+    // Where do we send output?
+    let dest_port = std::env::var("HEADER_STAMPER_PORT")
+        .expect("Need HEADER_STAMPER_PORT to be set")
+        .parse::<u16>()
+        .expect("Need HEADER_STAMPER_PORT to be a parsable u16");
+    let send_to_me = header_stamper_addr(args.bind, dest_port);
+
+    // The default address 0.0.0.0 is like INADDR_ANY; :: takes IPv4 and IPv6.
+    // The socket is bound once and used for every command and reply,
+    // so nothing is lost between commands and replies always carry
+    // the listen port as their source port (the header stamper keys on it).
+    let listen_addr = SocketAddr::new(args.bind, args.port);
+    let bound = if args.bind == Ipv6Addr::UNSPECIFIED {
+        bind_dual_stack(args.port)
+    } else {
+        UdpSocket::bind(listen_addr)
+    };
+    let sock = match bound {
+        Ok(s) => s,
+        Err(e) => {
+            log_error!("Cannot listen for commands on UDP {listen_addr}: {e}");
+            std::process::exit(1);
+        }
+    };
+    // Wake up now and then to check for a shutdown signal
+    sock.set_read_timeout(Some(SHUTDOWN_POLL))
+        .expect("Need to be able to set socket timeout");
+    signals::catch_shutdown_signals();
+    signals::catch_stats_signal();
+
+    let mut rate_limiter = args.rate_limit.map(RateLimiter::new);
+    let mut reassembler = args.multipart.then(|| {
+        Reassembler::new(
+            Duration::from_secs(args.reassembly_timeout),
+            args.recv_timeout.map(Duration::from_secs),
+        )
+    });
+
+    // Commands run on their own threads, which share
+    // a clone of the socket to reply on.
+    let reply_sock = sock
+        .try_clone()
+        .expect("Need to be able to clone the socket for replies");
+    let slots = Arc::new(Semaphore::new(args.max_concurrent as usize));
+    let acks = args.reliable.then(|| {
+        AckTracker::new(
+            Duration::from_millis(args.ack_timeout_ms),
+            args.max_retransmits,
+        )
+    });
+    let ctx = Arc::new(Context {
+        args,
+        acks,
+        filter,
+        audit,
+        tee,
+        reply_sock,
+        send_to_me,
+        running: RunningCommands::new(),
+        stats: Arc::new(Stats::default()),
+    });
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = ctx.args.metrics_addr
+        && let Err(e) = metrics::serve(addr, Arc::clone(&ctx.stats))
+    {
+        log_error!("Cannot serve metrics on {addr}: {e}");
+        std::process::exit(1);
+    }
+    let stats_interval = ctx.args.stats_interval.map(Duration::from_secs);
+    let mut last_stats = Instant::now();
+
+    // Count how many packets we receive for bookkeeping on the ground
+    // This deliberately wraps around after 255 commands.
+    let mut packets_received: u8 = 0;
+    let mut shutdown = None;
+    let mut killed = false;
+    systemd::notify_ready();
+    let mut watchdog = systemd::Watchdog::from_env();
+    loop {
+        watchdog.ping();
+        if signals::stats_requested() || stats_interval.is_some_and(|i| last_stats.elapsed() >= i) {
+            ctx.stats.log();
+            last_stats = Instant::now();
+        }
+        if signals::shutdown_requested() {
+            // Keep receiving meanwhile, so ACKs and health checks are still answered
+            let deadline = *shutdown.get_or_insert_with(|| {
+                systemd::notify_stopping();
+                log_info!(
+                    "Shutting down: waiting up to {}s for running commands to finish",
+                    ctx.args.shutdown_timeout
+                );
+                Instant::now() + Duration::from_secs(ctx.args.shutdown_timeout)
+            });
+            if slots.is_idle() {
+                break;
+            }
+            if Instant::now() >= deadline && !killed {
+                // Their replies still go out once they die
+                log_warning!("Shutdown timed out; killing running commands");
+                ctx.running.cancel_all();
+                killed = true;
+            }
+        }
+        let Some((cmd, request_id, sender)) =
+            receive_command(&sock, &ctx.args, reassembler.as_mut(), started)
+        else {
+            continue;
+        };
+
+        if let Some(acks) = &ctx.acks
+            && let Some(msg) = cmd.strip_prefix(ack::ACK_PREFIX)
+        {
+            // Not a command, so it doesn't count as one
+            if !acks.record(msg) {
+                log_debug!(
+                    "Ignoring ACK {:?} from {sender}: not for a reply being sent",
+                    String::from_utf8_lossy(msg)
+                );
+            }
+            continue;
+        }
+        if shutdown.is_some() {
+            log_warning!("Dropping command from {sender}: shutting down");
+            continue;
+        }
+        packets_received = packets_received.wrapping_add(1);
+        Stats::add(&ctx.stats.received, 1);
+
+        if let Some(id) = cmd.strip_prefix(CANCEL_PREFIX) {
+            // Handled right away, without waiting for a free slot,
+            // and not held to --rate-limit, so a client over its limit
+            // can still stop what it started
+            log_info!(
+                "Cancel request for command {:?} from {sender}",
+                String::from_utf8_lossy(id)
+            );
+            cancel_command(&ctx, id, &cmd, request_id, packets_received);
+            continue;
+        }
+        if let Some(limiter) = &mut rate_limiter
+            && !limiter.allow(sender.ip())
+        {
+            log_warning!("Refusing command #{packets_received} from {sender}: rate limited");
+            Stats::add(&ctx.stats.refused, 1);
+            refuse(&ctx, cmd, request_id, packets_received, b"rate limited");
+            continue;
+        }
+
+        // The loop never waits for a free slot, so ACKs,
+        // health checks, and signals are still seen meanwhile
+        let num_cmds_received = packets_received;
+        if let Some(permit) = slots.try_acquire() {
+            let ctx = Arc::clone(&ctx);
+            std::thread::spawn(move || {
+                run_command(&ctx, cmd, request_id, sender, num_cmds_received);
+                drop(permit);
+            });
+        } else if let WhenBusy::Queue = ctx.args.when_busy
+            && let Some(waiting) = slots.enqueue(ctx.args.max_queued as usize)
+        {
+            log_debug!("Command #{num_cmds_received} from {sender} is waiting for a free slot");
+            let ctx = Arc::clone(&ctx);
+            std::thread::spawn(move || {
+                let permit = waiting.acquire();
+                if signals::shutdown_requested() {
+                    log_warning!(
+                        "Refusing command #{num_cmds_received} from {sender}: shut down while it waited"
+                    );
+                    Stats::add(&ctx.stats.refused, 1);
+                    refuse(&ctx, cmd, request_id, num_cmds_received, b"shutting down");
+                } else {
+                    run_command(&ctx, cmd, request_id, sender, num_cmds_received);
+                }
+                drop(permit);
+            });
+        } else {
+            log_warning!("Refusing command #{num_cmds_received} from {sender}: server busy");
+            Stats::add(&ctx.stats.refused, 1);
+            refuse(&ctx, cmd, request_id, num_cmds_received, b"server busy");
+        }
+    }
+    ctx.stats.log();
+    log_info!("Shut down");
+}
+
+/// Longest the receive loop waits before checking for a shutdown signal.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(500);
+
+/// Where the header stamper listens on this machine,
+/// given the address commands are received on: replies go out of
+/// the same socket, so the loopback address must be of the same family.
+fn header_stamper_addr(bind: IpAddr, port: u16) -> String {
+    let loopback = match bind {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        // A dual-stack socket reaches the IPv4 loopback through its mapped address
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+    };
+    SocketAddr::new(loopback, port).to_string()
+}
+
+/// What every command thread needs to run a command and reply.
+struct Context {
+    args: ProgramArgs,
+    // Set with --reliable
+    acks: Option<AckTracker>,
+    filter: CommandFilter,
+    // Set with --audit-log
+    audit: Option<AuditLog>,
+    // Set with --tee-dir
+    tee: Option<TeeDir>,
+    reply_sock: UdpSocket,
+    send_to_me: String,
+    running: RunningCommands,
+    // Shared with the metrics server, if there is one
+    stats: Arc<Stats>,
+}
+
+/// Control message which kills a running command:
+/// `CANCEL <id>`, where id is the request id the command was sent with.
+const CANCEL_PREFIX: &[u8] = b"CANCEL ";
+
+/// Kill the commands sent with the request id given in `id`,
+/// replying `cancelled` if any were running or
+/// `no-such-request` if not.
+fn cancel_command(
+    ctx: &Context,
+    id: &[u8],
+    cmd: &[u8],
+    request_id: Option<Vec<u8>>,
+    num_cmds_received: u8,
+) {
+    let cancelled = ctx.running.cancel(id.trim_ascii());
+    let mut res = match cancelled {
+        Ok(true) => OutputWrapper::from_message(cmd.to_vec(), 0, b"cancelled", b""),
+        Ok(false) => OutputWrapper::from_message(cmd.to_vec(), 1, b"", b"no-such-request"),
+        Err(e) => OutputWrapper::from_error(cmd.to_vec(), &e),
+    };
+    res.request_id = request_id;
+    let res_bytes = res.to_packet(ctx.args.format);
+    let sent = reply::reply_with(
+        &res_bytes,
+        &ctx.reply_sock,
+        num_cmds_received,
+        &ctx.send_to_me,
+        ctx.acks.as_ref(),
+        ctx.args.compress_min(),
+        ctx.args.checksum,
+    );
+    Stats::add(&ctx.stats.bytes_replied, sent);
+}
+
+/// Reply to a command which won't be run, e.g. because of --rate-limit,
+/// with the reason in stderr.
+/// This may be done on the receiving thread, so the reply isn't
+/// held back for ACKs even with --reliable: nothing would be
+/// receiving them meanwhile.
+fn refuse(
+    ctx: &Context,
+    cmd: Vec<u8>,
+    request_id: Option<Vec<u8>>,
+    num_cmds_received: u8,
+    reason: &[u8],
+) {
+    let mut res = OutputWrapper::from_message(cmd, -1, b"", reason);
+    res.request_id = request_id;
+    let res_bytes = res.to_packet(ctx.args.format);
+    let sent = reply::reply_with(
+        &res_bytes,
+        &ctx.reply_sock,
+        num_cmds_received,
+        &ctx.send_to_me,
+        None,
+        None,
+        ctx.args.checksum,
+    );
+    Stats::add(&ctx.stats.bytes_replied, sent);
+}
+
+/// Execute one command and send back its output,
+/// along with the client's request id if it gave one.
+fn run_command(
+    ctx: &Context,
+    cmd: Vec<u8>,
+    request_id: Option<Vec<u8>>,
+    sender: SocketAddr,
+    num_cmds_received: u8,
+) {
+    let started = Instant::now();
+    let cmd_len = cmd.len();
+    let label = match &request_id {
+        Some(id) => format!(
+            "Command #{num_cmds_received} (request {})",
+            String::from_utf8_lossy(id)
+        ),
+        None => format!("Command #{num_cmds_received}"),
+    };
+    log_debug!("{label} from {sender}: {:?}", String::from_utf8_lossy(&cmd));
+    if !ctx.filter.permits(&cmd) {
+        log_warning!(
+            "{label} from {sender} not permitted: {:?}",
+            String::from_utf8_lossy(&cmd)
+        );
+        Stats::add(&ctx.stats.refused, 1);
+        let mut res = OutputWrapper::from_message(cmd, -1, b"", b"command not permitted");
+        res.request_id = request_id;
+        let res_bytes = res.to_packet(ctx.args.format);
+        let sent = reply::reply_with(
+            &res_bytes,
+            &ctx.reply_sock,
+            num_cmds_received,
+            &ctx.send_to_me,
+            ctx.acks.as_ref(),
+            ctx.args.compress_min(),
+            ctx.args.checksum,
+        );
+        Stats::add(&ctx.stats.bytes_replied, sent);
+        return;
+    }
+
+    // Running the command uses it up, so keep a copy for the audit log
+    let audited = ctx
+        .audit
+        .is_some()
+        .then(|| (cmd.clone(), request_id.clone()));
+    let in_flight = ctx.stats.start_command();
+    let status_code = if ctx.args.stream {
+        stream_command(ctx, cmd, request_id, num_cmds_received)
+    } else {
+        batch_command(ctx, cmd, request_id, num_cmds_received)
+    };
+    drop(in_flight);
+    ctx.stats.add_finished(status_code);
+    log_info!(
+        "{label} from {sender} ({cmd_len} bytes) exited with status {status_code} after {:.3}s",
+        started.elapsed().as_secs_f64()
+    );
+    if let Some(audit) = &ctx.audit
+        && let Some((cmd, request_id)) = audited
+    {
+        audit.record(&audit::Entry {
+            source: sender,
+            request_id: request_id.as_deref(),
+            cmd: &cmd,
+            status_code,
+            duration: started.elapsed(),
+        });
+    }
+}
+
+/// Run a command to completion, then reply with all of its output.
+/// Returns the status code of the command.
+fn batch_command(
+    ctx: &Context,
+    cmd: Vec<u8>,
+    request_id: Option<Vec<u8>>,
+    num_cmds_received: u8,
+) -> i32 {
+    let args = &ctx.args;
+    // If there is a problem executing part of the command,
+    // put the error msg into the wrapper stderr
+    let (mut res, error) = match exec::execute(&cmd, args, &ctx.running, request_id.as_deref()) {
+        Ok(r) => (r, None),
+        Err(e) => (OutputWrapper::from_error(cmd, &e), Some(e)),
+    };
+    res.request_id = request_id;
+    // Before truncating, so the file has all of the output
+    tee_output(ctx, &res);
+
+    if let Some(max_bytes) = args.max_reply_bytes {
+        res.truncate(max_bytes, args.combined_reply_limit);
+    }
+    let res_bytes = res.to_packet(args.format);
+    let sent = reply::reply_with(
+        &res_bytes,
+        &ctx.reply_sock,
+        num_cmds_received,
+        &ctx.send_to_me,
+        ctx.acks.as_ref(),
+        ctx.args.compress_min(),
+        ctx.args.checksum,
+    );
+    Stats::add(&ctx.stats.bytes_replied, sent);
+    if let Some(e) = error {
+        exit_if_cannot_switch_user(ctx, &e);
+    }
+    res.status_code
+}
+
+/// Run a command, streaming its output back as it is produced.
+/// Returns the status code of the command.
+fn stream_command(
+    ctx: &Context,
+    cmd: Vec<u8>,
+    request_id: Option<Vec<u8>>,
+    num_cmds_received: u8,
+) -> i32 {
+    let args = &ctx.args;
+    let mut streamer = reply::Streamer::new(
+        &ctx.reply_sock,
+        num_cmds_received,
+        &ctx.send_to_me,
+        ctx.acks.as_ref(),
+        args.checksum,
+    );
+    if let Some(id) = &request_id {
+        streamer.send(reply::StreamId::RequestId, id);
+    }
+    if ctx.tee.is_some() {
+        streamer.keep_output();
+    }
+    match exec::execute_streaming(
+        &cmd,
+        args,
+        &ctx.running,
+        request_id.as_deref(),
+        &mut streamer,
+    ) {
+        Ok(status_code) => {
+            if let Some((stdout, stderr)) = streamer.take_output() {
+                let mut res = OutputWrapper::from_message(cmd, status_code, &stdout, &stderr);
+                res.request_id = request_id;
+                tee_output(ctx, &res);
+            }
+            Stats::add(&ctx.stats.bytes_replied, streamer.finish(status_code));
+            status_code
+        }
+        Err(e) => {
+            // Nothing was streamed if the command didn't start,
+            // so report the error as a normal reply
+            let mut res = OutputWrapper::from_error(cmd, &e);
+            res.request_id = request_id;
+            tee_output(ctx, &res);
+            let res_bytes = res.to_packet(args.format);
+            let sent = reply::reply_with(
+                &res_bytes,
+                &ctx.reply_sock,
+                num_cmds_received,
+                &ctx.send_to_me,
+                ctx.acks.as_ref(),
+                ctx.args.compress_min(),
+                ctx.args.checksum,
+            );
+            Stats::add(&ctx.stats.bytes_replied, sent);
+            exit_if_cannot_switch_user(ctx, &e);
+            res.status_code
+        }
+    }
+}
+
+/// With --tee-dir, write a command's output to a file as well as replying.
+/// Failing to is logged, but doesn't stop the reply.
+fn tee_output(ctx: &Context, res: &OutputWrapper) {
+    if let Some(tee) = &ctx.tee {
+        match tee.write(res) {
+            Ok(path) => log_debug!("Wrote output to {}", path.display()),
+            Err(e) => log_error!("Cannot write output to --tee-dir: {e}"),
+        }
+    }
+}
+
+/// Every command would fail the same way if we can't switch to
+/// the `--run-as` user, so stop once the error has been sent back
+/// rather than keep replying with errors.
+fn exit_if_cannot_switch_user(ctx: &Context, e: &std::io::Error) {
+    if let Some(user) = &ctx.args.run_as
+        && user::is_switch_denied(e)
+    {
+        log_error!(
+            "Cannot run commands as {} (uid {}, gid {}): {e}; the executor needs to run as root or with CAP_SETUID and CAP_SETGID",
+            user.name,
+            user.uid,
+            user.gid
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Receive a command as a series of bytes from a socket.
+/// Datagrams from senders outside of `--allow` are dropped.
+/// Health checks are answered along the way (see ping.rs).
+/// If a reassembler is given, the command may be split across
+/// several datagrams (see reassembly.rs for the format).
+/// If an auth token is given, the command must start with a
+/// matching `AUTH <token>` line, which is stripped off
+/// (lines end with a NUL instead with `--framing nul`);
+/// commands which fail authentication are dropped.
+/// A `REQ-ID <id>` line after that is stripped off too
+/// (see request_id.rs); commands with a malformed id are dropped.
+/// Returns a tuple (cmd, request id, sender address).
+fn receive_command(
+    sock: &UdpSocket,
+    args: &ProgramArgs,
+    reassembler: Option<&mut Reassembler>,
+    started: Instant,
+) -> Option<(Vec<u8>, Option<Vec<u8>>, SocketAddr)> {
+    let received = match reassembler {
+        Some(r) => receive_multipart(sock, &args.allow, r, started),
+        None => receive_allowed(sock, &args.allow, started),
+    };
+    let (vecta, sender) = received.ok()?;
+
+    let cmd = match args.auth_token.as_deref() {
+        None => vecta,
+        Some(token) => match auth::authenticate(&vecta, token, args.framing) {
+            Some(cmd) => cmd,
+            None => {
+                log_warning!("Dropping unauthenticated command from {sender}");
+                return None;
+            }
+        },
+    };
+    match request_id::split_request_id(cmd, args.framing) {
+        Ok((request_id, cmd)) => Some((cmd, request_id, sender)),
+        Err(e) => {
+            log_warning!("Dropping command from {sender}: {e}");
+            None
+        }
+    }
+}
+
+/// Bind a UDP socket to `[::]:port` which takes IPv4 datagrams too
+/// (as `::ffff:a.b.c.d`), whatever the system's `bindv6only` default is.
+/// std binds as soon as it creates a socket, too early to clear IPV6_V6ONLY.
+fn bind_dual_stack(port: u16) -> std::io::Result<UdpSocket> {
+    let sock = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+    sock.set_only_v6(false)?;
+    sock.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+    Ok(sock.into())
+}
+
+/// Receive a single datagram from a socket.
+fn receive_datagram(sock: &UdpSocket) -> std::io::Result<(Vec<u8>, SocketAddr)> {
+    // A datagram can be up to 8192 bytes long
+    // Any longer gets dropped
+    let mut buf = [0; 8192];
+    let (num_recv, sender) = sock.recv_from(&mut buf)?;
+
+    // Drop empty bytes from the buffer
+    Ok((buf[..num_recv].to_vec(), sender))
+}
+
+/// Receive datagrams until one other than a health check
+/// arrives from an allowed sender.
+/// An empty allowlist allows everyone.
+fn receive_allowed(
+    sock: &UdpSocket,
+    allow: &[Cidr],
+    started: Instant,
+) -> std::io::Result<(Vec<u8>, SocketAddr)> {
+    loop {
+        let (datagram, sender) = receive_datagram(sock)?;
+        if !(allow.is_empty() || allow.iter().any(|c| c.contains(sender.ip()))) {
+            log_warning!("Dropping datagram from {sender}: not in the allowed sources");
+        } else if ping::is_probe(&datagram) {
+            log_debug!("Health check from {sender}");
+            if let Err(e) = ping::answer(sock, sender, started) {
+                log_warning!("Failed to answer health check from {sender}: {e}");
+            }
+        } else {
+            return Ok((datagram, sender));
+        }
+    }
+}
+
+/// Receive datagrams until one sender has delivered every
+/// chunk of a multipart command.
+/// Partial commands which take longer than the reassembly
+/// timeout, or whose sender goes quiet for longer than
+/// `--recv-timeout`, are thrown away so a lost chunk or a
+/// vanished client can't hang the loop.
+fn receive_multipart(
+    sock: &UdpSocket,
+    allow: &[Cidr],
+    reassembler: &mut Reassembler,
+    started: Instant,
+) -> std::io::Result<(Vec<u8>, SocketAddr)> {
+    loop {
+        // Only wait as long as the oldest partial command has left
+        let timeout = reassembler
+            .time_left()
+            .map_or(SHUTDOWN_POLL, |t| t.min(SHUTDOWN_POLL));
+        sock.set_read_timeout(Some(timeout))?;
+        match receive_allowed(sock, allow, started) {
+            Ok((chunk, sender)) => {
+                if let Some(cmd) = reassembler.add(sender, &chunk) {
+                    return Ok((cmd, sender));
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                // Let the caller check for signals; partial commands are kept meanwhile
+                expire_partial(reassembler);
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        }
+        expire_partial(reassembler);
+    }
+}
+
+/// Throw away partial commands which have waited too long for the rest.
+fn expire_partial(reassembler: &mut Reassembler) {
+    for sender in reassembler.expire() {
+        log_warning!(
+            "Abandoning partial command from {sender}: timed out waiting for the rest of it"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use crate::logging::log_warning;
+use crate::stats::Stats;
+use impish_executables::metrics::{self, Kind, Metric};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/*
+ * The stats Prometheus can scrape with --metrics-addr, as udpcapture's
+ * (see impish_executables::metrics for the serving).
+ * */
+
+/// Listen on `addr` and answer scrapes from a thread of our own.
+/// Returns an error if we can't listen there.
+pub fn serve(addr: SocketAddr, stats: Arc<Stats>) -> std::io::Result<()> {
+    metrics::serve(
+        addr,
+        move || current(&stats),
+        |e| log_warning!("Cannot answer a metrics request: {e}"),
+    )?;
+    Ok(())
+}
+
+fn current(stats: &Stats) -> Vec<Metric> {
+    let metric = |name, kind, help, value: &AtomicU64| Metric {
+        name,
+        kind,
+        help,
+        value: value.load(Ordering::Relaxed),
+    };
+    vec![
+        metric(
+            "commands_received_total",
+            Kind::Counter,
+            "Commands received, including cancel requests",
+            &stats.received,
+        ),
+        metric(
+            "commands_succeeded_total",
+            Kind::Counter,
+            "Commands which exited with status 0",
+            &stats.succeeded,
+        ),
+        metric(
+            "commands_failed_total",
+            Kind::Counter,
+            "Commands which exited with another status, or couldn't be run",
+            &stats.failed,
+        ),
+        metric(
+            "commands_refused_total",
+            Kind::Counter,
+            "Commands not run because of a filter, the rate limit, being busy, or shutting down",
+            &stats.refused,
+        ),
+        metric(
+            "commands_in_flight",
+            Kind::Gauge,
+            "Commands running now",
+            &stats.in_flight,
+        ),
+        metric(
+            "reply_bytes_total",
+            Kind::Counter,
+            "Bytes of reply packets sent, not counting resends",
+            &stats.bytes_replied,
+        ),
+    ]
+}
@@ -0,0 +1,278 @@
+use clap::ValueEnum;
+#[cfg(feature = "json")]
+use serde::Serialize;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+
+/// How the sections of a response are laid out
+/// before it gets chunked into reply packets.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ReplyFormat {
+    /// Sections split by the ASCII group separator (0x1D)
+    Delimited,
+    /// Sections preceded by their big-endian u32 length
+    LengthPrefixed,
+    /// A JSON object with base64-encoded sections
+    #[cfg(feature = "json")]
+    Json,
+}
+
+/* OutputWrapper wraps a process result
+  into a nice struct. Its stderr field
+  can also capture the _shell_ stderr in case
+  of some kind of OS error getting thrown before
+  or during execution.
+*/
+pub struct OutputWrapper {
+    pub cmd: Vec<u8>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status_code: i32,
+    /// The signal which killed the command, if it didn't exit on its own.
+    pub signal: Option<i32>,
+    /// The id the client sent with the command, to echo back (see request_id.rs).
+    pub request_id: Option<Vec<u8>>,
+}
+
+impl OutputWrapper {
+    pub fn from(cmd: Vec<u8>, proc_out: Output) -> OutputWrapper {
+        OutputWrapper {
+            cmd,
+            stdout: proc_out.stdout,
+            stderr: proc_out.stderr,
+            status_code: status_code(proc_out.status),
+            signal: proc_out.status.signal(),
+            request_id: None,
+        }
+    }
+
+    /// Cut stdout and stderr down to at most `max_bytes` each,
+    /// or `max_bytes` between the two of them if `combined` is set
+    /// (stdout gets first dibs).
+    /// A marker saying how much was cut is appended to each
+    /// truncated stream, on top of the limit.
+    pub fn truncate(&mut self, max_bytes: usize, combined: bool) {
+        let stdout_kept = truncate_stream(&mut self.stdout, max_bytes);
+        let stderr_limit = if combined {
+            max_bytes - stdout_kept
+        } else {
+            max_bytes
+        };
+        truncate_stream(&mut self.stderr, stderr_limit);
+    }
+
+    /// A reply made up by the executor itself rather than a command.
+    pub fn from_message(
+        cmd: Vec<u8>,
+        status_code: i32,
+        stdout: &[u8],
+        stderr: &[u8],
+    ) -> OutputWrapper {
+        OutputWrapper {
+            cmd,
+            stdout: stdout.to_vec(),
+            stderr: stderr.to_vec(),
+            status_code,
+            signal: None,
+            request_id: None,
+        }
+    }
+
+    /// Wrap an error which kept the command from running at all.
+    pub fn from_error(cmd: Vec<u8>, e: &std::io::Error) -> OutputWrapper {
+        OutputWrapper {
+            cmd,
+            stdout: vec![],
+            stderr: format!("{e:?}").into_bytes(),
+            status_code: -1,
+            signal: None,
+            request_id: None,
+        }
+    }
+
+    /// Lay the response out in the given format.
+    /// If the client sent a request id, the delimited and length-prefixed
+    /// formats start with the line `REQ-ID <id>`, as the command did,
+    /// and the JSON object gets a `request_id` field.
+    pub fn to_packet(&self, format: ReplyFormat) -> Vec<u8> {
+        let body = match format {
+            ReplyFormat::Delimited => self.to_delimited(),
+            ReplyFormat::LengthPrefixed => self.to_length_prefixed(),
+            #[cfg(feature = "json")]
+            ReplyFormat::Json => return self.to_json(),
+        };
+        match &self.request_id {
+            Some(id) => [&b"REQ-ID "[..], id, b"\n", &body].concat(),
+            None => body,
+        }
+    }
+
+    /// The original format. Compact, but a section which
+    /// itself contains 0x1D can't be told apart from the next one,
+    /// and the status code is truncated to a u8.
+    fn to_delimited(&self) -> Vec<u8> {
+        // ASCII group separator nonprintable character
+        const GROUP_SEP: u8 = 0x1D;
+
+        let mut response =
+            Vec::with_capacity(4 + self.cmd.len() + self.stdout.len() + self.stderr.len());
+        response.push(self.status_code as u8);
+        response.push(GROUP_SEP);
+        response.extend(self.cmd.iter());
+        response.push(GROUP_SEP);
+        response.extend(self.stdout.iter());
+        response.push(GROUP_SEP);
+        response.extend(self.stderr.iter());
+        response
+    }
+
+    /// Unambiguous binary format:
+    /// ```
+    /// (u8 0 = ok, 1 = error) + (i32 status code)
+    ///     + (u32 cmd length) + (cmd)
+    ///     + (u32 stdout length) + (stdout)
+    ///     + (u32 stderr length) + (stderr)
+    /// ```
+    /// All integers are big-endian.
+    fn to_length_prefixed(&self) -> Vec<u8> {
+        let mut response =
+            Vec::with_capacity(17 + self.cmd.len() + self.stdout.len() + self.stderr.len());
+        response.push(if self.status_code == 0 { 0 } else { 1 });
+        response.extend(self.status_code.to_be_bytes());
+        for section in [&self.cmd, &self.stdout, &self.stderr] {
+            let len = u32::try_from(section.len()).expect("section should be under 4 GiB");
+            response.extend(len.to_be_bytes());
+            response.extend(section.iter());
+        }
+        response
+    }
+
+    /// A JSON object (see `JsonReply`).
+    #[cfg(feature = "json")]
+    fn to_json(&self) -> Vec<u8> {
+        use base64::Engine;
+        let base64 = |data| base64::engine::general_purpose::STANDARD.encode(data);
+        let reply = JsonReply {
+            request_id: self.request_id.as_deref().map(String::from_utf8_lossy),
+            status: if self.status_code == 0 { "ok" } else { "error" },
+            code: self.status_code,
+            signal: self.signal,
+            cmd: base64(&self.cmd),
+            stdout: base64(&self.stdout),
+            stderr: base64(&self.stderr),
+        };
+        serde_json::to_vec(&reply).expect("replies serialize")
+    }
+}
+
+/// The `--format json` response:
+/// ```
+/// {"status": "ok" | "error", "code": i32, "signal": i32 | null, "cmd": "...", "stdout": "...", "stderr": "..."}
+/// ```
+/// where `cmd`, `stdout`, and `stderr` are base64-encoded
+/// so arbitrary bytes survive.
+/// A `"request_id": "..."` field comes first if the client sent one.
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct JsonReply<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<std::borrow::Cow<'a, str>>,
+    status: &'static str,
+    code: i32,
+    signal: Option<i32>,
+    cmd: String,
+    stdout: String,
+    stderr: String,
+}
+
+/// The status code to report for a finished command.
+/// A command killed by a signal has no exit code of its own,
+/// so it gets 128 + the signal number, as a shell would report it.
+/// That is never 0, so the reply is always flagged as an error.
+pub fn status_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(sig)) => 128 + sig,
+        (None, None) => -1,
+    }
+}
+
+/// Truncate a stream to `limit` bytes, appending a marker
+/// if anything was cut off.
+/// Returns how many bytes of the original stream were kept.
+fn truncate_stream(stream: &mut Vec<u8>, limit: usize) -> usize {
+    if stream.len() <= limit {
+        return stream.len();
+    }
+    let omitted = stream.len() - limit;
+    stream.truncate(limit);
+    stream.extend(format!("...truncated ({omitted} bytes omitted)").into_bytes());
+    limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(stdout: &[u8], stderr: &[u8]) -> OutputWrapper {
+        OutputWrapper::from_message(b"cmd".to_vec(), 0, stdout, stderr)
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_layout() {
+        let mut out = output(b"hi\n", b"");
+        assert_eq!(
+            String::from_utf8(out.to_packet(ReplyFormat::Json)).unwrap(),
+            r#"{"status":"ok","code":0,"signal":null,"cmd":"Y21k","stdout":"aGkK","stderr":""}"#
+        );
+
+        out.status_code = 137;
+        out.signal = Some(9);
+        out.request_id = Some(b"abc-1".to_vec());
+        assert_eq!(
+            String::from_utf8(out.to_packet(ReplyFormat::Json)).unwrap(),
+            r#"{"request_id":"abc-1","status":"error","code":137,"signal":9,"cmd":"Y21k","stdout":"aGkK","stderr":""}"#
+        );
+    }
+
+    #[test]
+    fn truncate_stream_marks_what_was_cut() {
+        let mut stream = b"0123456789".to_vec();
+        assert_eq!(truncate_stream(&mut stream, 10), 10);
+        assert_eq!(stream, b"0123456789");
+        assert_eq!(truncate_stream(&mut stream, 4), 4);
+        assert_eq!(stream, b"0123...truncated (6 bytes omitted)");
+        let mut stream = b"abc".to_vec();
+        assert_eq!(truncate_stream(&mut stream, 0), 0);
+        assert_eq!(stream, b"...truncated (3 bytes omitted)");
+    }
+
+    #[test]
+    fn truncate_limits_each_stream_separately() {
+        let mut out = output(b"0123456789", b"abcdef");
+        out.truncate(6, false);
+        assert_eq!(out.stdout, b"012345...truncated (4 bytes omitted)");
+        assert_eq!(out.stderr, b"abcdef");
+    }
+
+    #[test]
+    fn combined_truncate_gives_stdout_first_dibs() {
+        let mut out = output(b"0123", b"abcdef");
+        out.truncate(6, true);
+        assert_eq!(out.stdout, b"0123");
+        assert_eq!(out.stderr, b"ab...truncated (4 bytes omitted)");
+
+        // With stdout taking the whole limit, stderr gets nothing
+        let mut out = output(b"0123456789", b"abc");
+        out.truncate(6, true);
+        assert_eq!(out.stdout, b"012345...truncated (4 bytes omitted)");
+        assert_eq!(out.stderr, b"...truncated (3 bytes omitted)");
+
+        // Both fit, so neither is touched
+        let mut out = output(b"012", b"abc");
+        out.truncate(6, true);
+        assert_eq!(out.stdout, b"012");
+        assert_eq!(out.stderr, b"abc");
+    }
+}
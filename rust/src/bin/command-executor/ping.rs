@@ -0,0 +1,47 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Instant;
+
+/*
+ * Health checks: a datagram which is exactly `__PING__` or `__VERSION__`
+ * (a trailing newline is allowed) is answered straight back to its sender
+ * with one line of text, e.g.
+ *     command-executor 1.1.0 up 3600s load 0.12 0.08 0.01
+ * It is never run, needs no auth token, and doesn't count as a command.
+ * */
+
+const PROBES: [&[u8]; 2] = [b"__PING__", b"__VERSION__"];
+
+/// Whether a datagram is a health check rather than a command.
+pub fn is_probe(datagram: &[u8]) -> bool {
+    let trimmed = datagram.strip_suffix(b"\n").unwrap_or(datagram);
+    PROBES.contains(&trimmed)
+}
+
+/// Reply to a health check with our version, uptime, and the load average.
+pub fn answer(sock: &UdpSocket, sender: SocketAddr, started: Instant) -> std::io::Result<()> {
+    let load = std::fs::read_to_string("/proc/loadavg")
+        .ok()
+        .map(|s| s.split_whitespace().take(3).collect::<Vec<_>>().join(" "))
+        .unwrap_or_else(|| "unknown".to_string());
+    let reply = format!(
+        "command-executor {} up {}s load {load}\n",
+        env!("CARGO_PKG_VERSION"),
+        started.elapsed().as_secs()
+    );
+    sock.send_to(reply.as_bytes(), sender)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_exact_probes_are_recognised() {
+        assert!(is_probe(b"__PING__"));
+        assert!(is_probe(b"__VERSION__\n"));
+        assert!(!is_probe(b"__PING__; rm -rf /"));
+        assert!(!is_probe(b"AUTH secret\n__PING__"));
+        assert!(!is_probe(b"echo __PING__"));
+    }
+}
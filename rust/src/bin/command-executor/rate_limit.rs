@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/*
+ * Limiting how fast each source may send commands (--rate-limit).
+ * Every source IP has a bucket holding up to N tokens, which refills
+ * at N per SECONDS; each command takes a token, and a command which
+ * finds the bucket empty is refused.
+ * A full bucket is no different from a new one, so those are forgotten,
+ * which keeps the map down to the sources heard from lately.
+ * */
+
+/// At most `commands` commands every `per`, given as `N/SECONDS` (e.g. `10/60`).
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    commands: u32,
+    per: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rate, String> {
+        let err =
+            || format!("expected N/SECONDS, e.g. `10/60` for 10 commands a minute, not `{s}`");
+        let (commands, secs) = s.split_once('/').ok_or_else(err)?;
+        let commands: u32 = commands.trim().parse().map_err(|_| err())?;
+        let secs: f64 = secs.trim().parse().map_err(|_| err())?;
+        if commands == 0 || !(secs > 0.0 && secs.is_finite()) {
+            return Err(err());
+        }
+        Ok(Rate {
+            commands,
+            per: Duration::from_secs_f64(secs),
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last_fill: Instant,
+}
+
+pub struct RateLimiter {
+    rate: Rate,
+    buckets: HashMap<IpAddr, Bucket>,
+    last_sweep: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Rate) -> RateLimiter {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Take a token for a command from `ip`.
+    /// Returns false if it has used up its rate.
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let capacity = self.rate.commands as f64;
+        let per_sec = capacity / self.rate.per.as_secs_f64();
+        // Forget sources whose buckets have had time to refill
+        if now.duration_since(self.last_sweep) >= self.rate.per {
+            self.buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.last_fill).as_secs_f64() * per_sec < capacity
+            });
+            self.last_sweep = now;
+        }
+        // IPv4 senders on a dual-stack socket share a bucket with their plain IPv4 address
+        let bucket = self.buckets.entry(ip.to_canonical()).or_insert(Bucket {
+            tokens: capacity,
+            last_fill: now,
+        });
+        let refill = now.duration_since(bucket.last_fill).as_secs_f64() * per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.last_fill = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_source_gets_its_own_rate() {
+        let mut limiter = RateLimiter::new("2/10".parse().unwrap());
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "::ffff:10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+        assert!(limiter.allow_at(a, start));
+        assert!(limiter.allow_at(a, start));
+        assert!(!limiter.allow_at(a, start), "third in a burst of 2");
+        assert!(limiter.allow_at(b, start), "other sources aren't held back");
+
+        // One token back every 5 seconds
+        let later = start + Duration::from_secs(5);
+        assert!(limiter.allow_at(a, later));
+        assert!(!limiter.allow_at(a, later));
+
+        // Full buckets are forgotten
+        let much_later = start + Duration::from_secs(60);
+        assert!(limiter.allow_at(a, much_later));
+        assert_eq!(limiter.buckets.len(), 1);
+
+        assert!("0/10".parse::<Rate>().is_err());
+        assert!("5 per 10".parse::<Rate>().is_err());
+    }
+}
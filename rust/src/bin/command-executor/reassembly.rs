@@ -0,0 +1,268 @@
+use crate::logging::log_warning;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/*
+ * Reassembly of commands which are too long for one datagram.
+ *
+ * Each datagram of a multipart command starts with a two byte header:
+ *     (u8 sequence number) + (u8 final flag: 1 on the last chunk, else 0)
+ * followed by that chunk of the command.
+ * Chunks are numbered from 0 and may arrive in any order;
+ * chunks from different senders are reassembled separately.
+ * A partial command is abandoned once it has taken longer than the
+ * reassembly timeout, or (with --recv-timeout) once its sender
+ * has gone quiet for longer than the idle timeout.
+ * Partial commands are capped in number and in total size,
+ * so senders which never finish can't use up our memory.
+ * */
+
+const HEADER_LEN: usize = 2;
+
+/// Most senders with partial commands at once;
+/// the first chunk of any more is dropped.
+const MAX_PARTIALS: usize = 64;
+
+/// Most bytes held in partial commands at once;
+/// a sender whose chunk would go over has its partial command dropped.
+const MAX_PARTIAL_BYTES: usize = 16 << 20;
+
+struct Partial {
+    started: Instant,
+    last_chunk: Instant,
+    chunks: BTreeMap<u8, Vec<u8>>,
+    last_seq: Option<u8>,
+}
+
+pub struct Reassembler {
+    timeout: Duration,
+    idle_timeout: Option<Duration>,
+    partials: HashMap<SocketAddr, Partial>,
+    // Of command in all the partials
+    bytes_held: usize,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, idle_timeout: Option<Duration>) -> Reassembler {
+        Reassembler {
+            timeout,
+            idle_timeout,
+            partials: HashMap::new(),
+            bytes_held: 0,
+        }
+    }
+
+    /// Add a datagram from `sender` to its partial command.
+    /// Returns the full command once every chunk up to the
+    /// final one has arrived, else None.
+    pub fn add(&mut self, sender: SocketAddr, datagram: &[u8]) -> Option<Vec<u8>> {
+        if datagram.len() < HEADER_LEN {
+            log_warning!("Dropping multipart chunk from {sender}: too short for a header");
+            return None;
+        }
+        let seq = datagram[0];
+        let is_final = datagram[1] != 0;
+        let chunk = &datagram[HEADER_LEN..];
+
+        if !self.partials.contains_key(&sender) && self.partials.len() >= MAX_PARTIALS {
+            log_warning!(
+                "Dropping multipart chunk from {sender}: {MAX_PARTIALS} senders already have partial commands"
+            );
+            return None;
+        }
+        let now = Instant::now();
+        let partial = self.partials.entry(sender).or_insert_with(|| Partial {
+            started: now,
+            last_chunk: now,
+            chunks: BTreeMap::new(),
+            last_seq: None,
+        });
+        partial.last_chunk = now;
+        // A repeated chunk replaces the one before
+        let replaced = partial.chunks.get(&seq).map_or(0, Vec::len);
+        if self.bytes_held - replaced + chunk.len() > MAX_PARTIAL_BYTES {
+            log_warning!(
+                "Dropping multipart command from {sender}: partial commands would take more than {MAX_PARTIAL_BYTES} bytes"
+            );
+            self.remove(sender);
+            return None;
+        }
+        self.bytes_held = self.bytes_held - replaced + chunk.len();
+        partial.chunks.insert(seq, chunk.to_vec());
+        if is_final {
+            partial.last_seq = Some(seq);
+        }
+
+        let last = partial.last_seq?;
+        if partial.chunks.keys().next_back() > Some(&last) {
+            log_warning!("Dropping multipart command from {sender}: chunk after the final one");
+            self.remove(sender);
+            return None;
+        }
+        if partial.chunks.len() != (last as usize) + 1 {
+            // Still waiting on some chunks
+            return None;
+        }
+
+        let partial = self.remove(sender)?;
+        Some(partial.chunks.into_values().flatten().collect())
+    }
+
+    /// Stop tracking a sender's partial command, returning it.
+    fn remove(&mut self, sender: SocketAddr) -> Option<Partial> {
+        let partial = self.partials.remove(&sender)?;
+        self.bytes_held -= partial.chunks.values().map(Vec::len).sum::<usize>();
+        Some(partial)
+    }
+
+    /// Throw away partial commands which have waited longer
+    /// than the reassembly timeout, or been idle longer than the idle timeout.
+    /// Returns the senders whose commands were abandoned.
+    pub fn expire(&mut self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let expired: Vec<SocketAddr> = self
+            .partials
+            .iter()
+            .filter(|(_, p)| self.deadline(p) <= now)
+            .map(|(s, _)| *s)
+            .collect();
+        for s in expired.iter() {
+            self.remove(*s);
+        }
+        expired
+    }
+
+    /// How long to wait on the socket before the next
+    /// partial command times out, or None if nothing is pending.
+    pub fn time_left(&self) -> Option<Duration> {
+        let next = self.partials.values().map(|p| self.deadline(p)).min()?;
+        let left = next.saturating_duration_since(Instant::now());
+        // A zero timeout is invalid for a socket
+        Some(std::cmp::max(left, Duration::from_millis(1)))
+    }
+
+    /// When a partial command will be abandoned if it isn't finished.
+    fn deadline(&self, partial: &Partial) -> Instant {
+        let deadline = partial.started + self.timeout;
+        match self.idle_timeout {
+            Some(idle) => deadline.min(partial.last_chunk + idle),
+            None => deadline,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(seq: u8, is_final: bool, data: &[u8]) -> Vec<u8> {
+        [&[seq, is_final as u8][..], data].concat()
+    }
+
+    fn sender(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn reassembler() -> Reassembler {
+        Reassembler::new(Duration::from_secs(60), None)
+    }
+
+    #[test]
+    fn chunks_may_arrive_in_any_order() {
+        let mut r = reassembler();
+        assert_eq!(r.add(sender(1), &chunk(2, true, b"c")), None);
+        assert_eq!(r.add(sender(1), &chunk(0, false, b"a")), None);
+        // Another sender's chunks are kept apart
+        assert_eq!(r.add(sender(2), &chunk(0, true, b"x")), Some(b"x".to_vec()));
+        assert_eq!(
+            r.add(sender(1), &chunk(1, false, b"b")),
+            Some(b"abc".to_vec())
+        );
+        assert_eq!(r.time_left(), None, "nothing left pending");
+        assert_eq!(r.bytes_held, 0);
+    }
+
+    #[test]
+    fn repeated_chunk_replaces_the_first() {
+        let mut r = reassembler();
+        assert_eq!(r.add(sender(1), &chunk(0, false, b"old")), None);
+        assert_eq!(r.add(sender(1), &chunk(0, false, b"a")), None);
+        assert_eq!(r.bytes_held, 1);
+        assert_eq!(
+            r.add(sender(1), &chunk(1, true, b"b")),
+            Some(b"ab".to_vec())
+        );
+        assert_eq!(r.bytes_held, 0);
+    }
+
+    #[test]
+    fn chunk_after_the_final_one_drops_the_command() {
+        let mut r = reassembler();
+        assert_eq!(r.add(sender(1), &chunk(1, true, b"b")), None);
+        assert_eq!(r.add(sender(1), &chunk(2, false, b"c")), None);
+        assert_eq!(r.bytes_held, 0);
+        assert_eq!(
+            r.add(sender(1), &chunk(0, false, b"a")),
+            None,
+            "started over"
+        );
+    }
+
+    #[test]
+    fn partial_commands_time_out() {
+        let mut r = Reassembler::new(Duration::from_millis(20), None);
+        assert_eq!(r.add(sender(1), &chunk(0, false, b"a")), None);
+        assert!(r.expire().is_empty(), "not timed out yet");
+        assert!(r.time_left().unwrap() <= Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(r.expire(), vec![sender(1)]);
+        assert_eq!(r.bytes_held, 0);
+        // The rest of it is the start of a new partial command
+        assert_eq!(r.add(sender(1), &chunk(1, true, b"b")), None);
+    }
+
+    #[test]
+    fn quiet_senders_time_out_first() {
+        let mut r = Reassembler::new(Duration::from_secs(60), Some(Duration::from_millis(20)));
+        assert_eq!(r.add(sender(1), &chunk(0, false, b"a")), None);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(r.expire(), vec![sender(1)]);
+    }
+
+    #[test]
+    fn number_of_senders_is_capped() {
+        let mut r = reassembler();
+        for port in 0..MAX_PARTIALS as u16 {
+            assert_eq!(r.add(sender(port), &chunk(0, false, b"a")), None);
+        }
+        let extra = sender(MAX_PARTIALS as u16);
+        assert_eq!(r.add(extra, &chunk(0, false, b"a")), None);
+        assert!(!r.partials.contains_key(&extra));
+        // Senders already going may carry on
+        assert_eq!(
+            r.add(sender(0), &chunk(1, true, b"b")),
+            Some(b"ab".to_vec())
+        );
+        assert_eq!(r.add(extra, &chunk(0, true, b"c")), Some(b"c".to_vec()));
+    }
+
+    #[test]
+    fn bytes_held_are_capped() {
+        let mut r = reassembler();
+        let big = vec![0; 8190];
+        // Spread over several senders, each short of 256 chunks
+        let place = |n: usize| (sender((n / 200) as u16), (n % 200) as u8);
+        let mut n = 0;
+        while r.bytes_held + big.len() <= MAX_PARTIAL_BYTES {
+            let (from, seq) = place(n);
+            assert_eq!(r.add(from, &chunk(seq, false, &big)), None);
+            n += 1;
+        }
+        let held = r.bytes_held;
+        let (from, seq) = place(n);
+        assert_eq!(r.add(from, &chunk(seq, false, &big)), None);
+        assert!(!r.partials.contains_key(&from), "over the cap");
+        assert!(r.bytes_held < held);
+    }
+}
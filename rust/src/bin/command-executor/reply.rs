@@ -0,0 +1,447 @@
+use crate::ack::AckTracker;
+use crate::compress;
+use crate::logging::log_warning;
+use impish_executables::crc32::Crc32;
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bytes of response data carried by each reply packet.
+pub const PAYLOAD_SIZE: usize = 512;
+/// Bytes appended after the response data of each packet.
+pub const TRAILER_SIZE: usize = 4 + 1 + 4 + 4;
+/// Bytes of CRC-32 appended after the trailer with --checksum.
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// Bytes of command output carried by each streamed packet,
+/// after its stream id and length.
+pub const STREAM_DATA_SIZE: usize = PAYLOAD_SIZE - 3;
+
+/// Longest line sent in one packet with --line-mode,
+/// after its u32 line number; longer lines are sent in pieces.
+pub const LINE_DATA_SIZE: usize = STREAM_DATA_SIZE - 4;
+
+/// Reply to the given socket with the bytes of a response
+/// (see `OutputWrapper::to_packet`), split into as many
+/// packets as needed.
+/// With `acks` (--reliable), packets are sent again until
+/// the client acknowledges them (see ack.rs).
+/// With `compress_min` (--compress), responses of at least that
+/// many bytes are gzipped first (see compress.rs).
+/// With `checksum` (--checksum), each packet ends with a CRC-32 of itself.
+/// Returns the bytes of packets sent, not counting resends.
+pub fn reply_with(
+    res_bytes: &[u8],
+    sock: &UdpSocket,
+    num_cmds_received: u8,
+    send_to_me: &str,
+    acks: Option<&AckTracker>,
+    compress_min: Option<usize>,
+    checksum: bool,
+) -> u64 {
+    let compressed = compress_min.and_then(|min| {
+        compress::compress_response(res_bytes, min)
+            .inspect_err(|e| log_warning!("Sending reply uncompressed: cannot gzip it: {e}"))
+            .ok()
+            .flatten()
+    });
+    let res_bytes = compressed.as_deref().unwrap_or(res_bytes);
+    let packets = make_packets(res_bytes, now(), num_cmds_received, checksum);
+    let acks = acks.map(|acks| (acks, acks.expect(num_cmds_received)));
+    let mut bytes_sent = 0;
+    // Replies to concurrent commands share the socket unlocked,
+    // so their packets may interleave; the counter in the trailer
+    // tells them apart.
+    for send_bytes in &packets {
+        // A failed send shouldn't take the whole executor down;
+        // give up on this reply (or leave it to the retransmits)
+        // and carry on with the next command.
+        if let Err(e) = send_packet(sock, send_bytes, send_to_me) {
+            log_warning!("Failed to send reply to {send_to_me}: {e}");
+            break;
+        }
+        bytes_sent += send_bytes.len() as u64;
+    }
+    if let Some((acks, key)) = acks {
+        acks.wait_for_acks(key, &packets, |p| resend_packet(sock, p, send_to_me));
+    }
+    bytes_sent
+}
+
+/// Send one packet, then pause a short while
+/// to not overwhelm the network stack.
+fn send_packet(sock: &UdpSocket, send_bytes: &[u8], send_to_me: &str) -> std::io::Result<()> {
+    sock.send_to(send_bytes, send_to_me)?;
+    std::thread::sleep(Duration::from_millis(10));
+    Ok(())
+}
+
+/// Send a packet which wasn't acknowledged again.
+fn resend_packet(sock: &UdpSocket, send_bytes: &[u8], send_to_me: &str) {
+    if let Err(e) = send_packet(sock, send_bytes, send_to_me) {
+        log_warning!("Failed to resend reply to {send_to_me}: {e}");
+    }
+}
+
+/// Slice the response up into fixed-size packets.
+///
+/// Packet format:
+/// ```
+/// (512x u8 response data) + (u32 timestamp) + (u8 num cmds received) + (u32 packet order) + (u32 total number of reply packets)
+/// ```
+/// The last packet's response data is padded with zeros.
+/// All integers are little-endian.
+/// With `checksum`, each packet is followed by the u32 CRC-32 of
+/// everything before it, response data and trailer both.
+pub fn make_packets(
+    res_bytes: &[u8],
+    timestamp: u32,
+    num_cmds_received: u8,
+    checksum: bool,
+) -> Vec<Vec<u8>> {
+    let total_packets = u32::try_from(res_bytes.len().div_ceil(PAYLOAD_SIZE))
+        .expect("reply should fit in u32::MAX packets");
+    (0u32..)
+        .zip(res_bytes.chunks(PAYLOAD_SIZE))
+        .map(|(packet_ordering, chunk)| {
+            make_packet(
+                chunk,
+                timestamp,
+                num_cmds_received,
+                packet_ordering,
+                total_packets,
+                checksum,
+            )
+        })
+        .collect()
+}
+
+/// Pad the response data out and put the trailer,
+/// and the checksum if wanted, after it.
+fn make_packet(
+    chunk: &[u8],
+    timestamp: u32,
+    num_cmds_received: u8,
+    packet_ordering: u32,
+    total_packets: u32,
+    checksum: bool,
+) -> Vec<u8> {
+    let mut send_bytes = Vec::with_capacity(PAYLOAD_SIZE + TRAILER_SIZE + CHECKSUM_SIZE);
+    // Put the response bytes first so we can pad it easily
+    send_bytes.extend_from_slice(chunk);
+    send_bytes.resize(PAYLOAD_SIZE, 0);
+
+    send_bytes.extend(timestamp.to_le_bytes());
+    // Put the command counter
+    send_bytes.push(num_cmds_received);
+    // Put the packet ordering
+    send_bytes.extend(packet_ordering.to_le_bytes());
+    // Put the total number of packets we'll get
+    send_bytes.extend(total_packets.to_le_bytes());
+    if checksum {
+        let mut crc = Crc32::default();
+        crc.update(&send_bytes);
+        send_bytes.extend(crc.value().to_le_bytes());
+    }
+    send_bytes
+}
+
+/// Which output of the command a streamed packet carries.
+#[derive(Clone, Copy)]
+pub enum StreamId {
+    Finished = 0,
+    Stdout = 1,
+    Stderr = 2,
+    /// The client's request id, sent first if it gave one
+    RequestId = 3,
+    /// A line of stdout, with --line-mode
+    StdoutLine = 4,
+    /// A line of stderr, with --line-mode
+    StderrLine = 5,
+}
+
+/// Sends command output as it arrives, one packet per read.
+///
+/// Streamed packets use the same trailer as `make_packets`,
+/// but their response data is laid out as
+/// ```
+/// (u8 stream id) + (u16 data length) + (data)
+/// ```
+/// The total number of packets isn't known until the command exits,
+/// so it is 0 on every packet except the last.
+/// The last packet has stream id 0 (finished), and its data is the
+/// literal `finished` followed by the i32 status code of the command,
+/// with --reliable the u32 total number of packets,
+/// and with --checksum the u32 CRC-32 of the response data
+/// of every packet before it, in order.
+/// Packets of concurrent streams may be interleaved;
+/// the command counter in the trailer tells them apart.
+///
+/// With --line-mode, output is sent a line at a time instead,
+/// with stream id 4 (stdout) or 5 (stderr), and data
+/// ```
+/// (u32 line number) + (line)
+/// ```
+/// Lines of each stream are numbered from 1.
+/// A line is sent with its newline, so a piece of a line longer
+/// than `LINE_DATA_SIZE` (or a last line without one) has none.
+pub struct Streamer<'a> {
+    sock: &'a UdpSocket,
+    num_cmds_received: u8,
+    send_to_me: &'a str,
+    packets_sent: u32,
+    // Of the packets which were sent successfully
+    bytes_sent: u64,
+    // With the key the reply's acknowledgements are tracked under
+    acks: Option<(&'a AckTracker, u64)>,
+    // Kept for retransmits when acks are expected
+    sent: Vec<Vec<u8>>,
+    // Copies of stdout and stderr, with --tee-dir
+    kept: Option<(Vec<u8>, Vec<u8>)>,
+    // Line numbers last sent on stdout and stderr, with --line-mode
+    lines_sent: [u32; 2],
+    // Of the response data sent so far, with --checksum
+    crc: Option<Crc32>,
+}
+
+impl<'a> Streamer<'a> {
+    pub fn new(
+        sock: &'a UdpSocket,
+        num_cmds_received: u8,
+        send_to_me: &'a str,
+        acks: Option<&'a AckTracker>,
+        checksum: bool,
+    ) -> Streamer<'a> {
+        let acks = acks.map(|acks| (acks, acks.expect(num_cmds_received)));
+        Streamer {
+            sock,
+            num_cmds_received,
+            send_to_me,
+            packets_sent: 0,
+            bytes_sent: 0,
+            acks,
+            sent: Vec::new(),
+            kept: None,
+            lines_sent: [0, 0],
+            crc: checksum.then(Crc32::default),
+        }
+    }
+
+    /// Keep a copy of the stdout and stderr sent, for `take_output`.
+    pub fn keep_output(&mut self) {
+        self.kept = Some((Vec::new(), Vec::new()));
+    }
+
+    /// The stdout and stderr sent so far, if `keep_output` was called.
+    pub fn take_output(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.kept.take()
+    }
+
+    /// Send some output from one stream, split over
+    /// several packets if need be.
+    pub fn send(&mut self, stream: StreamId, data: &[u8]) {
+        self.keep(stream, data);
+        for chunk in data.chunks(STREAM_DATA_SIZE) {
+            self.send_packet(stream, chunk, 0);
+        }
+    }
+
+    /// Send some stdout or stderr a line per packet (--line-mode),
+    /// splitting lines longer than `LINE_DATA_SIZE` into pieces.
+    /// Each piece gets the next line number of its stream.
+    pub fn send_lines(&mut self, stream: StreamId, data: &[u8]) {
+        self.keep(stream, data);
+        let (line_stream, index) = match stream {
+            StreamId::Stderr => (StreamId::StderrLine, 1),
+            _ => (StreamId::StdoutLine, 0),
+        };
+        for line in data.split_inclusive(|b| *b == b'\n') {
+            for piece in line.chunks(LINE_DATA_SIZE) {
+                self.lines_sent[index] += 1;
+                let mut chunk = Vec::with_capacity(4 + piece.len());
+                chunk.extend(self.lines_sent[index].to_le_bytes());
+                chunk.extend_from_slice(piece);
+                self.send_packet(line_stream, &chunk, 0);
+            }
+        }
+    }
+
+    /// With `keep_output`, copy stdout and stderr as they are sent.
+    fn keep(&mut self, stream: StreamId, data: &[u8]) {
+        match (&mut self.kept, stream) {
+            (Some((stdout, _)), StreamId::Stdout) => stdout.extend(data),
+            (Some((_, stderr)), StreamId::Stderr) => stderr.extend(data),
+            _ => {}
+        }
+    }
+
+    /// Send the end-of-stream packet with the status code of the command,
+    /// then with --reliable resend whatever isn't acknowledged.
+    /// Returns the bytes of packets sent, not counting resends.
+    pub fn finish(mut self, status_code: i32) -> u64 {
+        let mut data = b"finished".to_vec();
+        data.extend(status_code.to_le_bytes());
+        let total_packets = self.packets_sent + 1;
+        if self.acks.is_some() {
+            data.extend(total_packets.to_le_bytes());
+        }
+        if let Some(crc) = self.crc {
+            data.extend(crc.value().to_le_bytes());
+        }
+        self.send_packet(StreamId::Finished, &data, total_packets);
+        if let Some((acks, key)) = self.acks {
+            acks.wait_for_acks(key, &self.sent, |p| {
+                resend_packet(self.sock, p, self.send_to_me)
+            });
+        }
+        self.bytes_sent
+    }
+
+    fn send_packet(&mut self, stream: StreamId, data: &[u8], total_packets: u32) {
+        let mut chunk = Vec::with_capacity(3 + data.len());
+        chunk.push(stream as u8);
+        chunk.extend((data.len() as u16).to_le_bytes());
+        chunk.extend_from_slice(data);
+        let send_bytes = make_packet(
+            &chunk,
+            now(),
+            self.num_cmds_received,
+            self.packets_sent,
+            total_packets,
+            self.crc.is_some(),
+        );
+        self.packets_sent += 1;
+        if let Some(crc) = &mut self.crc {
+            crc.update(&send_bytes[..PAYLOAD_SIZE]);
+        }
+
+        let sent = send_packet(self.sock, &send_bytes, self.send_to_me);
+        // Keep streaming even if one packet fails; the error may be transient
+        match sent {
+            Ok(()) => self.bytes_sent += send_bytes.len() as u64,
+            Err(e) => log_warning!("Failed to send streamed output to {}: {e}", self.send_to_me),
+        }
+        if self.acks.is_some() {
+            self.sent.push(send_bytes);
+        }
+    }
+}
+
+/// Current Unix time in seconds, as sent in the trailer.
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time should go forward")
+        .as_secs() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet_ordering(packet: &[u8]) -> u32 {
+        u32::from_le_bytes(
+            packet[PAYLOAD_SIZE + 5..PAYLOAD_SIZE + 9]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    fn total_packets(packet: &[u8]) -> u32 {
+        u32::from_le_bytes(
+            packet[PAYLOAD_SIZE + 9..PAYLOAD_SIZE + 13]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn large_reply_reassembles_in_order() {
+        // ~1 MB is well past where an 8 or 16 bit sequence number wraps
+        let response: Vec<u8> = (0..1_000_003u32).map(|i| (i % 251) as u8).collect();
+        let mut packets = make_packets(&response, 1234, 7, false);
+        let expected_total = response.len().div_ceil(PAYLOAD_SIZE) as u32;
+        assert_eq!(packets.len() as u32, expected_total);
+
+        // Scramble the arrival order, then put it back together
+        // using only what is in the trailers.
+        packets.reverse();
+        packets.sort_by_key(|p| packet_ordering(p));
+        let mut reassembled = Vec::new();
+        for (i, p) in packets.iter().enumerate() {
+            assert_eq!(p.len(), PAYLOAD_SIZE + TRAILER_SIZE);
+            assert_eq!(packet_ordering(p), i as u32);
+            assert_eq!(total_packets(p), expected_total);
+            assert_eq!(p[PAYLOAD_SIZE + 4], 7);
+            reassembled.extend_from_slice(&p[..PAYLOAD_SIZE]);
+        }
+        reassembled.truncate(response.len());
+        assert_eq!(reassembled, response);
+    }
+
+    #[test]
+    fn last_packet_is_zero_padded() {
+        let packets = make_packets(b"hello", 0, 0, false);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(&packets[0][..5], b"hello");
+        assert!(packets[0][5..PAYLOAD_SIZE].iter().all(|b| *b == 0));
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = Crc32::default();
+        crc.update(bytes);
+        crc.value()
+    }
+
+    fn checksum_ok(packet: &[u8]) -> bool {
+        let (covered, crc) = packet.split_at(PAYLOAD_SIZE + TRAILER_SIZE);
+        crc == crc32(covered).to_le_bytes()
+    }
+
+    #[test]
+    fn checksum_covers_data_and_trailer() {
+        let packets = make_packets(&[7; PAYLOAD_SIZE + 1], 1234, 7, true);
+        assert_eq!(packets.len(), 2);
+        for p in &packets {
+            assert_eq!(p.len(), PAYLOAD_SIZE + TRAILER_SIZE + CHECKSUM_SIZE);
+            assert!(checksum_ok(p));
+        }
+        // Corrupting the data or the trailer is caught
+        for i in [0, PAYLOAD_SIZE + 5] {
+            let mut bad = packets[0].clone();
+            bad[i] ^= 1;
+            assert!(!checksum_ok(&bad));
+        }
+    }
+
+    #[test]
+    fn finished_packet_checksums_the_whole_stream() {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let send_to_me = client.local_addr().unwrap().to_string();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut streamer = Streamer::new(&sock, 3, &send_to_me, None, true);
+        streamer.send(StreamId::Stdout, &[b'o'; 1000]);
+        streamer.send(StreamId::Stderr, b"oops");
+        streamer.finish(2);
+
+        let mut data = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = client.recv(&mut buf).unwrap();
+            let packet = &buf[..n];
+            assert!(checksum_ok(packet));
+            if packet[0] == StreamId::Finished as u8 {
+                let mut expected = b"finished".to_vec();
+                expected.extend(2i32.to_le_bytes());
+                expected.extend(crc32(&data).to_le_bytes());
+                assert_eq!(&packet[1..3], (expected.len() as u16).to_le_bytes());
+                assert_eq!(&packet[3..3 + expected.len()], expected);
+                assert_eq!(total_packets(packet), 4);
+                break;
+            }
+            data.extend(&packet[..PAYLOAD_SIZE]);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+/*
+ * Client-supplied request IDs.
+ *
+ * A command may start with the line `REQ-ID <id>` (after the AUTH
+ * line, if any; ended by a NUL with --framing nul). That line is
+ * stripped off before the command runs, and the id is echoed back
+ * in the reply, so a client with several commands in flight
+ * can tell which reply is for which.
+ * */
+use crate::framing::Framing;
+
+const REQ_ID_PREFIX: &[u8] = b"REQ-ID ";
+
+/// Longest id accepted; a UUID is 36 bytes.
+const MAX_ID_LEN: usize = 64;
+
+/// Split the `REQ-ID <id>` line off the start of a command.
+/// Returns the id, if there was one, and the command which follows.
+/// Ids are 1 to 64 characters from `A-Z a-z 0-9 . _ : -`, so they can be
+/// echoed back as-is in any reply format; others are an error.
+pub fn split_request_id(
+    cmd: Vec<u8>,
+    framing: Framing,
+) -> Result<(Option<Vec<u8>>, Vec<u8>), String> {
+    let Some(rest) = cmd.strip_prefix(REQ_ID_PREFIX) else {
+        return Ok((None, cmd));
+    };
+    let (id, rest) = framing.split_field(rest);
+    let valid = |b: &u8| b.is_ascii_alphanumeric() || b".:_-".contains(b);
+    if id.is_empty() || id.len() > MAX_ID_LEN || !id.iter().all(valid) {
+        return Err(format!(
+            "request id {:?} should be 1 to {MAX_ID_LEN} of A-Z a-z 0-9 . _ : -",
+            String::from_utf8_lossy(id)
+        ));
+    }
+    Ok((Some(id.to_vec()), rest.to_vec()))
+}
@@ -0,0 +1,150 @@
+use clap::ValueEnum;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// What to do with a command when `--max-concurrent` are already running.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum WhenBusy {
+    /// Wait for a free slot, with up to --max-queued commands waiting at once
+    Queue,
+    /// Reply `server busy` right away
+    Reject,
+}
+
+/// A counting semaphore which bounds how many
+/// commands may run at the same time,
+/// and how many may wait their turn.
+/// Waiting commands get slots in the order they joined the queue.
+pub struct Semaphore {
+    slots: usize,
+    state: Mutex<State>,
+    freed: Condvar,
+}
+
+struct State {
+    available: usize,
+    // The queue is the tickets from `serving` up to `next_ticket`
+    next_ticket: u64,
+    serving: u64,
+}
+
+/// A slot taken from a `Semaphore`; the slot is
+/// given back when this is dropped.
+pub struct Permit {
+    sem: Arc<Semaphore>,
+}
+
+/// A place in the queue for a slot, from `Semaphore::enqueue`.
+/// Those behind it wait until it is acquired.
+pub struct Waiting {
+    sem: Arc<Semaphore>,
+    ticket: u64,
+}
+
+impl Semaphore {
+    pub fn new(slots: usize) -> Semaphore {
+        Semaphore {
+            slots,
+            state: Mutex::new(State {
+                available: slots,
+                next_ticket: 0,
+                serving: 0,
+            }),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("semaphore lock poisoned")
+    }
+
+    /// Take a slot if one is free and nothing is queued for it, without waiting.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let mut state = self.lock();
+        if state.available == 0 || state.serving != state.next_ticket {
+            return None;
+        }
+        state.available -= 1;
+        Some(Permit {
+            sem: Arc::clone(self),
+        })
+    }
+
+    /// Join the queue for a slot, unless `max_waiting` are already in it.
+    pub fn enqueue(self: &Arc<Self>, max_waiting: usize) -> Option<Waiting> {
+        let mut state = self.lock();
+        if state.next_ticket - state.serving >= max_waiting as u64 {
+            return None;
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        Some(Waiting {
+            sem: Arc::clone(self),
+            ticket,
+        })
+    }
+
+    /// Check if every slot is free and nothing is queued for one,
+    /// i.e. nothing is running or about to.
+    pub fn is_idle(&self) -> bool {
+        let state = self.lock();
+        state.available == self.slots && state.serving == state.next_ticket
+    }
+}
+
+impl Waiting {
+    /// Wait until this is first in the queue and a slot is free, and take it.
+    pub fn acquire(self) -> Permit {
+        let mut state = self.sem.lock();
+        while state.available == 0 || state.serving != self.ticket {
+            state = self.sem.freed.wait(state).expect("semaphore lock poisoned");
+        }
+        state.available -= 1;
+        state.serving += 1;
+        // The next in line may be able to go too
+        self.sem.freed.notify_all();
+        drop(state);
+        Permit { sem: self.sem }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.sem.lock().available += 1;
+        self.sem.freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_is_bounded_and_in_order() {
+        let sem = Arc::new(Semaphore::new(1));
+        let running = sem.try_acquire().unwrap();
+        assert!(sem.try_acquire().is_none());
+        let first = sem.enqueue(2).unwrap();
+        let second = sem.enqueue(2).unwrap();
+        assert!(sem.enqueue(2).is_none(), "queue is full");
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let threads: Vec<_> = [(2, second), (1, first)]
+            .into_iter()
+            .map(|(n, waiting)| {
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    let _permit = waiting.acquire();
+                    tx.send(n).unwrap();
+                })
+            })
+            .collect();
+        assert!(!sem.is_idle());
+        drop(running);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [1, 2]);
+        assert!(sem.is_idle());
+        assert!(sem.try_acquire().is_some());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::logging::log_info;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/*
+ * Counters of what the executor has done, so we can tell how busy it
+ * is and how much is failing. They are logged every --stats-interval,
+ * on SIGUSR1, and once more on shutdown, and with the `metrics` feature
+ * served to Prometheus (see metrics.rs).
+ * */
+
+#[derive(Default)]
+pub struct Stats {
+    // Commands taken, including cancel requests
+    pub received: AtomicU64,
+    // Commands which exited with status 0
+    pub succeeded: AtomicU64,
+    // Commands which exited with another status, or couldn't be run
+    pub failed: AtomicU64,
+    // Commands not run because of --allow-pattern, --deny-pattern, --rate-limit,
+    // or being busy or shutting down
+    pub refused: AtomicU64,
+    // Bytes of reply packets sent, not counting resends
+    pub bytes_replied: AtomicU64,
+    // Commands running right now; goes down as well as up
+    pub in_flight: AtomicU64,
+}
+
+impl Stats {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count a command as running until the returned guard is dropped.
+    pub fn start_command(&self) -> InFlight<'_> {
+        Stats::add(&self.in_flight, 1);
+        InFlight { stats: self }
+    }
+
+    /// Count a command which ran as having succeeded or failed.
+    pub fn add_finished(&self, status_code: i32) {
+        match status_code {
+            0 => Stats::add(&self.succeeded, 1),
+            _ => Stats::add(&self.failed, 1),
+        }
+    }
+
+    pub fn log(&self) {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        log_info!(
+            "Stats: received {} commands, {} succeeded, {} failed, {} refused, {} running, replied with {} bytes",
+            get(&self.received),
+            get(&self.succeeded),
+            get(&self.failed),
+            get(&self.refused),
+            get(&self.in_flight),
+            get(&self.bytes_replied),
+        );
+    }
+}
+
+/// A running command, from `Stats::start_command`.
+pub struct InFlight<'a> {
+    stats: &'a Stats,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * The few libc calls we need that std doesn't wrap,
+ * made through the libc crate so the structs and constants
+ * are laid out right for whatever we are built for.
+ * */
+use std::ffi::{CStr, CString, c_char, c_int};
+
+/// Resources which `set_limit` can limit (see man setrlimit).
+pub const RLIMIT_CPU: c_int = libc::RLIMIT_CPU as c_int;
+pub const RLIMIT_NPROC: c_int = libc::RLIMIT_NPROC as c_int;
+pub const RLIMIT_AS: c_int = libc::RLIMIT_AS as c_int;
+
+/// The name of a resource, as used in messages.
+pub fn limit_name(resource: c_int) -> &'static str {
+    match resource {
+        RLIMIT_CPU => "RLIMIT_CPU",
+        RLIMIT_NPROC => "RLIMIT_NPROC",
+        RLIMIT_AS => "RLIMIT_AS",
+        _ => "unknown",
+    }
+}
+
+/// Send SIGKILL to every process in a process group.
+pub fn kill_process_group(pgid: u32) -> std::io::Result<()> {
+    let pgid = c_int::try_from(pgid).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+    // Safety: kill takes no pointers; a negative pid names a process group
+    if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set both the soft and hard limit of a resource for this process,
+/// to `value` or the current hard limit, whichever is lower
+/// (only root may raise a hard limit).
+/// Only makes system calls, so it is safe to call between fork and exec.
+pub fn set_limit(resource: c_int, value: u64) -> std::io::Result<()> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // The resource's type differs between libcs
+    // Safety: the pointer is to a live rlimit
+    if unsafe { libc::getrlimit(resource as _, &mut limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // rlim_t may be narrower than u64; too big for it is as good as unlimited
+    let value = libc::rlim_t::try_from(value)
+        .unwrap_or(libc::RLIM_INFINITY)
+        .min(limit.rlim_max);
+    let limit = libc::rlimit {
+        rlim_cur: value,
+        rlim_max: value,
+    };
+    // Safety: as above
+    if unsafe { libc::setrlimit(resource as _, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A POSIX extended regular expression (see man regcomp), as `grep -E` takes.
+pub struct Regex {
+    // Boxed so it never moves once compiled
+    compiled: Box<libc::regex_t>,
+}
+
+// Safety: a compiled regex_t is only read by regexec,
+// which may be called on it from several threads at once
+unsafe impl Send for Regex {}
+unsafe impl Sync for Regex {}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let c_pattern =
+            CString::new(pattern).map_err(|_| "pattern contains a NUL byte".to_string())?;
+        // Safety: all zeroes is a valid (if uncompiled) regex_t
+        let mut compiled: Box<libc::regex_t> = Box::new(unsafe { std::mem::zeroed() });
+        // POSIX extended syntax, and we only want to know whether it matched
+        let flags = libc::REG_EXTENDED | libc::REG_NOSUB;
+        // Safety: compiled is a regex_t, and c_pattern is NUL-terminated
+        let err = unsafe { libc::regcomp(&mut *compiled, c_pattern.as_ptr(), flags) };
+        if err != 0 {
+            let mut msg = [0 as c_char; 256];
+            // Safety: regerror writes at most msg.len() bytes, NUL-terminated
+            unsafe { libc::regerror(err, &*compiled, msg.as_mut_ptr(), msg.len()) };
+            // Safety: as above
+            let msg = unsafe { CStr::from_ptr(msg.as_ptr()) };
+            return Err(msg.to_string_lossy().into_owned());
+        }
+        Ok(Regex { compiled })
+    }
+
+    /// Whether the pattern matches somewhere in `text`.
+    /// Text with a NUL byte in it can't be matched, so never does.
+    pub fn is_match(&self, text: &[u8]) -> bool {
+        let Ok(text) = CString::new(text) else {
+            return false;
+        };
+        // Safety: compiled was compiled by regcomp, and text is NUL-terminated;
+        // REG_NOSUB means no match positions are written
+        unsafe { libc::regexec(&*self.compiled, text.as_ptr(), 0, std::ptr::null_mut(), 0) == 0 }
+    }
+}
+
+impl Drop for Regex {
+    fn drop(&mut self) {
+        // Safety: compiled was compiled by regcomp and isn't used again
+        unsafe { libc::regfree(&mut *self.compiled) };
+    }
+}
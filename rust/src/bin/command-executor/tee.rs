@@ -0,0 +1,127 @@
+use crate::output::OutputWrapper;
+use chrono::Utc;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/*
+ * Copies of command output kept on disk (--tee-dir), so the full output
+ * of a long job survives even if its reply is lost or truncated.
+ *
+ * Each command gets a file named like udpcapture's:
+ *     cmd_<time>_<N>[_<request id>].out
+ * with the time in UTC as in udpcapture's default --filename-format, and N counting up
+ * from 0 among commands which finish in the same second.
+ * The file holds:
+ *     cmd: <the command, on one line>
+ *     code: <status code>
+ *     signal: <signal number, if the command was killed by one>
+ *     stdout: <length> bytes
+ *     <stdout>
+ *     stderr: <length> bytes
+ *     <stderr>
+ * Each section is followed by a newline; the lengths say where it really ends.
+ * */
+
+/// Names tried before giving up on finding an unused one.
+const MAX_NAME_ATTEMPTS: u32 = 1000;
+
+/// strftime pattern for the time in file names: year, day of year, then time of day.
+const TIME_FORMAT: &str = "%Y-%j-%H-%M-%S";
+
+pub struct TeeDir {
+    dir: PathBuf,
+}
+
+impl TeeDir {
+    /// Use a directory for output files, creating it if need be.
+    pub fn open(dir: &Path) -> std::io::Result<TeeDir> {
+        std::fs::create_dir_all(dir)?;
+        Ok(TeeDir {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Write a command's output to a new file, synced to disk.
+    /// Returns the file's path.
+    pub fn write(&self, res: &OutputWrapper) -> std::io::Result<PathBuf> {
+        let (path, mut file) = self.create(res.request_id.as_deref())?;
+        file.write_all(&contents(res))?;
+        file.sync_all()?;
+        Ok(path)
+    }
+
+    /// Create a file with a name no other command has used.
+    fn create(&self, request_id: Option<&[u8]>) -> std::io::Result<(PathBuf, File)> {
+        let time = Utc::now().format(TIME_FORMAT);
+        // Ids need no escaping (see request_id.rs)
+        let id = match request_id {
+            Some(id) => format!("_{}", String::from_utf8_lossy(id)),
+            None => String::new(),
+        };
+        for n in 0..MAX_NAME_ATTEMPTS {
+            let path = self.dir.join(format!("cmd_{time}_{n}{id}.out"));
+            // Commands finishing at once race for names, so only create new ones
+            match File::options().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(std::io::Error::other(format!(
+            "the {MAX_NAME_ATTEMPTS} names tried for cmd_{time}_N{id}.out all exist"
+        )))
+    }
+}
+
+fn contents(res: &OutputWrapper) -> Vec<u8> {
+    let cmd = String::from_utf8_lossy(&res.cmd);
+    let mut out = format!(
+        "cmd: {}\ncode: {}\n",
+        cmd.trim_end().replace('\n', "; "),
+        res.status_code
+    );
+    if let Some(sig) = res.signal {
+        out.push_str(&format!("signal: {sig}\n"));
+    }
+    let mut out = out.into_bytes();
+    for (name, section) in [("stdout", &res.stdout), ("stderr", &res.stderr)] {
+        out.extend(format!("{name}: {} bytes\n", section.len()).into_bytes());
+        out.extend(section);
+        out.push(b'\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_command_gets_its_own_file() {
+        let dir = std::env::temp_dir().join(format!("tee-{}", std::process::id()));
+        let tee = TeeDir::open(&dir).unwrap();
+        let mut res = OutputWrapper::from_message(
+            b"echo hi\necho oops >&2\n".to_vec(),
+            3,
+            b"hi\n",
+            b"oops\n",
+        );
+        res.request_id = Some(b"job-7".to_vec());
+        let first = tee.write(&res).unwrap();
+        let second = tee.write(&res).unwrap();
+        assert_ne!(first, second);
+        for path in [&first, &second] {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            assert!(
+                name.starts_with("cmd_") && name.ends_with("_job-7.out"),
+                "{name}"
+            );
+        }
+        assert_eq!(
+            std::fs::read(&first).unwrap(),
+            b"cmd: echo hi; echo oops >&2\ncode: 3\nstdout: 3 bytes\nhi\n\nstderr: 5 bytes\noops\n\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
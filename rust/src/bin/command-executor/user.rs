@@ -0,0 +1,75 @@
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::str::FromStr;
+
+/*
+ * The user commands are run as, given by `--run-as`.
+ * Users are looked up by name or by uid through the C library,
+ * so any source it is set up for (/etc/passwd, LDAP, sssd, ...) counts.
+ * */
+
+#[derive(Clone, Debug)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+impl FromStr for User {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<User, String> {
+        let name = CString::new(s).map_err(|_| format!("no user `{s}`"))?;
+        // Safety: name is NUL-terminated, and lookup hands over live buffers
+        let by_name = lookup(|pwd, buf, result| unsafe {
+            libc::getpwnam_r(name.as_ptr(), pwd, buf.as_mut_ptr(), buf.len(), result)
+        });
+        let user = match (by_name, s.parse::<libc::uid_t>()) {
+            (Ok(None), Ok(uid)) => {
+                // Safety: lookup hands over live buffers
+                lookup(|pwd, buf, result| unsafe {
+                    libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), result)
+                })
+            }
+            (by_name, _) => by_name,
+        };
+        user.map_err(|e| format!("cannot look up user `{s}`: {e}"))?
+            .ok_or(format!("no user `{s}`"))
+    }
+}
+
+/// Run a getpw*_r lookup, with a buffer big enough for the entry.
+/// Returns None if there is no such user.
+fn lookup(
+    get: impl Fn(&mut libc::passwd, &mut [c_char], &mut *mut libc::passwd) -> c_int,
+) -> std::io::Result<Option<User>> {
+    // Safety: all zeroes is a valid passwd (its pointers null)
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf: Vec<c_char> = vec![0; 1024];
+    let mut result = std::ptr::null_mut();
+    loop {
+        match get(&mut pwd, &mut buf, &mut result) {
+            0 => break,
+            // Some entries (e.g. with long lists of groups) need more room
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            err => return Err(std::io::Error::from_raw_os_error(err)),
+        }
+    }
+    if result.is_null() {
+        return Ok(None);
+    }
+    // Safety: on success the strings point into buf, which outlives this
+    let text = |s: *const c_char| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned();
+    Ok(Some(User {
+        name: text(pwd.pw_name),
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        home: text(pwd.pw_dir),
+    }))
+}
+
+/// Check if a command failed to start because the executor
+/// isn't allowed to switch to the `--run-as` user.
+pub fn is_switch_denied(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EPERM)
+}
@@ -4,7 +4,7 @@ const NUM_CHAN: usize = 4;
 
 /// Accumulate the data in `spectra` into `sum_bins` using the ranges optionally defined by `rebin_edges`.
 fn accumulate_spec(
-    sum_bins: &mut Vec<Vec<u32>>,
+    sum_bins: &mut [Vec<u32>],
     spectra: [[u16; 1000]; NUM_CHAN],
     rebin_edges: &Option<Vec<u16>>,
 ) {
@@ -27,9 +27,8 @@ fn accumulate_spec(
 }
 
 /// Parse the rebin edges string into a Vec<u16>.
-fn parse_rebin_edges(bins: &String) -> Vec<u16> {
+fn parse_rebin_edges(bins: &str) -> Vec<u16> {
     bins.split("-")
-        .into_iter()
         .map(|b| {
             b.parse::<u16>()
                 .expect("Must be able to parse energy bin edge")
@@ -41,10 +40,10 @@ fn parse_rebin_edges(bins: &String) -> Vec<u16> {
 fn parse_daqbox_spectrum(data: &[u8]) -> [[u16; 1000]; NUM_CHAN] {
     let mut ret: [[u16; 1000]; NUM_CHAN] = [[0; 1000]; NUM_CHAN];
     for i in (0..data.len()).step_by(8) {
-        for chan in 0..4 {
+        for (chan, spectrum) in ret.iter_mut().enumerate() {
             let start = i + chan * 2;
             // 2B per channel
-            ret[chan][i / (2 * NUM_CHAN)] = (data[start] as u16) * 256 + (data[start + 1] as u16);
+            spectrum[i / (2 * NUM_CHAN)] = (data[start] as u16) * 256 + (data[start + 1] as u16);
         }
     }
     ret
@@ -69,12 +68,11 @@ fn main() {
     let my_sock = UdpSocket::bind(format!("0.0.0.0:{listen_port}"))
         .expect("Need to be able to bind UDP listen socket");
 
-    let rebin_edges: Option<Vec<u16>>;
-    if args.len() > 4 && args[4].len() > 0 {
-        rebin_edges = Some(parse_rebin_edges(&args[4]));
+    let rebin_edges = if args.len() > 4 && !args[4].is_empty() {
+        Some(parse_rebin_edges(&args[4]))
     } else {
-        rebin_edges = None;
-    }
+        None
+    };
 
     let num_bins = if let Some(e) = &rebin_edges {
         e.len() - 1
@@ -129,9 +127,7 @@ fn main() {
 
             // Clear the accumulated packet data
             for bin_set in &mut sum_bins {
-                for i in 0..bin_set.len() {
-                    bin_set[i] = 0;
-                }
+                bin_set.fill(0);
             }
         }
     }
@@ -1,6 +1,18 @@
-use clap::{ArgGroup, Parser};
-use std::net::SocketAddr;
+use crate::compress::Compression;
+use crate::forward::{ForwardFilter, ForwardMode, Rate};
+use crate::multicast::MulticastGroup;
+use crate::writer::{DEFAULT_TIME_FORMAT, LatestFile, OutputFormat};
+use chrono::format::{Item, StrftimeItems};
+use clap::error::ErrorKind;
+use clap::{ArgGroup, CommandFactory, Parser, ValueEnum};
+use impish_executables::cidr::Cidr;
+use impish_executables::config::{self, Settings};
+use impish_executables::merge_settings;
+use serde::Deserialize;
+use serde::de::{Deserializer, Error as _};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::option::Option;
+use std::path::{Path, PathBuf};
 
 /*
  * Command line args for udpcapture.
@@ -13,12 +25,17 @@ use std::option::Option;
 #[clap(group(
     ArgGroup::new("outputs")
         .required(true)
-        .args(&["base_filename", "forward_addrs"])
+        .args(&["base_filename", "forward_addrs", "forward_tcp"])
+        .multiple(true)
+))]
+#[clap(group(
+    ArgGroup::new("forwards")
+        .args(&["forward_addrs", "forward_tcp"])
         .multiple(true)
 ))]
 #[clap(group(
     ArgGroup::new("fileopts")
-        .args(&["file_lifetime", "max_file_size"])
+        .args(&["file_lifetime", "max_file_size", "rotate_on"])
         .multiple(true)
 ))]
 // Info on the command itself
@@ -28,12 +45,137 @@ use std::option::Option;
     long_about=None
 )]
 pub struct ProgramArgs {
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Read settings from a TOML file of `option_name = value` lines; options given on the command line win"
+    )]
+    pub config: Option<PathBuf>,
+
     #[arg(
         short = 'p',
         long,
-        help = "UDP port to listen on, in native endian representation"
+        required_unless_present = "replay",
+        value_delimiter = ',',
+        help = "UDP port to listen on, in native endian representation; may be repeated or comma-separated"
+    )]
+    pub port: Vec<u16>,
+
+    #[arg(
+        long,
+        value_name = "ADDR",
+        default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        help = "Local IPv4 or IPv6 address to listen on"
+    )]
+    pub bind: IpAddr,
+
+    #[arg(
+        long,
+        value_name = "GROUP[@INTERFACE]",
+        help = "Join this multicast group on every port; the interface is an address for IPv4, a name or index for IPv6; may be repeated"
     )]
-    pub port: u16,
+    pub multicast_group: Vec<MulticastGroup>,
+
+    #[arg(
+        long,
+        value_name = "PACKETS",
+        default_value_t = 4096,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Packets which may wait to be written and forwarded; more are dropped, and the drops logged"
+    )]
+    pub queue_depth: u32,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Size of the kernel's receive buffer for each socket, to absorb bursts [default: the system's]"
+    )]
+    pub rcvbuf: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Let other udpcapture processes listen on the same ports (SO_REUSEPORT); the kernel shares packets between them"
+    )]
+    pub reuse_port: bool,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Only capture packets which arrive on this network interface, e.g. eth1 (SO_BINDTODEVICE)"
+    )]
+    pub interface: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Log counts of packets received, dropped, written, and forwarded this often [default: only on SIGUSR1 and exit]"
+    )]
+    pub stats_interval: Option<u64>,
+
+    #[cfg(feature = "metrics")]
+    #[arg(
+        long,
+        value_name = "ADDR:PORT",
+        help = "Serve the stats over HTTP at this address, for Prometheus to scrape"
+    )]
+    pub metrics_addr: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        value_name = "IP or CIDR",
+        help = "Only capture and forward packets from this address or CIDR block (IPv4 or IPv6); may be repeated [default: everyone]"
+    )]
+    pub from: Vec<Cidr>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Drop packets shorter than N bytes, without writing or forwarding them"
+    )]
+    pub min_packet_bytes: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Drop packets longer than N bytes, without writing or forwarding them"
+    )]
+    pub max_packet_bytes: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Write and forward zero-length packets, rather than dropping them [default: drop them]"
+    )]
+    pub keep_empty: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Only write every Nth packet to file"
+    )]
+    pub sample: u64,
+
+    #[arg(
+        long,
+        requires = "forwards",
+        help = "Apply --sample to forwarded packets too, rather than forwarding every packet"
+    )]
+    pub sample_forwarded: bool,
+
+    #[arg(
+        long,
+        help = "Don't write a packet which is the same as the one before it"
+    )]
+    pub dedup: bool,
+
+    #[arg(
+        long,
+        requires_all = ["dedup", "forwards"],
+        help = "Don't forward a packet which is the same as the one before it either"
+    )]
+    pub dedup_forwarded: bool,
 
     #[arg(
         short = 's',
@@ -43,13 +185,109 @@ pub struct ProgramArgs {
     )]
     pub max_file_size: Option<u64>,
 
+    #[arg(
+        long,
+        requires = "max_file_size",
+        help = "Drop packets too big to fit in a file of --max-file-size, rather than writing each to a file of its own"
+    )]
+    pub drop_oversized: bool,
+
+    #[arg(
+        long,
+        requires = "base_filename",
+        help = "Delete the oldest files this run wrote once together they take up more than this (bytes)"
+    )]
+    pub max_total_bytes: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "base_filename",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Delete the oldest files this run wrote once there are more than N"
+    )]
+    pub max_files: Option<u64>,
+
+    #[arg(
+        long,
+        requires = "base_filename",
+        help = "Wait for each file to be written to disk when it is closed, before post-processing it"
+    )]
+    pub fsync: bool,
+
+    #[arg(
+        long,
+        requires = "base_filename",
+        help = "Write the CRC-32 of each file (as on disk, so after any compression) to <file>.crc when it is closed"
+    )]
+    pub checksum: bool,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        requires = "base_filename",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Flush buffered data to the open file this often, so less is lost if udpcapture is killed [default: only when the file closes]"
+    )]
+    pub flush_interval: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "base_filename",
+        help = "Append a JSON line describing each file to FILE when it is closed"
+    )]
+    pub manifest: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "base_filename",
+        help = "Keep a symlink at PATH to the newest file, replaced atomically"
+    )]
+    pub latest_symlink: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LatestFile::Closed,
+        requires = "latest_symlink",
+        help = "Which file --latest-symlink points at"
+    )]
+    pub latest_file: LatestFile,
+
     #[arg(
         short = 'l',
         long,
         group = "fileopts",
-        help = "Maximum file lifetime before close (seconds)"
+        help = "Maximum file lifetime before close (seconds) [default: no limit]"
+    )]
+    pub file_lifetime: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "minute|hour|NNs",
+        value_parser = parse_rotate_period,
+        group = "fileopts",
+        help = "Close files on wall-clock boundaries: every minute, every hour, or every NN seconds since midnight UTC"
+    )]
+    pub rotate_on: Option<u64>,
+
+    #[arg(
+        long,
+        requires = "base_filename",
+        help = "Exit once the first file has been closed and post-processed"
+    )]
+    pub once: bool,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        conflicts_with = "replay",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Stop capturing after this long, closing and post-processing the open file, and exit"
     )]
-    pub file_lifetime: Option<u16>,
+    pub duration: Option<u64>,
 
     #[arg(
         short = 'b',
@@ -60,6 +298,38 @@ pub struct ProgramArgs {
     )]
     pub base_filename: Option<String>,
 
+    #[arg(
+        long,
+        group = "outputs",
+        conflicts_with_all = ["base_filename", "fileopts"],
+        help = "Write packets to stdout instead of files, e.g. to pipe them into another program"
+    )]
+    pub stdout: bool,
+
+    #[arg(
+        long,
+        requires = "base_filename",
+        help = "Directory to put output files in, created if need be [default: working directory]"
+    )]
+    pub out_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "STRFTIME",
+        default_value = DEFAULT_TIME_FORMAT,
+        value_parser = parse_time_format,
+        help = "strftime pattern for the time in output file names"
+    )]
+    pub filename_format: String,
+
+    #[arg(
+        long,
+        value_name = "EXT",
+        value_parser = parse_extension,
+        help = "Extension for output files, replacing .bin or .pcap (before any compression extension)"
+    )]
+    pub extension: Option<String>,
+
     #[arg(
         short = 'c',
         long,
@@ -67,6 +337,84 @@ pub struct ProgramArgs {
     )]
     pub post_process_cmd: Option<String>,
 
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        requires = "post_process_cmd",
+        help = "Most post-process commands to run at once"
+    )]
+    pub post_process_jobs: u16,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "bash",
+        requires = "post_process_cmd",
+        conflicts_with = "post_process_exec",
+        help = "Shell to run the post-process command with, as `<PATH> -c <command>`"
+    )]
+    pub post_process_shell: PathBuf,
+
+    #[arg(
+        long,
+        requires = "post_process_cmd",
+        help = "Run the post-process command directly, split on whitespace into a program and its arguments, without a shell"
+    )]
+    pub post_process_exec: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        requires = "base_filename",
+        help = "Compress output files as they are written; --max-file-size counts bytes before compression"
+    )]
+    pub compress: Option<Compression>,
+
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        requires = "compress",
+        help = "Compression level, from 1 (fastest) to 9 for gzip or 19 for zstd [default: the compressor's]"
+    )]
+    pub compress_level: Option<u32>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Raw,
+        help = "How packets are laid out in output files (or in the --replay file)"
+    )]
+    pub format: OutputFormat,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "forward_addrs",
+        conflicts_with_all = ["port", "base_filename"],
+        help = "Instead of capturing, send the packets in a file written by udpcapture to the forwarding addresses, then exit"
+    )]
+    pub replay: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        requires = "replay",
+        value_parser = parse_speed,
+        help = "Replay packets with their recorded spacing, sped up by FACTOR (1 is real time) [default: as fast as possible]"
+    )]
+    pub speed: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 1024,
+        requires = "replay",
+        value_parser = clap::value_parser!(u16).range(1..=65507),
+        help = "Size of the packets a raw file is replayed in"
+    )]
+    pub chunk_size: u16,
+
     #[arg(
         short = 'f',
         long,
@@ -74,4 +422,278 @@ pub struct ProgramArgs {
         group = "outputs"
     )]
     pub forward_addrs: Option<Vec<SocketAddr>>,
+
+    #[arg(
+        long,
+        value_name = "ADDR:PORT",
+        help = "Address to forward data to over TCP, each packet preceded by its length; may be repeated"
+    )]
+    pub forward_tcp: Vec<SocketAddr>,
+
+    #[arg(
+        long,
+        requires = "forwards",
+        conflicts_with = "replay",
+        help = "Put the sender's address and port in front of each forwarded packet (see the readme for the layout)"
+    )]
+    pub forward_with_source: bool,
+
+    #[arg(
+        long,
+        value_name = "ADDR:PORT",
+        requires = "forward_addrs",
+        help = "Local address and port to send forwarded packets from [default: any address, a port picked by the OS]"
+    )]
+    pub forward_from: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ForwardMode::Broadcast,
+        help = "Which forwarding addresses each packet goes to"
+    )]
+    pub forward_mode: ForwardMode,
+
+    #[arg(
+        long,
+        value_name = "PPS or BYTES/s",
+        requires = "forward_addrs",
+        help = "Forward at most this many packets (e.g. 500) or bytes (e.g. 64kB/s) a second; packets over the limit aren't forwarded, but are still written"
+    )]
+    pub forward_rate: Option<Rate>,
+
+    #[arg(
+        long,
+        value_name = "from=CIDR or at=OFFSET:HEX",
+        requires = "forwards",
+        conflicts_with = "replay",
+        help = "Only forward packets from this sender, or with these bytes at this offset, e.g. at=0:cafe; may be repeated; every packet is still written [default: forward all]"
+    )]
+    pub forward_filter: Vec<ForwardFilter>,
+}
+
+/// What a --config file may set: any option but --config itself.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSettings {
+    port: Option<Vec<u16>>,
+    bind: Option<IpAddr>,
+    #[serde(deserialize_with = "config::from_strs")]
+    multicast_group: Option<Vec<MulticastGroup>>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    queue_depth: Option<u32>,
+    rcvbuf: Option<u32>,
+    reuse_port: Option<bool>,
+    interface: Option<String>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    stats_interval: Option<u64>,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<SocketAddr>,
+    #[serde(deserialize_with = "config::from_strs")]
+    from: Option<Vec<Cidr>>,
+    min_packet_bytes: Option<usize>,
+    max_packet_bytes: Option<usize>,
+    keep_empty: Option<bool>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    sample: Option<u64>,
+    sample_forwarded: Option<bool>,
+    dedup: Option<bool>,
+    dedup_forwarded: Option<bool>,
+    max_file_size: Option<u64>,
+    drop_oversized: Option<bool>,
+    max_total_bytes: Option<u64>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    max_files: Option<u64>,
+    fsync: Option<bool>,
+    checksum: Option<bool>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    flush_interval: Option<u64>,
+    manifest: Option<PathBuf>,
+    latest_symlink: Option<PathBuf>,
+    #[serde(deserialize_with = "config::value_enum")]
+    latest_file: Option<LatestFile>,
+    file_lifetime: Option<u32>,
+    #[serde(deserialize_with = "rotate_period_setting")]
+    rotate_on: Option<u64>,
+    once: Option<bool>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    duration: Option<u64>,
+    base_filename: Option<String>,
+    stdout: Option<bool>,
+    out_dir: Option<PathBuf>,
+    #[serde(deserialize_with = "time_format_setting")]
+    filename_format: Option<String>,
+    #[serde(deserialize_with = "extension_setting")]
+    extension: Option<String>,
+    post_process_cmd: Option<String>,
+    #[serde(deserialize_with = "config::at_least_one")]
+    post_process_jobs: Option<u16>,
+    post_process_shell: Option<PathBuf>,
+    post_process_exec: Option<bool>,
+    #[serde(deserialize_with = "config::value_enum")]
+    compress: Option<Compression>,
+    compress_level: Option<u32>,
+    #[serde(deserialize_with = "config::value_enum")]
+    format: Option<OutputFormat>,
+    replay: Option<PathBuf>,
+    #[serde(deserialize_with = "speed_setting")]
+    speed: Option<f64>,
+    #[serde(deserialize_with = "chunk_size_setting")]
+    chunk_size: Option<u16>,
+    forward_addrs: Option<Vec<SocketAddr>>,
+    forward_tcp: Option<Vec<SocketAddr>>,
+    forward_with_source: Option<bool>,
+    forward_from: Option<SocketAddr>,
+    #[serde(deserialize_with = "config::value_enum")]
+    forward_mode: Option<ForwardMode>,
+    #[serde(deserialize_with = "config::from_str")]
+    forward_rate: Option<Rate>,
+    #[serde(deserialize_with = "config::from_strs")]
+    forward_filter: Option<Vec<ForwardFilter>>,
+}
+
+impl Settings for ProgramArgs {
+    type File = FileSettings;
+
+    fn config(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+
+    fn merge(&mut self, file: FileSettings, given: &dyn Fn(&str) -> bool) -> Vec<&'static str> {
+        merge_settings!(self, file, given;
+            port, bind, multicast_group, queue_depth, rcvbuf, reuse_port, interface,
+            stats_interval, #[cfg(feature = "metrics")] metrics_addr, from,
+            min_packet_bytes, max_packet_bytes, keep_empty, sample, sample_forwarded,
+            dedup, dedup_forwarded, max_file_size, drop_oversized, max_total_bytes,
+            max_files, fsync, checksum, flush_interval, manifest, latest_symlink,
+            latest_file, file_lifetime, rotate_on, once, duration, base_filename, stdout,
+            out_dir, filename_format, extension, post_process_cmd, post_process_jobs,
+            post_process_shell, post_process_exec, compress, compress_level, format,
+            replay, speed, chunk_size, forward_addrs, forward_tcp, forward_with_source,
+            forward_from, forward_mode, forward_rate, forward_filter,
+        )
+    }
+}
+
+/// Parse the command line, with the settings from the --config file if there is one
+/// (see impish_executables::config), and check the values which go together.
+/// Exits with a message if anything is wrong.
+pub fn parse() -> ProgramArgs {
+    let args: ProgramArgs = config::parse_with_config().unwrap_or_else(|e| e.exit());
+    let compression = args.compress.unwrap_or_default();
+    if let Some(level) = args.compress_level
+        && !compression.levels().is_some_and(|l| l.contains(&level))
+    {
+        let name = compression
+            .to_possible_value()
+            .expect("no compression is skipped");
+        let name = name.get_name();
+        let message = match compression.levels() {
+            None => format!("--compress {name} has no levels"),
+            Some(levels) => format!(
+                "--compress-level for {name} must be from {} to {}, not {level}",
+                levels.start(),
+                levels.end()
+            ),
+        };
+        invalid(message);
+    }
+    if args.post_process_exec
+        && args
+            .post_process_cmd
+            .as_deref()
+            .is_some_and(|cmd| cmd.trim().is_empty())
+    {
+        invalid("--post-process-exec needs a program to run".to_string());
+    }
+    if let (Some(min), Some(max)) = (args.min_packet_bytes, args.max_packet_bytes)
+        && min > max
+    {
+        invalid(format!(
+            "--min-packet-bytes ({min}) is more than --max-packet-bytes ({max})"
+        ));
+    }
+    args
+}
+
+/// Exit with a usage error about values which don't go together.
+fn invalid(message: String) -> ! {
+    ProgramArgs::command()
+        .bin_name("udpcapture")
+        .error(ErrorKind::ValueValidation, message)
+        .exit()
+}
+
+/// Check that a strftime pattern is one chrono understands,
+/// so a typo is caught at startup rather than when the first file opens.
+fn parse_time_format(s: &str) -> Result<String, String> {
+    if StrftimeItems::new(s).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("`{s}` is not a valid strftime pattern"));
+    }
+    Ok(s.to_string())
+}
+
+/// Take an extension with or without its leading dot.
+fn parse_extension(s: &str) -> Result<String, String> {
+    let ext = s.strip_prefix('.').unwrap_or(s);
+    if ext.is_empty() || ext.contains('/') {
+        return Err(format!("`{s}` is not a valid file extension"));
+    }
+    Ok(ext.to_string())
+}
+
+/// Turn `minute`, `hour`, or `NNs` into a number of seconds.
+fn parse_rotate_period(s: &str) -> Result<u64, String> {
+    let secs = match s {
+        "minute" => 60,
+        "hour" => 3600,
+        _ => s
+            .strip_suffix('s')
+            .and_then(|n| n.parse::<u64>().ok())
+            .ok_or(format!(
+                "expected `minute`, `hour`, or a number of seconds like `30s`, not `{s}`"
+            ))?,
+    };
+    if secs == 0 {
+        return Err("the rotation period can't be 0s".to_string());
+    }
+    Ok(secs)
+}
+
+/// Take a replay speed-up factor, which must be positive.
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!("speed must be a positive number, not `{s}`")),
+    }
+}
+
+// The options above with value parsers of their own, as settings in a --config file
+
+fn rotate_period_setting<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    config::parse_with(d, parse_rotate_period)
+}
+
+fn time_format_setting<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    config::parse_with(d, parse_time_format)
+}
+
+fn extension_setting<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    config::parse_with(d, parse_extension)
+}
+
+fn speed_setting<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f64>, D::Error> {
+    let speed = f64::deserialize(d)?;
+    parse_speed(&speed.to_string())
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+fn chunk_size_setting<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u16>, D::Error> {
+    match u16::deserialize(d)? {
+        size @ 1..=65507 => Ok(Some(size)),
+        size => Err(D::Error::custom(format!(
+            "chunk_size must be from 1 to 65507, not {size}"
+        ))),
+    }
 }
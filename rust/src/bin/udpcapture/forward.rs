@@ -0,0 +1,378 @@
+use clap::ValueEnum;
+use impish_executables::cidr::Cidr;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/*
+ * Forwarding of captured packets to other addresses.
+ * A destination which can't be sent to is skipped for a while,
+ * backing off further each time it fails again,
+ * so a down destination neither stops capture nor floods the log.
+ *
+ * Packets are sent from sockets of our own rather than the capture sockets,
+ * so they don't come from the capture port. By default these are on a port
+ * the OS picks, one socket for each address family we send to.
+ * */
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long to leave a destination after `failures` failures in a row:
+/// 100 ms, doubling with each failure, up to 30 s.
+pub fn backoff(failures: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// Length of the header --forward-with-source puts in front of each packet.
+pub const SOURCE_HEADER_LEN: usize = 18;
+
+/// Put the sender's address in front of a packet, for --forward-with-source:
+/// its IPv6 address (IPv4 ones mapped, as `::ffff:a.b.c.d`) in 16 bytes,
+/// then its port in 2, both in network byte order.
+pub fn with_source(data: &[u8], from: SocketAddr) -> Vec<u8> {
+    let ip = match from.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    let mut packet = Vec::with_capacity(SOURCE_HEADER_LEN + data.len());
+    packet.extend(ip.octets());
+    packet.extend(from.port().to_be_bytes());
+    packet.extend(data);
+    packet
+}
+
+/// A test a packet has to pass to be forwarded, for --forward-filter:
+/// `from=<IP or CIDR>` for its sender,
+/// or `at=<OFFSET>:<HEX>` for bytes of its payload, e.g. `at=0:cafe`.
+#[derive(Clone, Debug)]
+pub enum ForwardFilter {
+    From(Cidr),
+    Bytes { offset: usize, pattern: Vec<u8> },
+}
+
+impl FromStr for ForwardFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ForwardFilter, String> {
+        if let Some(cidr) = s.strip_prefix("from=") {
+            return Ok(ForwardFilter::From(cidr.parse()?));
+        }
+        let Some((offset, hex)) = s.strip_prefix("at=").and_then(|m| m.split_once(':')) else {
+            return Err(format!(
+                "expected `from=<IP or CIDR>` or `at=<OFFSET>:<HEX>`, not `{s}`"
+            ));
+        };
+        let offset = offset
+            .parse()
+            .map_err(|_| format!("invalid offset `{offset}` in `{s}`"))?;
+        let hex = hex.strip_prefix("0x").unwrap_or(hex);
+        let pattern = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .filter(|p| !p.is_empty())
+            .ok_or(format!(
+                "expected an even number of hex digits after the offset, not `{hex}`"
+            ))?;
+        Ok(ForwardFilter::Bytes { offset, pattern })
+    }
+}
+
+/// Check a packet against the --forward-filters. It passes if its sender
+/// matches any `from=` filter and its payload any `at=` filter;
+/// with no filters of a kind, every packet matches that kind.
+pub fn passes(filters: &[ForwardFilter], data: &[u8], from: SocketAddr) -> bool {
+    let matches = |f: &ForwardFilter| match f {
+        ForwardFilter::From(cidr) => cidr.contains(from.ip()),
+        ForwardFilter::Bytes { offset, pattern } => data
+            .get(*offset..)
+            .is_some_and(|rest| rest.starts_with(pattern)),
+    };
+    // Sender filters, then payload filters
+    [true, false].into_iter().all(|senders| {
+        let mut of_kind = filters
+            .iter()
+            .filter(|f| matches!(f, ForwardFilter::From(_)) == senders)
+            .peekable();
+        of_kind.peek().is_none() || of_kind.any(matches)
+    })
+}
+
+/// Which destinations each packet is forwarded to.
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum ForwardMode {
+    /// Every destination
+    #[default]
+    Broadcast,
+    /// One destination, taking turns
+    #[value(name = "roundrobin")]
+    RoundRobin,
+    /// One destination, picked by the sender's address, so each sender always goes to the same one
+    Hash,
+}
+
+/// A limit on how much is forwarded, given as packets per second
+/// (`500` or `500pps`) or bytes per second (`64000B/s`, `64kB/s`, `1.5MB/s`).
+#[derive(Clone, Copy, Debug)]
+pub enum Rate {
+    Packets(f64),
+    Bytes(f64),
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rate, String> {
+        let (number, rate): (&str, fn(f64) -> Rate) = if let Some(n) = s.strip_suffix("B/s") {
+            (n, Rate::Bytes)
+        } else {
+            (s.strip_suffix("pps").unwrap_or(s), Rate::Packets)
+        };
+        let (number, multiplier) = match number.strip_suffix('k') {
+            Some(n) => (n, 1e3),
+            None => match number.strip_suffix('M') {
+                Some(n) => (n, 1e6),
+                None => (number, 1.0),
+            },
+        };
+        match number.parse::<f64>() {
+            Ok(n) if n > 0.0 && n.is_finite() => Ok(rate(n * multiplier)),
+            _ => Err(format!(
+                "expected packets per second (`500`, `500pps`) or bytes per second (`64kB/s`), not `{s}`"
+            )),
+        }
+    }
+}
+
+/// Token bucket for --forward-rate, holding up to a second's worth,
+/// so bursts are let through as long as the average stays under the rate.
+struct RateLimit {
+    rate: Rate,
+    tokens: f64,
+    last_fill: Instant,
+}
+
+impl RateLimit {
+    fn new(rate: Rate) -> RateLimit {
+        let mut limit = RateLimit {
+            rate,
+            tokens: 0.0,
+            last_fill: Instant::now(),
+        };
+        limit.tokens = limit.per_second();
+        limit
+    }
+
+    fn per_second(&self) -> f64 {
+        match self.rate {
+            Rate::Packets(n) | Rate::Bytes(n) => n,
+        }
+    }
+
+    /// Take the budget for a packet of `len` bytes, if there is enough.
+    fn take(&mut self, len: usize) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_fill).as_secs_f64() * self.per_second();
+        self.tokens = (self.tokens + refill).min(self.per_second());
+        self.last_fill = now;
+        let cost = match self.rate {
+            Rate::Packets(_) => 1.0,
+            Rate::Bytes(_) => len as f64,
+        };
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+}
+
+struct Destination {
+    addr: SocketAddr,
+    // Failures in a row
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Destination {
+    fn backing_off(&self, now: Instant) -> bool {
+        self.retry_at.is_some_and(|t| now < t)
+    }
+}
+
+pub struct Forwarder {
+    destinations: Vec<Destination>,
+    // Sockets to send IPv4 and IPv6 packets from;
+    // with a source address given, both are the one socket
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    mode: ForwardMode,
+    // Next destination for round-robin forwarding
+    next: usize,
+    rate_limit: Option<RateLimit>,
+    // Failed sends since take_errors was last called
+    errors: u64,
+}
+
+impl Forwarder {
+    /// Forward to the given addresses, sending from `source`
+    /// (an address and port, either of which may be 0) if given.
+    pub fn new(
+        addrs: &[SocketAddr],
+        source: Option<SocketAddr>,
+        mode: ForwardMode,
+        rate: Option<Rate>,
+    ) -> std::io::Result<Forwarder> {
+        let (v4, v6) = match source {
+            Some(source) => {
+                let sock = UdpSocket::bind(source)?;
+                (Some(sock.try_clone()?), Some(sock))
+            }
+            None => {
+                let bind_if_used = |unspecified: SocketAddr| {
+                    let used = addrs.iter().any(|a| a.is_ipv4() == unspecified.is_ipv4());
+                    used.then(|| UdpSocket::bind(unspecified)).transpose()
+                };
+                (
+                    bind_if_used((Ipv4Addr::UNSPECIFIED, 0).into())?,
+                    bind_if_used((Ipv6Addr::UNSPECIFIED, 0).into())?,
+                )
+            }
+        };
+        Ok(Forwarder {
+            v4,
+            v6,
+            mode,
+            next: 0,
+            rate_limit: rate.map(RateLimit::new),
+            errors: 0,
+            destinations: addrs
+                .iter()
+                .map(|addr| Destination {
+                    addr: *addr,
+                    failures: 0,
+                    retry_at: None,
+                })
+                .collect(),
+        })
+    }
+
+    /// Send the data on to the destinations the forwarding mode picks,
+    /// skipping any which are backing off.
+    /// `from` is the packet's sender, for --forward-mode hash.
+    /// Errors are logged, never returned.
+    /// Returns how many destinations it was sent to,
+    /// or None if it was dropped for going over the --forward-rate.
+    pub fn forward(&mut self, data: &[u8], from: SocketAddr) -> Option<u64> {
+        if let Some(limit) = &mut self.rate_limit
+            && !limit.take(data.len())
+        {
+            return None;
+        }
+        let now = Instant::now();
+        let n = self.destinations.len();
+        let sent = match self.mode {
+            ForwardMode::Broadcast => (0..n).filter(|i| self.send(*i, data, now)).count() as u64,
+            ForwardMode::RoundRobin => {
+                // The next destination in turn which isn't backing off
+                let Some(i) = (0..n)
+                    .map(|k| (self.next + k) % n)
+                    .find(|i| !self.destinations[*i].backing_off(now))
+                else {
+                    return Some(0);
+                };
+                self.next = (i + 1) % n;
+                self.send(i, data, now) as u64
+            }
+            ForwardMode::Hash => {
+                // Not moved elsewhere while its destination is down,
+                // so a sender's packets never get split between two
+                let mut hasher = DefaultHasher::new();
+                from.ip().to_canonical().hash(&mut hasher);
+                let i = (hasher.finish() % n as u64) as usize;
+                self.send(i, data, now) as u64
+            }
+        };
+        Some(sent)
+    }
+
+    /// How many sends have failed since this was last called.
+    pub fn take_errors(&mut self) -> u64 {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Send to one destination, unless it's backing off.
+    /// Returns whether it was sent.
+    fn send(&mut self, i: usize, data: &[u8], now: Instant) -> bool {
+        let d = &mut self.destinations[i];
+        if d.backing_off(now) {
+            return false;
+        }
+        let sock = if d.addr.is_ipv4() { &self.v4 } else { &self.v6 };
+        let sock = sock
+            .as_ref()
+            .expect("Have a socket for every destination's family");
+        match sock.send_to(data, d.addr) {
+            Ok(_) => {
+                if d.failures > 0 {
+                    eprintln!(
+                        "Forwarding to {} works again after {} failures",
+                        d.addr, d.failures
+                    );
+                }
+                d.failures = 0;
+                d.retry_at = None;
+                true
+            }
+            Err(e) => {
+                self.errors += 1;
+                d.failures += 1;
+                let backoff = backoff(d.failures);
+                d.retry_at = Some(now + backoff);
+                eprintln!(
+                    "Cannot forward to {} ({} failures in a row): {e}; skipping it for {:?}",
+                    d.addr, d.failures, backoff
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_of_each_kind_must_all_be_met() {
+        let filters: Vec<ForwardFilter> = ["from=10.0.0.0/8", "at=2:cafe", "at=0:0x01"]
+            .iter()
+            .map(|f| f.parse().unwrap())
+            .collect();
+        let inside: SocketAddr = "10.1.2.3:4000".parse().unwrap();
+        let outside: SocketAddr = "192.168.1.1:4000".parse().unwrap();
+        assert!(passes(&filters, b"\x00\x00\xca\xfe", inside));
+        assert!(passes(&filters, b"\x01", inside));
+        assert!(!passes(&filters, b"\x01", outside));
+        // The pattern runs past the end of the packet
+        assert!(!passes(&filters, b"\x00\x00\xca", inside));
+        assert!(passes(&[], b"", outside));
+
+        for bad in [
+            "at=0:",
+            "at=0:abc",
+            "at=x:ab",
+            "to=1.2.3.4",
+            "from=1.2.3.4/40",
+        ] {
+            assert!(bad.parse::<ForwardFilter>().is_err(), "{bad}");
+        }
+    }
+}
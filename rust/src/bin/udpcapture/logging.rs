@@ -0,0 +1,28 @@
+/*
+ * Where the library's `log` messages go: straight to stderr,
+ * unadorned, like everything else udpcapture has to say.
+ * */
+
+struct Stderr;
+
+impl log::Log for Stderr {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Send the `log` crate's messages, up to info, to stderr.
+pub fn init() {
+    // Only fails if a logger is already set, in which case it can keep them
+    if log::set_logger(&Stderr).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+}
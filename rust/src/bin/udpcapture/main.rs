@@ -9,79 +9,480 @@
  * Rust is nice, though, because it's safe :-)
  * */
 mod args;
-mod writer;
-use clap::Parser;
-use std::cmp::max;
+mod forward;
+mod logging;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod multicast;
+mod postprocess;
+mod replay;
+mod retry;
+mod stats;
+mod sys;
+mod tcp_forward;
+use chrono::{DateTime, Utc};
+use forward::Forwarder;
+use impish_executables::cidr::Cidr;
+use impish_executables::udpcapture::{compress, pcap, writer};
+use impish_executables::{signals, systemd};
+use postprocess::PostProcessor;
+use replay::ReplayOptions;
+use retry::RetryingWriter;
+use socket2::{Domain, SockRef, Socket, Type};
+use stats::Stats;
+use std::borrow::Cow;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::ErrorKind;
-use std::net::{SocketAddr, UdpSocket};
-use std::process::Command;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+use tcp_forward::TcpForwarder;
+use writer::{FileWriter, PacketInfo, WriterOptions};
+
+/// Longest we wait for a packet before checking whether the file
+/// has expired and whether SIGINT/SIGTERM has arrived.
+const WAKE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Shortest time between logs of packets dropped because the queue was full.
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A packet received on one of the capture sockets.
+struct Received {
+    data: Vec<u8>,
+    info: PacketInfo,
+}
 
 fn main() {
-    let args = args::ProgramArgs::parse();
-    let sock = UdpSocket::bind(format!("0.0.0.0:{}", args.port))
-        .expect("UDP socket port needs to be available to bind");
+    let args = args::parse();
+    logging::init();
+    signals::catch_shutdown_signals();
+    signals::catch_stats_signal();
+    if let Some(file) = &args.replay {
+        run_replay(&args, file);
+        return;
+    }
+    let socks: Vec<UdpSocket> = args
+        .port
+        .iter()
+        .map(|p| bind(args.bind, *p, args.reuse_port))
+        .collect();
+    if let Some(interface) = &args.interface {
+        for sock in socks.iter() {
+            bind_to_device(sock, interface);
+        }
+    }
+    for group in args.multicast_group.iter() {
+        for sock in socks.iter() {
+            if let Err(e) = group.join(sock) {
+                eprintln!("Cannot join multicast group {group}: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    for sock in socks.iter() {
+        // Packets are timestamped by the kernel as they arrive,
+        // rather than when we get round to them; failing that, by us
+        if let Err(e) = sys::enable_timestamps(sock) {
+            eprintln!("Cannot have the kernel timestamp packets: {e}; timestamping them ourselves");
+        }
+    }
+    if let Some(bytes) = args.rcvbuf {
+        for sock in socks.iter() {
+            set_recv_buffer(sock, bytes);
+        }
+    }
 
-    if let Some(life) = args.file_lifetime {
-        // Make the socket timeout 5x shorter
-        // than the file lifetime.
-        // Minimum 1s
-        let timeout = max(life / 5, 1) as u64;
-        sock.set_read_timeout(Some(Duration::from_secs(timeout)))
-            .expect("Timeout must be a valid duration in seconds");
+    // Each socket gets a thread which does nothing but receive,
+    // and passes packets on to this one to be written and forwarded,
+    // so a slow disk or forward doesn't leave packets in the socket buffer.
+    let (tx, rx) = mpsc::sync_channel(args.queue_depth as usize);
+    let stats = Arc::new(Stats::default());
+    for sock in socks.iter() {
+        let sock = sock
+            .try_clone()
+            .expect("Need to be able to share the socket with its receiver");
+        spawn_receiver(sock, tx.clone(), Arc::clone(&stats));
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = args.metrics_addr
+        && let Err(e) = metrics::serve(addr, Arc::clone(&stats))
+    {
+        eprintln!("Cannot serve metrics on {addr}: {e}");
+        std::process::exit(1);
     }
 
-    let mut writer = writer::FileWriter::new(
+    let mut forwarder = args
+        .forward_addrs
+        .as_deref()
+        .map(|addrs| make_forwarder(addrs, &args));
+    let mut tcp_forwarder = (!args.forward_tcp.is_empty()).then(|| {
+        TcpForwarder::new(
+            &args.forward_tcp,
+            args.queue_depth as usize,
+            Arc::clone(&stats),
+        )
+    });
+    if let Some(dir) = &args.out_dir
+        && let Err(e) = std::fs::create_dir_all(dir)
+    {
+        eprintln!("Cannot create output directory {}: {e}", dir.display());
+        std::process::exit(1);
+    }
+    let runner = if args.post_process_exec {
+        postprocess::Runner::Exec
+    } else {
+        postprocess::Runner::Shell(args.post_process_shell)
+    };
+    let post_processor = PostProcessor::new(
+        args.post_process_cmd,
+        args.post_process_jobs as usize,
+        runner,
+    );
+    let file_closed = |saved_file| {
+        Stats::add(&stats.files_closed, 1);
+        post_processor.submit(saved_file);
+    };
+    let writing = args.base_filename.is_some() || args.stdout;
+    let writer = FileWriter::new(
         args.base_filename,
         args.max_file_size,
-        args.file_lifetime.unwrap_or(u16::MAX),
+        args.file_lifetime
+            .map(|secs| Duration::from_secs(secs as u64)),
+        WriterOptions {
+            out_dir: args.out_dir,
+            time_format: Some(args.filename_format),
+            extension: args.extension,
+            compression: args.compress.unwrap_or_default(),
+            compression_level: args.compress_level,
+            format: args.format,
+            max_total_bytes: args.max_total_bytes,
+            max_files: args.max_files.map(|n| n as usize),
+            checksum: args.checksum,
+            fsync: args.fsync,
+            rotate_every: args.rotate_on,
+            drop_oversized: args.drop_oversized,
+            flush_interval: args.flush_interval.map(Duration::from_secs),
+            manifest: args.manifest,
+            latest_symlink: args.latest_symlink,
+            latest_file: args.latest_file,
+            stdout: args.stdout,
+        },
     );
+    let mut writer = RetryingWriter::new(writer, args.stdout);
+    systemd::notify_ready();
+    let mut watchdog = systemd::Watchdog::from_env();
 
-    loop {
-        let data = receive_data(&sock);
-        if let Some(saved_file) = writer.maybe_write_data(&data) {
-            post_process(&args.post_process_cmd, &saved_file);
+    // Packets which got past --from, for --sample
+    let mut packets_seen: u64 = 0;
+    // Length and hash of the last packet, for --dedup
+    let mut last_fingerprint: Option<(usize, u64)> = None;
+    let stats_interval = args.stats_interval.map(Duration::from_secs);
+    let mut last_stats = Instant::now();
+    // With --once, set when the first file is closed
+    let mut done = false;
+    // Monotonic, so a clock step can't make the run shorter or longer
+    let stop_at = args
+        .duration
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let out_of_time = || stop_at.is_some_and(|t| Instant::now() >= t);
+    // With --stdout, there's nothing left to do once its reader goes away
+    while !done && !out_of_time() && !signals::shutdown_requested() && !writer.stdout_closed() {
+        watchdog.ping();
+        if signals::stats_requested() || stats_interval.is_some_and(|i| last_stats.elapsed() >= i) {
+            stats.log(&socks);
+            last_stats = Instant::now();
+        }
+        let received = match rx.recv_timeout(WAKE_INTERVAL) {
+            Ok(r) => Some(r),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => panic!("all receivers have stopped"),
+        };
+        // Packets from senders outside of --from are dropped
+        let received = received.filter(|r| is_allowed(&args.from, r.info.from));
+        // As are packets outside --min-packet-bytes and --max-packet-bytes
+        let received = received.filter(|r| {
+            let len = r.data.len();
+            let right_size = args.min_packet_bytes.is_none_or(|min| len >= min)
+                && args.max_packet_bytes.is_none_or(|max| len <= max);
+            if !right_size {
+                Stats::add(&stats.wrong_size, 1);
+            }
+            right_size
+        });
+        // Zero-length packets are dropped too, unless --keep-empty
+        let received = received.filter(|r| {
+            let keep = args.keep_empty || !r.data.is_empty();
+            if !keep {
+                Stats::add(&stats.empty, 1);
+            }
+            keep
+        });
+        let Some(received) = received else {
+            // Nothing to write, but the file may have expired
+            if let Some(saved_file) = writer.write(&[], None) {
+                file_closed(saved_file);
+                done = args.once;
+            }
+            continue;
+        };
+
+        // With --dedup, a packet the same as the one before isn't kept
+        let duplicate = args.dedup && {
+            let fingerprint = fingerprint(&received.data);
+            let same = last_fingerprint == Some(fingerprint);
+            last_fingerprint = Some(fingerprint);
+            same
+        };
+        if duplicate {
+            Stats::add(&stats.duplicates, 1);
+        }
+        // With --sample N only every Nth packet is kept;
+        // duplicates don't count towards N
+        let sampled = !duplicate && packets_seen.is_multiple_of(args.sample);
+        if !duplicate {
+            packets_seen += 1;
+        }
+        let (data, info) = if sampled {
+            (&received.data[..], Some(received.info))
+        } else {
+            // Still give the writer a chance to close an expired file
+            (&[][..], None)
+        };
+        if writing {
+            Stats::add(&stats.bytes_written, data.len() as u64);
+        }
+        if let Some(saved_file) = writer.write(data, info) {
+            file_closed(saved_file);
+            done = args.once;
         }
-        if let Some(fwds) = &args.forward_addrs {
-            forward_data(&sock, &data, &fwds);
+        let mut forwarding =
+            (sampled || !args.sample_forwarded) && !(duplicate && args.dedup_forwarded);
+        // Of those, only packets passing --forward-filter go on; all were written
+        if forwarding && !forward::passes(&args.forward_filter, &received.data, received.info.from)
+        {
+            Stats::add(&stats.forward_filtered, 1);
+            forwarding = false;
+        }
+        let forwarded = if forwarding && args.forward_with_source {
+            Cow::Owned(forward::with_source(&received.data, received.info.from))
+        } else {
+            Cow::Borrowed(&received.data[..])
+        };
+        if let Some(fwd) = &mut forwarder
+            && forwarding
+        {
+            match fwd.forward(&forwarded, received.info.from) {
+                Some(sent) => Stats::add(&stats.forwarded, sent),
+                None => Stats::add(&stats.forward_rate_dropped, 1),
+            }
+            Stats::add(&stats.forward_errors, fwd.take_errors());
+        }
+        if let Some(fwd) = &mut tcp_forwarder
+            && forwarding
+        {
+            fwd.forward(&forwarded);
         }
     }
-}
 
-fn receive_data(sock: &UdpSocket) -> Vec<u8> {
-    // Max packet size in UDP
-    let mut buf = [0u8; 65535];
-    let recvd = match sock.recv(&mut buf) {
-        Ok(rec) => rec,
-        Err(e) => {
-            if e.kind() == ErrorKind::WouldBlock {
-                // Socket timed out; don't care
-                // But, set the ret Vec to no size
-                0
-            } else {
-                panic!("unexpected error when receiving: {e:?}")
+    // Don't lose the tail of the file being written
+    if done {
+        eprintln!("Closed a file with --once, shutting down");
+    } else if out_of_time() {
+        eprintln!("Captured for --duration, shutting down");
+    } else {
+        eprintln!("Shutting down");
+    }
+    systemd::notify_stopping();
+    if let Some(saved_file) = writer.close() {
+        file_closed(saved_file);
+    }
+    post_processor.finish();
+    for group in args.multicast_group.iter() {
+        for sock in socks.iter() {
+            if let Err(e) = group.leave(sock) {
+                eprintln!("Cannot leave multicast group {group}: {e}");
             }
         }
+    }
+    stats.log(&socks);
+}
+
+/// Replay a file to the forwarding addresses, exiting if it can't be read.
+fn run_replay(args: &args::ProgramArgs, file: &Path) {
+    let addrs = args
+        .forward_addrs
+        .as_deref()
+        .expect("--replay requires forwarding addresses");
+    let mut forwarder = make_forwarder(addrs, args);
+    let opts = ReplayOptions {
+        format: args.format,
+        speed: args.speed,
+        chunk_size: args.chunk_size as usize,
     };
-    return buf[..recvd].to_vec();
+    match replay::replay(file, &mut forwarder, &opts) {
+        Ok(sent) => eprintln!("Replayed {sent} packets from {}", file.display()),
+        Err(e) => {
+            eprintln!("Cannot replay {}: {e}", file.display());
+            std::process::exit(1);
+        }
+    }
 }
 
-fn post_process(cmd: &Option<String>, file: &String) {
-    if let Some(cmd) = cmd {
-        // The file which was just written gets put into
-        // the shell variable `out_file`.
-        // Post-process scripts may access it as $out_file
-        let full_cmd = format!("out_file={}; {}", file, cmd);
-        match Command::new("bash").arg("-c").arg(&full_cmd).output() {
-            Ok(op) => eprintln!("`{}` ran: {:?}", &cmd, &op),
-            Err(e) => eprintln!("`{}` did not run: {:?}", &cmd, &e),
+/// Set up forwarding, or exit if the --forward-from address can't be bound.
+fn make_forwarder(addrs: &[SocketAddr], args: &args::ProgramArgs) -> Forwarder {
+    let source = args.forward_from;
+    match Forwarder::new(addrs, source, args.forward_mode, args.forward_rate) {
+        Ok(f) => f,
+        Err(e) => {
+            let source = source.map_or("any address".to_string(), |s| s.to_string());
+            eprintln!("Cannot bind {source} to forward from: {e}");
+            std::process::exit(1);
         }
     }
 }
 
-fn forward_data(sock: &UdpSocket, dat: &[u8], destinations: &Vec<SocketAddr>) {
-    for d in destinations.iter() {
-        sock.send_to(dat, &d)
-            .expect(&format!("Need to be able to send data to {:?}", d));
+/// Length and hash of a packet, to tell if two are the same
+/// without keeping the last one around.
+fn fingerprint(data: &[u8]) -> (usize, u64) {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    (data.len(), hasher.finish())
+}
+
+/// Check if a sender matches one of the --from filters.
+/// No filters lets everyone through.
+fn is_allowed(filters: &[Cidr], sender: SocketAddr) -> bool {
+    filters.is_empty() || filters.iter().any(|c| c.contains(sender.ip()))
+}
+
+/// Bind a capture socket, or exit if the port can't be had.
+fn bind(addr: IpAddr, port: u16, reuse_port: bool) -> UdpSocket {
+    let addr = SocketAddr::new(addr, port);
+    let sock = if reuse_port {
+        bind_reuse_port(addr)
+    } else {
+        UdpSocket::bind(addr)
+    };
+    match sock {
+        Ok(sock) => sock,
+        Err(e) => {
+            eprintln!("Cannot bind UDP address {addr}: {e}");
+            std::process::exit(1);
+        }
     }
 }
+
+/// Bind a UDP socket with SO_REUSEPORT set, so that other sockets
+/// (in this process or others) can bind the same address and port,
+/// and the kernel shares incoming packets between them.
+/// std binds as soon as it creates a socket, too early to set the option.
+fn bind_reuse_port(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    sock.set_reuse_port(true)?;
+    sock.bind(&addr.into())?;
+    Ok(sock.into())
+}
+
+/// Apply --interface to a socket, or exit if it can't be.
+/// Needs CAP_NET_RAW on older kernels.
+fn bind_to_device(sock: &UdpSocket, interface: &str) {
+    if let Err(e) = SockRef::from(sock).bind_device(Some(interface.as_bytes())) {
+        let reason = match e.kind() {
+            ErrorKind::PermissionDenied => {
+                "udpcapture needs CAP_NET_RAW (or root) for --interface".to_string()
+            }
+            _ if e.raw_os_error() == Some(libc::ENODEV) => {
+                format!("no network interface `{interface}`")
+            }
+            _ => e.to_string(),
+        };
+        eprintln!("Cannot capture only on {interface}: {reason}");
+        std::process::exit(1);
+    }
+}
+
+/// Set --rcvbuf on a socket and log what we got, or exit if it can't be set.
+/// The kernel may give us a different size (Linux doubles it for
+/// bookkeeping, and caps it at net.core.rmem_max).
+fn set_recv_buffer(sock: &UdpSocket, bytes: u32) {
+    let addr = sock
+        .local_addr()
+        .expect("Bound socket should have a local address");
+    let sock = SockRef::from(sock);
+    let granted = sock
+        .set_recv_buffer_size(bytes as usize)
+        .and_then(|()| sock.recv_buffer_size());
+    match granted {
+        Ok(granted) => {
+            eprintln!("Receive buffer on {addr}: asked for {bytes} bytes, got {granted}")
+        }
+        Err(e) => {
+            eprintln!("Cannot set receive buffer on {addr}: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Receive packets from a socket on a new thread,
+/// sending each one down the channel.
+/// Packets which don't fit in the channel are dropped,
+/// and how many is logged at most once every DROP_LOG_INTERVAL.
+fn spawn_receiver(sock: UdpSocket, tx: SyncSender<Received>, stats: Arc<Stats>) {
+    let local_addr = sock
+        .local_addr()
+        .expect("Bound socket should have a local address");
+    std::thread::spawn(move || {
+        // Packets dropped since the last log of them
+        let mut dropped: u64 = 0;
+        let mut last_drop_log: Option<Instant> = None;
+        // Max packet size in UDP; reused for every packet
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let (data, from, captured) = receive_data(&sock, &mut buf);
+            Stats::add(&stats.received, 1);
+            let info = PacketInfo {
+                from,
+                to: local_addr,
+                captured,
+            };
+            let received = Received { data, info };
+            match tx.try_send(received) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    dropped += 1;
+                    Stats::add(&stats.dropped, 1);
+                }
+                Err(TrySendError::Disconnected(_)) => break,
+            }
+            if dropped > 0 && last_drop_log.is_none_or(|t| t.elapsed() >= DROP_LOG_INTERVAL) {
+                eprintln!(
+                    "Queue is full (see --queue-depth); dropped {dropped} packets on {local_addr}"
+                );
+                dropped = 0;
+                last_drop_log = Some(Instant::now());
+            }
+        }
+    });
+}
+
+/// Receive a packet, returning it with its sender and when the kernel
+/// received it, or when we did if the kernel didn't say.
+fn receive_data(sock: &UdpSocket, buf: &mut [u8]) -> (Vec<u8>, SocketAddr, DateTime<Utc>) {
+    let (recvd, from, received) = loop {
+        match sys::recv_with_time(sock, buf) {
+            Ok(rec) => break rec,
+            // A signal arrived mid-call; just try again
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                // This runs on a receiver thread, where a panic
+                // would only stop capture on this one port
+                eprintln!("unexpected error when receiving: {e:?}");
+                std::process::exit(1);
+            }
+        }
+    };
+    let captured = received.map_or_else(Utc::now, DateTime::from);
+    (buf[..recvd].to_vec(), from, captured)
+}
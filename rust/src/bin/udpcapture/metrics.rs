@@ -0,0 +1,58 @@
+use crate::stats::Stats;
+use impish_executables::metrics::{self, Kind, Metric};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/*
+ * The stats Prometheus can scrape with --metrics-addr
+ * (see impish_executables::metrics for the serving).
+ * */
+
+/// Listen on `addr` and answer scrapes from a thread of our own.
+/// Returns an error if we can't listen there.
+pub fn serve(addr: SocketAddr, stats: Arc<Stats>) -> std::io::Result<()> {
+    metrics::serve(
+        addr,
+        move || current(&stats),
+        |e| eprintln!("Cannot answer a metrics request: {e}"),
+    )?;
+    Ok(())
+}
+
+fn current(stats: &Stats) -> Vec<Metric> {
+    let counter = |name, help, value: &AtomicU64| Metric {
+        name,
+        kind: Kind::Counter,
+        help,
+        value: value.load(Ordering::Relaxed),
+    };
+    vec![
+        counter(
+            "packets_received_total",
+            "Packets received on the capture sockets",
+            &stats.received,
+        ),
+        counter(
+            "packets_dropped_total",
+            "Packets dropped because the queue was full",
+            &stats.dropped,
+        ),
+        counter(
+            "bytes_written_total",
+            "Bytes written to files, before compression",
+            &stats.bytes_written,
+        ),
+        counter("files_closed_total", "Files closed", &stats.files_closed),
+        counter(
+            "packets_forwarded_total",
+            "Packets forwarded, counting once per destination",
+            &stats.forwarded,
+        ),
+        counter(
+            "forward_errors_total",
+            "Failed sends to forwarding destinations",
+            &stats.forward_errors,
+        ),
+    ]
+}
@@ -0,0 +1,84 @@
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::str::FromStr;
+
+/// A multicast group to capture from, given as `GROUP` or `GROUP@INTERFACE`.
+/// An IPv4 group's interface is given by one of its addresses;
+/// an IPv6 group's by its name (e.g. `eth0`) or index.
+/// Without an interface, the kernel picks one.
+#[derive(Clone, Debug)]
+pub struct MulticastGroup {
+    group: IpAddr,
+    interface: Interface,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Interface {
+    V4(Ipv4Addr),
+    // Interface index; 0 lets the kernel choose
+    V6(u32),
+}
+
+impl MulticastGroup {
+    pub fn join(&self, sock: &UdpSocket) -> std::io::Result<()> {
+        match (self.group, self.interface) {
+            (IpAddr::V4(g), Interface::V4(i)) => sock.join_multicast_v4(&g, &i),
+            (IpAddr::V6(g), Interface::V6(i)) => sock.join_multicast_v6(&g, i),
+            _ => unreachable!("interface matches the group's family when parsed"),
+        }
+    }
+
+    pub fn leave(&self, sock: &UdpSocket) -> std::io::Result<()> {
+        match (self.group, self.interface) {
+            (IpAddr::V4(g), Interface::V4(i)) => sock.leave_multicast_v4(&g, &i),
+            (IpAddr::V6(g), Interface::V6(i)) => sock.leave_multicast_v6(&g, i),
+            _ => unreachable!("interface matches the group's family when parsed"),
+        }
+    }
+}
+
+impl std::fmt::Display for MulticastGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.interface {
+            Interface::V4(i) if !i.is_unspecified() => write!(f, "{}@{i}", self.group),
+            Interface::V6(i) if i != 0 => write!(f, "{}@{i}", self.group),
+            _ => write!(f, "{}", self.group),
+        }
+    }
+}
+
+impl FromStr for MulticastGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MulticastGroup, String> {
+        let (group, interface) = match s.split_once('@') {
+            Some((g, i)) => (g, Some(i)),
+            None => (s, None),
+        };
+        let group =
+            IpAddr::from_str(group).map_err(|e| format!("invalid address `{group}`: {e}"))?;
+        if !group.is_multicast() {
+            return Err(format!("{group} is not a multicast address"));
+        }
+        let interface = match (group, interface) {
+            (IpAddr::V4(_), None) => Interface::V4(Ipv4Addr::UNSPECIFIED),
+            (IpAddr::V4(_), Some(i)) => Interface::V4(i.parse().map_err(|_| {
+                format!("interface for an IPv4 group must be an IPv4 address, not `{i}`")
+            })?),
+            (IpAddr::V6(_), None) => Interface::V6(0),
+            (IpAddr::V6(_), Some(i)) => Interface::V6(interface_index(i)?),
+        };
+        Ok(MulticastGroup { group, interface })
+    }
+}
+
+/// Look up a network interface's index from its name, or take it as given.
+fn interface_index(name: &str) -> Result<u32, String> {
+    if let Ok(index) = name.parse() {
+        return Ok(index);
+    }
+    let path = format!("/sys/class/net/{name}/ifindex");
+    std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|i| i.trim().parse().ok())
+        .ok_or(format!("no network interface `{name}`"))
+}
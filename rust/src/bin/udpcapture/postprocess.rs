@@ -0,0 +1,192 @@
+use chrono::SecondsFormat;
+use impish_executables::udpcapture::writer::ClosedFile;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/*
+ * Running --post-process-cmd on closed files.
+ * Commands run on worker threads, so a slow one
+ * (e.g. uploading the file) doesn't hold up capture.
+ * At most `jobs` commands run at once; files closed while
+ * they are all busy wait in a queue, in the order they were closed.
+ *
+ * The command gets the file, and details of it, in environment variables:
+ *     out_file        its name
+ *     out_size        bytes on disk
+ *     out_packets     packets in the file
+ *     out_open_time   when it was opened and closed,
+ *     out_close_time  as RFC 3339 UTC times with microseconds
+ * */
+
+/// Files which can wait for a free worker before closing another blocks capture.
+const QUEUE_LEN: usize = 64;
+
+/// How the post-process command is run.
+#[derive(Clone)]
+pub enum Runner {
+    /// By a shell, as `<shell> -c <command>`
+    Shell(PathBuf),
+    /// Directly, split on whitespace into a program and its arguments;
+    /// there is no quoting, so no argument can hold a space
+    Exec,
+}
+
+impl Runner {
+    fn command(&self, cmd: &str) -> Command {
+        match self {
+            Runner::Shell(shell) => {
+                let mut command = Command::new(shell);
+                command.arg("-c").arg(cmd);
+                command
+            }
+            Runner::Exec => {
+                let mut words = cmd.split_whitespace();
+                let program = words
+                    .next()
+                    .expect("exec command was checked to be non-empty");
+                let mut command = Command::new(program);
+                command.args(words);
+                command
+            }
+        }
+    }
+}
+
+pub struct PostProcessor {
+    // None once we are shutting down
+    queue: Option<SyncSender<ClosedFile>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl PostProcessor {
+    /// Start `jobs` workers running `cmd` the way `runner` says.
+    /// Without a command there is nothing to do and no workers are started.
+    pub fn new(cmd: Option<String>, jobs: usize, runner: Runner) -> PostProcessor {
+        let Some(cmd) = cmd else {
+            return PostProcessor {
+                queue: None,
+                workers: Vec::new(),
+            };
+        };
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        // Workers take turns pulling files off the one queue
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..jobs)
+            .map(|_| {
+                let cmd = cmd.clone();
+                let runner = runner.clone();
+                let rx = Arc::clone(&rx);
+                std::thread::spawn(move || work(&cmd, &runner, &rx))
+            })
+            .collect();
+        PostProcessor {
+            queue: Some(tx),
+            workers,
+        }
+    }
+
+    /// Queue a closed file to be post-processed.
+    /// Only blocks if the queue is full, which is logged.
+    pub fn submit(&self, file: ClosedFile) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let file = match queue.try_send(file) {
+            Ok(()) => return,
+            Err(TrySendError::Full(file)) => file,
+            Err(TrySendError::Disconnected(_)) => panic!("all post-process workers have stopped"),
+        };
+        eprintln!(
+            "Post-processing is falling behind: {QUEUE_LEN} files are waiting; waiting to queue {}",
+            file.name
+        );
+        queue
+            .send(file)
+            .expect("Post-process workers should still be running");
+    }
+
+    /// Wait for every queued file to be post-processed.
+    pub fn finish(mut self) {
+        // Closing the queue lets the workers stop once it's empty
+        if self.queue.take().is_none() {
+            return;
+        }
+        eprintln!("Waiting for post-processing to finish");
+        for w in self.workers.drain(..) {
+            w.join().expect("Post-process worker should not panic");
+        }
+    }
+}
+
+fn work(cmd: &str, runner: &Runner, files: &Mutex<Receiver<ClosedFile>>) {
+    loop {
+        // Only hold the lock while waiting, not while running the command
+        let file = match files.lock().unwrap().recv() {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+        run(cmd, runner, &file);
+    }
+}
+
+fn run(cmd: &str, runner: &Runner, file: &ClosedFile) {
+    match command(cmd, runner, file).output() {
+        Ok(op) => eprintln!("`{}` ran: {:?}", &cmd, &op),
+        Err(e) => eprintln!("`{}` did not run: {:?}", &cmd, &e),
+    }
+}
+
+/// The command to run for a file.
+fn command(cmd: &str, runner: &Runner, file: &ClosedFile) -> Command {
+    let time = |t: chrono::DateTime<chrono::Utc>| t.to_rfc3339_opts(SecondsFormat::Micros, true);
+    let mut command = runner.command(cmd);
+    // The file's name goes in the environment rather than into the command,
+    // so it needs no quoting, whatever characters it has
+    command
+        .env("out_file", &file.name)
+        .env("out_size", file.bytes.to_string())
+        .env("out_packets", file.packets.to_string())
+        .env("out_open_time", time(file.opened))
+        .env("out_close_time", time(file.closed))
+        // In its own process group, so a Ctrl-C
+        // meant for us doesn't kill the commands we wait for on shutdown
+        .process_group(0);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_name_reaches_command_verbatim() {
+        let now = chrono::Utc::now();
+        let file = ClosedFile {
+            name: "runs/it's a $HOME `date` file.bin".to_string(),
+            bytes: 10,
+            packets: 2,
+            opened: now,
+            closed: now,
+        };
+        let stdout = |cmd: &str, runner: Runner| {
+            let out = command(cmd, &runner, &file).output().unwrap();
+            assert!(out.status.success());
+            String::from_utf8(out.stdout).unwrap()
+        };
+        assert_eq!(
+            stdout(
+                r#"printf '%s %s' "$out_file" "$out_packets""#,
+                Runner::Shell("bash".into())
+            ),
+            format!("{} 2", file.name)
+        );
+        assert_eq!(
+            stdout("printenv out_file", Runner::Exec),
+            format!("{}\n", file.name)
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use crate::forward::Forwarder;
+use crate::pcap;
+use crate::writer::{self, OutputFormat};
+use chrono::prelude::*;
+use impish_executables::signals;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/*
+ * Replaying a file we captured back onto the network,
+ * e.g. to test whatever consumes the packets.
+ * Packets are sent to the forwarding addresses, the same way
+ * as packets being captured are forwarded.
+ * */
+
+pub struct ReplayOptions {
+    pub format: OutputFormat,
+    // Send packets with their recorded spacing, sped up by this factor.
+    // None sends them as fast as we can.
+    pub speed: Option<f64>,
+    // Size of the chunks a raw file is sent in
+    pub chunk_size: usize,
+}
+
+type Records = Box<dyn Iterator<Item = std::io::Result<(DateTime<Utc>, Vec<u8>)>>>;
+
+/// Send every packet in the file to the forwarder's destinations,
+/// stopping early on SIGINT/SIGTERM.
+/// Returns how many packets were sent.
+pub fn replay(
+    path: &Path,
+    forwarder: &mut Forwarder,
+    opts: &ReplayOptions,
+) -> std::io::Result<u64> {
+    let file = BufReader::new(File::open(path)?);
+    // Raw files have no packet boundaries or times, so are just cut into chunks
+    let records: Records = match opts.format {
+        OutputFormat::Raw => Box::new(chunks(file, opts.chunk_size)),
+        OutputFormat::Pcap => Box::new(pcap::read(file)?),
+        OutputFormat::Framed => Box::new(writer::read_framed(file)),
+        OutputFormat::Hexdump => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "hexdump files can't be replayed",
+            ));
+        }
+    };
+
+    let mut sent = 0;
+    // Capture time of the first packet, and when we sent it
+    let mut start: Option<(DateTime<Utc>, Instant)> = None;
+    for record in records {
+        if signals::shutdown_requested() {
+            break;
+        }
+        let (captured, data) = record?;
+        if let Some(speed) = opts.speed {
+            let (first_captured, first_sent) = *start.get_or_insert((captured, Instant::now()));
+            // Times which go backwards are sent straight away
+            let offset = (captured - first_captured)
+                .to_std()
+                .unwrap_or(Duration::ZERO);
+            let due = first_sent + offset.div_f64(speed);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        // Recorded senders aren't kept, so all look the same to --forward-mode hash
+        forwarder.forward(&data, (Ipv4Addr::UNSPECIFIED, 0).into());
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// Cut a raw file into chunks, with the time they are read as their capture time.
+fn chunks<R: Read>(
+    mut reader: R,
+    chunk_size: usize,
+) -> impl Iterator<Item = std::io::Result<(DateTime<Utc>, Vec<u8>)>> {
+    std::iter::from_fn(move || {
+        let mut chunk = Vec::with_capacity(chunk_size);
+        match reader
+            .by_ref()
+            .take(chunk_size as u64)
+            .read_to_end(&mut chunk)
+        {
+            Ok(0) => None,
+            Ok(_) => Some(Ok((Utc::now(), chunk))),
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
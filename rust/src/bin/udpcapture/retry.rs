@@ -0,0 +1,83 @@
+use impish_executables::udpcapture::writer::{ClosedFile, FileWriter, PacketInfo};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+/*
+ * What udpcapture does when files can't be written (e.g. the disk is full):
+ * log it, throw packets away for a while rather than failing on every one,
+ * then try again. Forwarding carries on meanwhile.
+ * */
+
+/// How long we stop writing files after an error writing one.
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct RetryingWriter {
+    writer: FileWriter,
+    // Set after a write error; nothing is written until then
+    retry_at: Option<Instant>,
+    packets_not_written: u64,
+    // Writing to stdout rather than files
+    stdout: bool,
+    // Set once whatever reads stdout goes away
+    stdout_closed: bool,
+}
+
+impl RetryingWriter {
+    pub fn new(writer: FileWriter, stdout: bool) -> RetryingWriter {
+        RetryingWriter {
+            writer,
+            retry_at: None,
+            packets_not_written: 0,
+            stdout,
+            stdout_closed: false,
+        }
+    }
+
+    /// Write a packet with FileWriter::maybe_write_data, unless we are
+    /// waiting to try again after an error. Returns any file it closes.
+    pub fn write(&mut self, data: &[u8], info: Option<PacketInfo>) -> Option<ClosedFile> {
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at {
+                if info.is_some() {
+                    self.packets_not_written += 1;
+                }
+                return None;
+            }
+            eprintln!(
+                "Trying to write files again; {} packets were not written meanwhile",
+                self.packets_not_written
+            );
+            self.retry_at = None;
+            self.packets_not_written = 0;
+        }
+        self.writer
+            .maybe_write_data(data, info)
+            .unwrap_or_else(|e| self.failed(e))
+    }
+
+    /// Close the open file, if there is one. Returns any file it closes.
+    pub fn close(&mut self) -> Option<ClosedFile> {
+        self.writer.close().unwrap_or_else(|e| self.failed(e))
+    }
+
+    /// With --stdout, whether whatever was reading it has gone away,
+    /// so there's nothing more to write.
+    pub fn stdout_closed(&self) -> bool {
+        self.stdout_closed
+    }
+
+    fn failed(&mut self, e: std::io::Error) -> Option<ClosedFile> {
+        // Only stdout's reader going away is for good
+        if self.stdout && e.kind() == ErrorKind::BrokenPipe {
+            eprintln!("{e}; stopping");
+            self.stdout_closed = true;
+            return None;
+        }
+        eprintln!(
+            "Cannot write {e}; not writing for {:?} (forwarding carries on)",
+            WRITE_RETRY_INTERVAL
+        );
+        self.retry_at = Some(Instant::now() + WRITE_RETRY_INTERVAL);
+        None
+    }
+}
@@ -0,0 +1,89 @@
+use std::net::UdpSocket;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/*
+ * Counters of what udpcapture has done, so we can tell
+ * whether it's keeping up. They are logged every --stats-interval,
+ * on SIGUSR1, and once more on shutdown.
+ * */
+
+#[derive(Default)]
+pub struct Stats {
+    pub received: AtomicU64,
+    // Dropped by us because the queue was full
+    pub dropped: AtomicU64,
+    // Dropped by --min-packet-bytes or --max-packet-bytes
+    pub wrong_size: AtomicU64,
+    // Zero-length packets dropped because there was no --keep-empty
+    pub empty: AtomicU64,
+    // Not written because of --dedup
+    pub duplicates: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub files_closed: AtomicU64,
+    // Packets sent on to forwarding destinations, counting once per destination
+    pub forwarded: AtomicU64,
+    // Sends to forwarding destinations which failed
+    pub forward_errors: AtomicU64,
+    // Packets not forwarded because of --forward-rate
+    pub forward_rate_dropped: AtomicU64,
+    // Packets not forwarded because of --forward-filter
+    pub forward_filtered: AtomicU64,
+}
+
+impl Stats {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Log the counters, and the kernel's count of packets
+    /// it dropped from the sockets' buffers, where it's available.
+    pub fn log(&self, socks: &[UdpSocket]) {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let kernel_dropped = match socks.iter().map(kernel_drops).sum::<Option<u64>>() {
+            Some(n) => n.to_string(),
+            None => "?".to_string(),
+        };
+        eprintln!(
+            "Stats: received {} packets, dropped {} (queue full) and {} (socket buffer full), \
+             {} of the wrong size, {} empty, {} duplicates, \
+             wrote {} bytes, closed {} files, forwarded {} packets ({} failed, {} over --forward-rate and {} not matching --forward-filter not forwarded)",
+            get(&self.received),
+            get(&self.dropped),
+            kernel_dropped,
+            get(&self.wrong_size),
+            get(&self.empty),
+            get(&self.duplicates),
+            get(&self.bytes_written),
+            get(&self.files_closed),
+            get(&self.forwarded),
+            get(&self.forward_errors),
+            get(&self.forward_rate_dropped),
+            get(&self.forward_filtered),
+        );
+    }
+}
+
+/// Packets the kernel dropped because the socket's receive buffer was full,
+/// from the `drops` column of /proc/net/udp (or udp6).
+/// None if it can't be found, e.g. when not on Linux.
+fn kernel_drops(sock: &UdpSocket) -> Option<u64> {
+    // A socket's row is identified by its inode
+    let inode = std::fs::metadata(format!("/proc/self/fd/{}", sock.as_raw_fd()))
+        .ok()?
+        .ino()
+        .to_string();
+    ["/proc/net/udp", "/proc/net/udp6"]
+        .iter()
+        .find_map(|table| {
+            let table = std::fs::read_to_string(table).ok()?;
+            table.lines().skip(1).find_map(|row| {
+                let fields: Vec<&str> = row.split_whitespace().collect();
+                if fields.get(9) != Some(&inode.as_str()) {
+                    return None;
+                }
+                fields.last()?.parse().ok()
+            })
+        })
+}
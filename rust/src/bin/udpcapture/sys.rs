@@ -0,0 +1,100 @@
+/*
+ * Receiving packets with the time the kernel got them,
+ * which neither std nor socket2 wraps, through the libc crate
+ * so the structs and constants are laid out right for whatever we are built for.
+ * */
+use std::ffi::c_int;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::time::{Duration, SystemTime};
+
+/// Have the kernel record when each packet arrives on the socket,
+/// for `recv_with_time` to return.
+pub fn enable_timestamps(sock: &UdpSocket) -> std::io::Result<()> {
+    set_option(sock.as_raw_fd(), libc::SO_TIMESTAMPNS, 1)
+}
+
+/// Receive a packet like `UdpSocket::recv_from`, also returning when
+/// the kernel received it if `enable_timestamps` has been called.
+pub fn recv_with_time(
+    sock: &UdpSocket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<SystemTime>)> {
+    // Safety: all zeroes is a valid sockaddr_storage
+    let mut name: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    // Room for the one control message we ask for, aligned for its header
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // Safety: all zeroes is a valid msghdr; some libcs have padding fields
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = (&raw mut name).cast();
+    msg.msg_namelen = size_of_val(&name) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = size_of_val(&control) as _;
+    // Safety: every pointer in msg is to a buffer of the length given with it,
+    // all of which outlive the call
+    let len = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let from = socket_addr(&name).ok_or(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "unknown sender address family",
+    ))?;
+    Ok((len as usize, from, received_time(&msg)))
+}
+
+/// Find the SCM_TIMESTAMPNS control message among those recvmsg gave us.
+fn received_time(msg: &libc::msghdr) -> Option<SystemTime> {
+    // Safety: recvmsg left msg's control buffer holding well-formed
+    // control messages, up to its controllen; the CMSG_* macros
+    // stay within that, giving null after the last one
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+    while let Some(header) = unsafe { cmsg.as_ref() } {
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_TIMESTAMPNS {
+            // Safety: a SCM_TIMESTAMPNS message holds a timespec; it may not be aligned
+            let time: libc::timespec =
+                unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(header).cast()) };
+            let since_epoch = Duration::new(time.tv_sec as u64, time.tv_nsec as u32);
+            return SystemTime::UNIX_EPOCH.checked_add(since_epoch);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, header) };
+    }
+    None
+}
+
+/// Set an integer SOL_SOCKET option.
+fn set_option(fd: c_int, name: c_int, value: c_int) -> std::io::Result<()> {
+    let len = size_of::<c_int>() as libc::socklen_t;
+    // Safety: the value pointer is to a c_int, whose length we pass
+    if unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, name, (&raw const value).cast(), len) } != 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Read back the address recvmsg filled in.
+fn socket_addr(name: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match name.ss_family as c_int {
+        libc::AF_INET => {
+            // Safety: the family says the storage holds a sockaddr_in
+            let a = unsafe { &*(&raw const *name).cast::<libc::sockaddr_in>() };
+            let ip = Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr));
+            Some((ip, u16::from_be(a.sin_port)).into())
+        }
+        libc::AF_INET6 => {
+            // Safety: the family says the storage holds a sockaddr_in6
+            let a = unsafe { &*(&raw const *name).cast::<libc::sockaddr_in6>() };
+            let ip = Ipv6Addr::from(a.sin6_addr.s6_addr);
+            let port = u16::from_be(a.sin6_port);
+            Some(SocketAddrV6::new(ip, port, a.sin6_flowinfo, a.sin6_scope_id).into())
+        }
+        _ => None,
+    }
+}
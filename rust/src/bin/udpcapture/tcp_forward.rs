@@ -0,0 +1,151 @@
+use crate::forward::backoff;
+use crate::stats::Stats;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+/*
+ * Forwarding of captured packets over TCP (--forward-tcp),
+ * for destinations UDP can't reach.
+ * Since TCP is a stream, each packet is sent as
+ *     (u32 payload length, little-endian) + (payload)
+ * so the receiver can split them apart again.
+ *
+ * Each destination has a thread of its own, which keeps a connection open
+ * and sends it the packets queued for it, so capture never waits on TCP.
+ * If the connection can't be made or breaks, the thread reconnects,
+ * backing off the same way as UDP forwarding does,
+ * and packets queue up meanwhile; once the queue is full, more are dropped.
+ * */
+
+/// Longest we wait for a connection to be made.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest a write may block before the connection is given up on and remade.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Destination {
+    addr: SocketAddr,
+    queue: SyncSender<Vec<u8>>,
+    // Whether the queue was full last time, so that is only logged once
+    full: bool,
+}
+
+pub struct TcpForwarder {
+    destinations: Vec<Destination>,
+    stats: Arc<Stats>,
+}
+
+impl TcpForwarder {
+    /// Start forwarding to the given addresses, with up to `queue_depth`
+    /// packets waiting to be sent to each.
+    pub fn new(addrs: &[SocketAddr], queue_depth: usize, stats: Arc<Stats>) -> TcpForwarder {
+        let destinations = addrs
+            .iter()
+            .map(|addr| {
+                let (tx, rx) = mpsc::sync_channel(queue_depth);
+                let addr = *addr;
+                let stats = Arc::clone(&stats);
+                std::thread::spawn(move || send_queued(addr, rx, &stats));
+                Destination {
+                    addr,
+                    queue: tx,
+                    full: false,
+                }
+            })
+            .collect();
+        TcpForwarder {
+            destinations,
+            stats,
+        }
+    }
+
+    /// Queue the data to be sent to every destination.
+    /// It is counted as forwarded once sent; a destination whose queue is full
+    /// doesn't get it, which is counted as a failed send.
+    /// Sends which fail and are retried aren't counted, as the packet isn't lost.
+    pub fn forward(&mut self, data: &[u8]) {
+        for d in self.destinations.iter_mut() {
+            let mut frame = Vec::with_capacity(4 + data.len());
+            frame.extend((data.len() as u32).to_le_bytes());
+            frame.extend(data);
+            match d.queue.try_send(frame) {
+                Ok(()) => d.full = false,
+                Err(TrySendError::Full(_)) => {
+                    Stats::add(&self.stats.forward_errors, 1);
+                    if !d.full {
+                        eprintln!(
+                            "Queue for forwarding to {} over TCP is full; dropping packets for it until it drains",
+                            d.addr
+                        );
+                    }
+                    d.full = true;
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    panic!("TCP forwarding thread for {} has stopped", d.addr)
+                }
+            }
+        }
+    }
+}
+
+/// Send the packets queued for one destination, (re)connecting as needed.
+/// Returns once the forwarder is dropped.
+fn send_queued(addr: SocketAddr, queue: Receiver<Vec<u8>>, stats: &Stats) {
+    let mut conn: Option<TcpStream> = None;
+    // Failures in a row
+    let mut failures: u32 = 0;
+    for frame in queue.iter() {
+        // Keep at it until this packet is sent; later ones wait in the queue
+        loop {
+            let sock = match conn.take() {
+                Some(sock) => sock,
+                None => match connect(addr) {
+                    Ok(sock) => sock,
+                    Err(e) => {
+                        failures += 1;
+                        let wait = backoff(failures);
+                        eprintln!(
+                            "Cannot connect to {addr} to forward over TCP ({failures} failures in a row): {e}; retrying in {wait:?}"
+                        );
+                        std::thread::sleep(wait);
+                        continue;
+                    }
+                },
+            };
+            // A packet partly written when the connection broke is sent whole
+            // on the next one; the receiver drops the partial frame with the old connection
+            match (&sock).write_all(&frame) {
+                Ok(()) => {
+                    if failures > 0 {
+                        eprintln!(
+                            "Forwarding to {addr} over TCP works again after {failures} failures"
+                        );
+                    }
+                    failures = 0;
+                    conn = Some(sock);
+                    Stats::add(&stats.forwarded, 1);
+                    break;
+                }
+                Err(e) => {
+                    failures += 1;
+                    let wait = backoff(failures);
+                    eprintln!(
+                        "Connection to {addr} for forwarding over TCP broke: {e}; reconnecting in {wait:?}"
+                    );
+                    std::thread::sleep(wait);
+                }
+            }
+        }
+    }
+}
+
+fn connect(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let sock = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    sock.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    // Packets go out as they come, rather than waiting to fill a segment
+    sock.set_nodelay(true)?;
+    Ok(sock)
+}
@@ -0,0 +1,132 @@
+/* Blocks of addresses, as given to udpcapture's --from
+ * and command-executor's --allow to say who may send to them.
+ * */
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of IPv4 or IPv6 addresses, like `10.0.0.0/8` or `fd00::/8`.
+/// A bare address is a block of one.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Check if an address falls in this block.
+    /// IPv4 addresses mapped into IPv6 (`::ffff:a.b.c.d`)
+    /// are treated as the IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                (u32::from(net) & mask) == (u32::from(ip) & mask)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                (u128::from(net) & mask) == (u128::from(ip) & mask)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr)
+            .map_err(|e| format!("invalid address `{addr}`: {e}"))?
+            .to_canonical();
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_len)
+                .ok_or(format!("prefix length must be 0 to {max_len}, not `{p}`"))?,
+            None => max_len,
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn zero_prefix_contains_everything_of_its_family() {
+        let v4: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(v4.contains(ip("10.1.2.3")));
+        assert!(v4.contains(ip("255.255.255.255")));
+        assert!(!v4.contains(ip("fd00::1")));
+
+        let v6: Cidr = "::/0".parse().unwrap();
+        assert!(v6.contains(ip("fd00::1")));
+        assert!(!v6.contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn full_prefix_contains_only_that_address() {
+        let v4: Cidr = "192.168.1.7/32".parse().unwrap();
+        assert!(v4.contains(ip("192.168.1.7")));
+        assert!(!v4.contains(ip("192.168.1.8")));
+
+        let v6: Cidr = "fd00::7/128".parse().unwrap();
+        assert!(v6.contains(ip("fd00::7")));
+        assert!(!v6.contains(ip("fd00::8")));
+
+        // A bare address is the same as a full prefix
+        let bare: Cidr = "192.168.1.7".parse().unwrap();
+        assert!(bare.contains(ip("192.168.1.7")));
+        assert!(!bare.contains(ip("192.168.1.8")));
+    }
+
+    #[test]
+    fn blocks_match_on_their_prefix() {
+        let v4: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(v4.contains(ip("10.255.0.1")));
+        assert!(!v4.contains(ip("11.0.0.1")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fdff::1")));
+        assert!(!v6.contains(ip("fe00::1")));
+    }
+
+    #[test]
+    fn v4_mapped_addresses_are_treated_as_v4() {
+        // e.g. senders on a dual-stack socket
+        let v4: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(v4.contains(ip("::ffff:10.1.2.3")));
+        assert!(!v4.contains(ip("::ffff:11.1.2.3")));
+
+        // ... and so are blocks given that way
+        let mapped: Cidr = "::ffff:10.0.0.0".parse().unwrap();
+        assert!(mapped.contains(ip("10.0.0.0")));
+        assert!(!mapped.contains(ip("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn over_long_prefixes_are_rejected() {
+        let e = "10.0.0.0/33".parse::<Cidr>().unwrap_err();
+        assert!(e.contains("0 to 32"), "{e}");
+        let e = "fd00::/129".parse::<Cidr>().unwrap_err();
+        assert!(e.contains("0 to 128"), "{e}");
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+}
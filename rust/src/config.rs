@@ -0,0 +1,381 @@
+use clap::builder::Resettable;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, Command, Parser, ValueEnum};
+use serde::Deserialize;
+use serde::de::{DeserializeOwned, Deserializer, Error as _};
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+
+/*
+ * Reading a --config file, for udpcapture and command-executor:
+ * the same settings as the command line, in TOML, e.g.
+ *     port = [12345, 12346]
+ *     base_filename = "/data/telemetry"
+ *     max_file_size = 10000000
+ *     fsync = true
+ * Each program deserializes the file into a struct of its own (`Settings::File`),
+ * with an optional field for each option, named as the option is with `_` for `-`.
+ * The file's settings fill in the options not given on the command line
+ * or in the environment, and then clap checks how the options go together
+ * (required ones, groups, `requires`, conflicts) as it would for a command line.
+ * */
+
+/// Program arguments which a --config file can also set.
+pub trait Settings: Parser {
+    /// What the file may hold: every field an `Option`, and unknown keys refused.
+    type File: DeserializeOwned;
+
+    /// The file given with --config, if any.
+    fn config(&self) -> Option<&Path>;
+
+    /// Take the file's settings for the options which aren't `given`,
+    /// and return the ids of those the file gave.
+    /// `merge_settings!` writes this.
+    fn merge(&mut self, file: Self::File, given: &dyn Fn(&str) -> bool) -> Vec<&'static str>;
+}
+
+/// Write the body of `Settings::merge`, for fields named the same in the arguments and the file,
+/// each file field an `Option` of a value which converts `Into` the argument's.
+/// A switch set to `false` in the file isn't counted as given.
+#[macro_export]
+macro_rules! merge_settings {
+    ($args:expr, $file:expr, $given:expr; $($(#[$attr:meta])* $field:ident),* $(,)?) => {{
+        let mut from_file: Vec<&'static str> = Vec::new();
+        $(
+            $(#[$attr])*
+            if let Some(value) = $file.$field
+                && !$given(stringify!($field))
+            {
+                let any: &dyn std::any::Any = &value;
+                if any.downcast_ref::<bool>() != Some(&false) {
+                    from_file.push(stringify!($field));
+                }
+                $args.$field = value.into();
+            }
+        )*
+        from_file
+    }};
+}
+
+/// Parse the command line, with the settings from the --config file if there is one.
+/// Mistakes, and --help and --version, come back as clap errors
+/// for the program to `exit()` with.
+pub fn parse_with_config<P: Settings>() -> Result<P, clap::Error> {
+    parse_with_config_from(std::env::args_os())
+}
+
+/// `parse_with_config`, for the command line given.
+pub fn parse_with_config_from<P: Settings>(
+    cli: impl IntoIterator<Item = OsString>,
+) -> Result<P, clap::Error> {
+    let cli: Vec<OsString> = cli.into_iter().collect();
+    // Nothing is required yet, since the file may provide it
+    let matches = match relaxed(P::command()).try_get_matches_from(&cli) {
+        Ok(matches) => matches,
+        // Report the mistake (or show --help) as for the command line alone
+        Err(e) => return Err(P::try_parse_from(cli).err().unwrap_or(e)),
+    };
+    let mut args = P::from_arg_matches(&matches)?;
+    let Some(path) = args.config().map(Path::to_path_buf) else {
+        // No file, so clap can check the command line as usual
+        return P::try_parse_from(cli);
+    };
+    let file = read(&path).map_err(|(kind, e)| {
+        clap::Error::raw(kind, format!("config file {}: {e}\n", path.display()))
+    })?;
+
+    let given = |id: &str| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    let from_file = args.merge(file, &given);
+    check_together(P::command(), &cli[0], |id| {
+        given(id) || from_file.contains(&id)
+    })?;
+    Ok(args)
+}
+
+/// Read and deserialize a config file.
+fn read<T: DeserializeOwned>(path: &Path) -> Result<T, (ErrorKind, String)> {
+    let text = std::fs::read_to_string(path).map_err(|e| (ErrorKind::Io, e.to_string()))?;
+    toml::from_str(&text).map_err(|e| (ErrorKind::InvalidValue, e.to_string()))
+}
+
+/// The command, with no option or group required, and none requiring another.
+fn relaxed(command: Command) -> Command {
+    let groups: Vec<_> = command.get_groups().map(|g| g.get_id().clone()).collect();
+    let command = command.mut_args(|a| {
+        a.required(false)
+            .required_unless_present(Resettable::Reset)
+            .requires(Resettable::Reset)
+    });
+    groups.into_iter().fold(command, |command, group| {
+        command.mut_group(group, |g| g.required(false).requires(Resettable::Reset))
+    })
+}
+
+/// Have clap check that the options which are `present` go together,
+/// by parsing a command line of just their names,
+/// as switches, since their values have been parsed already.
+fn check_together(
+    command: Command,
+    program: &OsString,
+    present: impl Fn(&str) -> bool,
+) -> Result<(), clap::Error> {
+    let mut names = vec![program.clone()];
+    names.extend(
+        command
+            .get_arguments()
+            .filter(|a| present(a.get_id().as_str()))
+            .filter_map(|a| a.get_long())
+            .map(|long| format!("--{long}").into()),
+    );
+    command
+        .mut_args(|a| {
+            a.action(ArgAction::SetTrue)
+                .value_parser(clap::value_parser!(bool))
+                .num_args(0)
+                .value_names(None::<&str>)
+                .value_delimiter(None)
+                .allow_hyphen_values(false)
+                .default_value(None)
+                .env(None)
+        })
+        .try_get_matches_from(names)
+        .map(drop)
+}
+
+/// For `#[serde(deserialize_with)]` helpers: a string setting,
+/// checked and converted by `parse`, the option's value parser.
+pub fn parse_with<'de, D, T, E>(
+    d: D,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    E: Display,
+{
+    let s = String::deserialize(d)?;
+    parse(&s).map(Some).map_err(D::Error::custom)
+}
+
+/// For `#[serde(deserialize_with)]`: a string setting, parsed with `FromStr` as on the command line.
+pub fn from_str<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    parse_with(d, str::parse)
+}
+
+/// `from_str`, for an array of them.
+pub fn from_strs<'de, D, T>(d: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| s.parse().map_err(D::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// For `#[serde(deserialize_with)]`: one of a `ValueEnum`'s names, as on the command line.
+pub fn value_enum<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: ValueEnum,
+{
+    parse_with(d, |s| T::from_str(s, false))
+}
+
+/// For `#[serde(deserialize_with)]`: a number which must be at least 1,
+/// as `value_parser!(..).range(1..)` checks on the command line.
+pub fn at_least_one<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + PartialOrd + From<u8>,
+{
+    let n = T::deserialize(d)?;
+    if n < T::from(1) {
+        return Err(D::Error::custom("must be at least 1"));
+    }
+    Ok(Some(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cidr::Cidr;
+    use clap::ArgGroup;
+    use std::path::PathBuf;
+
+    #[derive(Parser)]
+    #[clap(group(ArgGroup::new("outputs").required(true).multiple(true).args(["base", "forward"])))]
+    struct TestArgs {
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        port: u16,
+        #[arg(long, env = "IMPISH_CONFIG_TEST_SIZE", default_value_t = 1)]
+        max_size: u64,
+        #[arg(long, requires = "base")]
+        fsync: bool,
+        #[arg(long, conflicts_with = "base")]
+        stdout: bool,
+        #[arg(long)]
+        base: Option<String>,
+        #[arg(long)]
+        forward: Vec<String>,
+        #[arg(long)]
+        from: Vec<Cidr>,
+    }
+
+    #[derive(Default, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    struct TestFile {
+        #[serde(deserialize_with = "at_least_one")]
+        port: Option<u16>,
+        max_size: Option<u64>,
+        fsync: Option<bool>,
+        stdout: Option<bool>,
+        base: Option<String>,
+        forward: Option<Vec<String>>,
+        #[serde(deserialize_with = "from_strs")]
+        from: Option<Vec<Cidr>>,
+    }
+
+    impl Settings for TestArgs {
+        type File = TestFile;
+
+        fn config(&self) -> Option<&Path> {
+            self.config.as_deref()
+        }
+
+        fn merge(&mut self, file: TestFile, given: &dyn Fn(&str) -> bool) -> Vec<&'static str> {
+            merge_settings!(self, file, given; port, max_size, fsync, stdout, base, forward, from)
+        }
+    }
+
+    fn parse_test_args(config: &str, cli: &[&str]) -> Result<TestArgs, clap::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "impish-config-test-{}-{:x}.toml",
+            std::process::id(),
+            config.len() * 31 + cli.len()
+        ));
+        std::fs::write(&path, config).unwrap();
+        let mut args = vec![
+            "test".into(),
+            "--config".into(),
+            path.clone().into_os_string(),
+        ];
+        args.extend(cli.iter().map(OsString::from));
+        let parsed = parse_with_config_from(args);
+        std::fs::remove_file(&path).unwrap();
+        parsed
+    }
+
+    #[test]
+    fn file_settings_are_deserialized() {
+        let args = parse_test_args(
+            "# a comment\n\
+             port = 2\n\
+             base = \"a # b\"\n\
+             forward = ['x', \"y\\tz\"]\n\
+             from = [\"10.0.0.0/8\"]\n",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(args.port, 2);
+        assert_eq!(args.base.as_deref(), Some("a # b"));
+        assert_eq!(args.forward, ["x", "y\tz"]);
+        assert_eq!(args.from.len(), 1);
+        assert!(args.from[0].contains("10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn bad_files_are_refused() {
+        let error = |config| parse_test_args(config, &["--base=b"]).err().unwrap();
+        assert_eq!(error("prot = 2").kind(), ErrorKind::InvalidValue);
+        assert!(
+            error("prot = 2")
+                .to_string()
+                .contains("unknown field `prot`")
+        );
+        assert!(error("port = \"2\"").to_string().contains("invalid type"));
+        assert!(error("port = 0").to_string().contains("must be at least 1"));
+        assert!(error("from = [\"1.2.3.4/40\"]").to_string().contains("40"));
+        assert!(
+            error("port = 1\nport = 2")
+                .to_string()
+                .contains("duplicate key")
+        );
+
+        let missing = parse_with_config_from::<TestArgs>(
+            ["test", "--config", "/nonexistent/impish.toml"].map(OsString::from),
+        );
+        assert_eq!(missing.err().unwrap().kind(), ErrorKind::Io);
+    }
+
+    #[test]
+    fn command_line_and_environment_beat_the_file() {
+        let config = "port = 2\nmax_size = 3\nfsync = true\nbase = 'a'\nforward = ['a', 'b']\n";
+        let args = parse_test_args(config, &[]).unwrap();
+        assert_eq!(args.port, 2);
+        assert_eq!(args.max_size, 3);
+        assert!(args.fsync);
+        assert_eq!(args.forward, ["a", "b"]);
+
+        // Safety: no other test reads or writes this variable
+        unsafe { std::env::set_var("IMPISH_CONFIG_TEST_SIZE", "4") };
+        let args = parse_test_args(config, &["--port", "5", "--forward=c"]);
+        unsafe { std::env::remove_var("IMPISH_CONFIG_TEST_SIZE") };
+        let args = args.unwrap();
+        assert_eq!(args.port, 5);
+        assert_eq!(args.max_size, 4);
+        assert!(args.fsync);
+        // Lists aren't merged; the command line's replaces the file's
+        assert_eq!(args.forward, ["c"]);
+    }
+
+    #[test]
+    fn options_are_checked_together_with_the_file() {
+        // The file can satisfy the required group, and what the command line requires
+        assert!(parse_test_args("base = 'a'", &["--fsync"]).is_ok());
+        assert!(parse_test_args("forward = ['a']", &[]).is_ok());
+
+        let error = parse_test_args("port = 2", &[]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::MissingRequiredArgument);
+        let error = parse_test_args("fsync = true\nforward = ['a']", &[])
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::MissingRequiredArgument);
+        let error = parse_test_args("base = 'a'", &["--stdout"]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+        // A switch turned off in the file isn't given
+        assert!(parse_test_args("base = 'a'\nstdout = false", &[]).is_ok());
+    }
+
+    #[test]
+    fn without_a_file_the_command_line_is_checked_as_usual() {
+        let args = TestArgs::parse_from(["test", "--base=b"]);
+        assert_eq!(args.base.as_deref(), Some("b"));
+        let error = parse_with_config_from::<TestArgs>(["test"].map(OsString::from));
+        assert_eq!(
+            error.err().unwrap().kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        let error = parse_with_config_from::<TestArgs>(
+            ["test", "--port=0", "--base=b"].map(OsString::from),
+        );
+        assert_eq!(error.err().unwrap().kind(), ErrorKind::ValueValidation);
+    }
+}
@@ -0,0 +1,68 @@
+/*
+ * CRC-32 (the IEEE one, as used by gzip, zip, and Ethernet)
+ * for udpcapture's file checksums and command-executor's reply trailers.
+ * Small enough to write here rather than pull in a crate for.
+ * */
+
+const POLYNOMIAL: u32 = 0xedb88320;
+
+/// Remainders for each byte value, worked out at compile time.
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// A running CRC-32 of everything passed to `update`.
+#[derive(Clone, Copy)]
+pub struct Crc32 {
+    // Kept inverted, as the algorithm works on it
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32 { state: u32::MAX }
+    }
+}
+
+impl Crc32 {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.state = TABLE[((self.state ^ b as u32) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    /// The CRC of everything so far.
+    pub fn value(&self) -> u32 {
+        !self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_standard_check_value() {
+        let mut crc = Crc32::default();
+        assert_eq!(crc.value(), 0);
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xcbf43926);
+    }
+}
@@ -0,0 +1,11 @@
+/* Parts of the IMPISH executables which other Rust tools can use too.
+ * The executables themselves are in src/bin and build on this.
+ * */
+pub mod cidr;
+pub mod config;
+pub mod crc32;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod signals;
+pub mod systemd;
+pub mod udpcapture;
@@ -0,0 +1,156 @@
+/*
+ * Serving stats over HTTP for Prometheus to scrape (--metrics-addr),
+ * for udpcapture and command-executor. Only built with the `metrics` feature.
+ * Just enough HTTP for that: each request is answered with every metric
+ * in the text exposition format, whatever its path, and the connection closed.
+ * Requests are answered one at a time on a thread of their own,
+ * so a slow or stuck client only holds up other scrapes, never the program.
+ * */
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Longest we wait on a client before giving up on it.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How Prometheus should treat a metric's value.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    /// Only ever goes up
+    Counter,
+    /// Goes up and down
+    Gauge,
+}
+
+/// One value to serve, with what Prometheus needs to know about it.
+pub struct Metric {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub help: &'static str,
+    pub value: u64,
+}
+
+/// Listen on `addr` and answer scrapes from a thread of our own,
+/// with the metrics `current` gives at the time.
+/// Failures to answer a client are passed to `on_error`.
+/// Returns the address listened on (useful with port 0),
+/// or an error if we can't listen there.
+pub fn serve(
+    addr: SocketAddr,
+    current: impl Fn() -> Vec<Metric> + Send + 'static,
+    on_error: impl Fn(std::io::Error) + Send + 'static,
+) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for conn in listener.incoming() {
+            if let Err(e) = conn.and_then(|c| answer(c, &current())) {
+                on_error(e);
+            }
+        }
+    });
+    Ok(addr)
+}
+
+fn answer(conn: TcpStream, metrics: &[Metric]) -> std::io::Result<()> {
+    conn.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    conn.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    // Read the request up to the blank line ending its headers;
+    // what it asks for doesn't matter
+    let mut reader = BufReader::new(&conn);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+    let body = exposition(metrics);
+    let mut conn = &conn;
+    write!(
+        conn,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// The metrics in Prometheus's text exposition format.
+fn exposition(metrics: &[Metric]) -> String {
+    metrics
+        .iter()
+        .map(|m| {
+            let kind = match m.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            format!(
+                "# HELP {0} {1}\n# TYPE {0} {kind}\n{0} {2}\n",
+                m.name, m.help, m.value
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn scrape(addr: SocketAddr) -> String {
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn scrapes_get_the_current_values() {
+        let count = Arc::new(AtomicU64::new(3));
+        let served = Arc::clone(&count);
+        let addr = serve(
+            "127.0.0.1:0".parse().unwrap(),
+            move || {
+                vec![
+                    Metric {
+                        name: "things_total",
+                        kind: Kind::Counter,
+                        help: "Things seen",
+                        value: served.load(Ordering::Relaxed),
+                    },
+                    Metric {
+                        name: "things_now",
+                        kind: Kind::Gauge,
+                        help: "Things here now",
+                        value: 1,
+                    },
+                ]
+            },
+            |e| panic!("{e}"),
+        )
+        .unwrap();
+
+        let response = scrape(addr);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert_eq!(
+            body,
+            "# HELP things_total Things seen\n\
+             # TYPE things_total counter\n\
+             things_total 3\n\
+             # HELP things_now Things here now\n\
+             # TYPE things_now gauge\n\
+             things_now 1\n"
+        );
+
+        count.store(4, Ordering::Relaxed);
+        assert!(scrape(addr).contains("\nthings_total 4\n"));
+    }
+}
@@ -0,0 +1,55 @@
+/* The signals udpcapture and command-executor catch rather than die of:
+ * SIGINT and SIGTERM ask for a clean shutdown, and SIGUSR1 for the stats.
+ * The handlers only set a flag, which the main loops check.
+ * */
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+
+static SHUTDOWN_REQUESTED: LazyLock<Arc<AtomicBool>> = LazyLock::new(Arc::default);
+static STATS_REQUESTED: LazyLock<Arc<AtomicBool>> = LazyLock::new(Arc::default);
+
+/// Catch SIGINT and SIGTERM so that, rather than the process dying on the spot,
+/// `shutdown_requested` starts returning true.
+/// Child processes don't inherit the handlers; exec resets them.
+pub fn catch_shutdown_signals() {
+    for signum in [SIGINT, SIGTERM] {
+        catch(signum, &SHUTDOWN_REQUESTED);
+    }
+}
+
+/// Catch SIGUSR1, which asks for the stats to be logged,
+/// rather than letting it kill the process.
+pub fn catch_stats_signal() {
+    catch(SIGUSR1, &STATS_REQUESTED);
+}
+
+fn catch(signum: i32, flag: &Arc<AtomicBool>) {
+    if let Err(e) = signal_hook::flag::register(signum, Arc::clone(flag)) {
+        panic!("Cannot install handler for signal {signum}: {e}");
+    }
+}
+
+/// Check if SIGINT or SIGTERM has arrived.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Check if SIGUSR1 has arrived since we last checked.
+pub fn stats_requested() -> bool {
+    STATS_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_signal_is_seen_once() {
+        catch_stats_signal();
+        assert!(!stats_requested());
+        signal_hook::low_level::raise(SIGUSR1).unwrap();
+        assert!(stats_requested());
+        assert!(!stats_requested(), "cleared once seen");
+    }
+}
@@ -0,0 +1,96 @@
+/* Telling systemd how a `Type=notify` service is doing (see man sd_notify):
+ * that it is ready, that it is still alive (for `WatchdogSec=`),
+ * and that it is stopping.
+ * The protocol is one datagram to the unix socket named by $NOTIFY_SOCKET,
+ * so std is enough; no need to link libsystemd.
+ *
+ * Without the `systemd` feature, or when not run by systemd
+ * (no $NOTIFY_SOCKET), every call here does nothing.
+ * */
+use std::time::{Duration, Instant};
+
+/// Tell systemd the service is up, e.g. once its sockets are bound.
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Tell systemd the service is shutting down.
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// Sends `WATCHDOG=1` often enough to keep systemd's watchdog happy,
+/// if it was asked for (`WatchdogSec=`).
+pub struct Watchdog {
+    // None if there's no watchdog to keep happy
+    interval: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Read the watchdog timeout systemd gives us in $WATCHDOG_USEC.
+    pub fn from_env() -> Watchdog {
+        let interval = cfg!(feature = "systemd")
+            .then(watchdog_timeout)
+            .flatten()
+            // Ping at twice the rate needed, as systemd suggests
+            .map(|timeout| timeout / 2);
+        Watchdog {
+            interval,
+            last_ping: None,
+        }
+    }
+
+    /// Call from the main loop, at least every half watchdog timeout;
+    /// sends `WATCHDOG=1` when one is due.
+    pub fn ping(&mut self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        if self.last_ping.is_none_or(|t| t.elapsed() >= interval) {
+            notify("WATCHDOG=1");
+            self.last_ping = Some(Instant::now());
+        }
+    }
+}
+
+/// The watchdog timeout, if systemd set one for this process.
+fn watchdog_timeout() -> Option<Duration> {
+    // WATCHDOG_PID, if set, says which process the watchdog is for
+    if let Ok(pid) = std::env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Send a state change to systemd, if the feature is on and systemd is listening.
+/// Failures are logged, since systemd will notice anyway.
+fn notify(state: &str) {
+    if !cfg!(feature = "systemd") {
+        return;
+    }
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket, state) {
+        log::warn!("Cannot notify systemd ({state}): {e}");
+    }
+}
+
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let path = socket.as_bytes();
+    // A leading @ means an abstract socket, which has no file
+    let addr = match path.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(std::path::Path::new(socket))?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
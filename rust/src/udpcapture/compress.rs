@@ -0,0 +1,103 @@
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+
+/*
+ * Compression of output files as they are written.
+ * The compressed stream goes through a buffer straight to the file,
+ * so flushing the encoder (e.g. for --flush-interval) gets
+ * everything so far into the file, not just into the compressor.
+ * */
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Compression {
+    /// No compression
+    #[default]
+    None,
+    /// gzip, giving `.gz` files
+    Gzip,
+    /// zstd, giving `.zst` files; faster than gzip for a similar size
+    Zstd,
+}
+
+impl Compression {
+    /// Extension added to the file name after `.bin`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// The compression levels the compressor takes, fastest first,
+    /// or None if there's no compressor.
+    pub fn levels(&self) -> Option<RangeInclusive<u32>> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some(1..=9),
+            Compression::Zstd => Some(1..=19),
+        }
+    }
+}
+
+/// A compressed stream being written to a file.
+/// `finish` must be called once all data is written,
+/// or the end of the compressed stream will be missing.
+pub enum Encoder {
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Encoder {
+    /// Start compressing into `file`, at the given level
+    /// (one of `compression.levels()`) or the compressor's default.
+    pub fn new(
+        file: File,
+        compression: Compression,
+        level: Option<u32>,
+    ) -> std::io::Result<Encoder> {
+        let file = BufWriter::new(file);
+        Ok(match compression {
+            Compression::None => unreachable!("uncompressed files have no encoder"),
+            Compression::Gzip => {
+                let level = level.map_or(flate2::Compression::default(), flate2::Compression::new);
+                Encoder::Gzip(GzEncoder::new(file, level))
+            }
+            // Level 0 is zstd's default
+            Compression::Zstd => {
+                Encoder::Zstd(zstd::Encoder::new(file, level.unwrap_or(0) as i32)?)
+            }
+        })
+    }
+
+    /// Write the end of the compressed stream and everything buffered.
+    /// Returns the file, for syncing.
+    pub fn finish(self) -> std::io::Result<File> {
+        let file = match self {
+            Encoder::Gzip(e) => e.finish()?,
+            Encoder::Zstd(e) => e.finish()?,
+        };
+        file.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+        }
+    }
+
+    /// End the compressed block so far and write it to the file,
+    /// so it can be decompressed even if the stream is never finished.
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+        }
+    }
+}
@@ -0,0 +1,9 @@
+/* The rotating capture files udpcapture writes, for use outside udpcapture.
+ * `writer::FileWriter` is the way in: it takes packets one at a time,
+ * and hands back the name of each file it finishes.
+ * What it has to say along the way (files deleted to stay under budget,
+ * links it couldn't make) goes through the `log` crate.
+ * */
+pub mod compress;
+pub mod pcap;
+pub mod writer;
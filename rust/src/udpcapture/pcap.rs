@@ -0,0 +1,338 @@
+use chrono::prelude::*;
+use std::io::{ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/*
+ * Writing captured packets as a pcap file
+ * (https://www.tcpdump.org/manpages/pcap-savefile.5.html)
+ * which tcpdump and wireshark can open directly.
+ *
+ * We only see the UDP payload, so each record gets a made up
+ * IPv4 or IPv6 + UDP header in front of it, addressed from the sender
+ * to our listening port. The file uses the "raw IP" link type,
+ * so there is no Ethernet header.
+ *
+ * Files in that layout can also be read back, for --replay.
+ * */
+
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_RAW: u32 = 101;
+
+const IPPROTO_UDP: u8 = 17;
+const TTL: u8 = 64;
+const UDP_HEADER_LEN: usize = 8;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const GLOBAL_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+/// The header which starts every pcap file.
+/// Integers are written little-endian; readers
+/// tell the byte order from the magic number.
+pub fn global_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(GLOBAL_HEADER_LEN);
+    header.extend(MAGIC_MICROS.to_le_bytes());
+    header.extend(VERSION_MAJOR.to_le_bytes());
+    header.extend(VERSION_MINOR.to_le_bytes());
+    // Time zone offset and timestamp accuracy, both always 0
+    header.extend(0i32.to_le_bytes());
+    header.extend(0u32.to_le_bytes());
+    header.extend(SNAPLEN.to_le_bytes());
+    header.extend(LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+/// One pcap record: the record header, then the payload
+/// wrapped up as a UDP datagram from `src` to `dst`.
+pub fn record(payload: &[u8], time: DateTime<Utc>, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let packet = ip_packet(payload, src, dst);
+    let len = packet.len() as u32;
+    let mut rec = Vec::with_capacity(RECORD_HEADER_LEN + packet.len());
+    rec.extend((time.timestamp() as u32).to_le_bytes());
+    rec.extend(time.timestamp_subsec_micros().to_le_bytes());
+    // Captured and original lengths; nothing is cut off
+    rec.extend(len.to_le_bytes());
+    rec.extend(len.to_le_bytes());
+    rec.extend(packet);
+    rec
+}
+
+/// Build an IP + UDP packet around the payload.
+/// If the two addresses are of different families
+/// (e.g. an IPv4 sender on an IPv6 socket), the destination
+/// address is replaced with the unspecified address of the sender's family.
+fn ip_packet(payload: &[u8], src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    match (src.ip().to_canonical(), dst.ip().to_canonical()) {
+        (IpAddr::V4(s), d) => {
+            let d = match d {
+                IpAddr::V4(d) => d,
+                IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+            };
+            let mut packet = Vec::with_capacity(IPV4_HEADER_LEN + udp_len);
+            packet.push(0x45); // Version 4, 5 word header
+            packet.push(0); // Type of service
+            packet.extend(len_u16(IPV4_HEADER_LEN + udp_len).to_be_bytes());
+            packet.extend(0u16.to_be_bytes()); // Identification
+            packet.extend(0x4000u16.to_be_bytes()); // Don't fragment
+            packet.push(TTL);
+            packet.push(IPPROTO_UDP);
+            packet.extend(0u16.to_be_bytes()); // Header checksum, filled below
+            packet.extend(s.octets());
+            packet.extend(d.octets());
+            let sum = checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+
+            let pseudo_header = [
+                &s.octets()[..],
+                &d.octets()[..],
+                &[0, IPPROTO_UDP],
+                &len_u16(udp_len).to_be_bytes(),
+            ]
+            .concat();
+            packet.extend(udp_datagram(
+                payload,
+                src.port(),
+                dst.port(),
+                &pseudo_header,
+            ));
+            packet
+        }
+        (IpAddr::V6(s), d) => {
+            let d = match d {
+                IpAddr::V6(d) => d,
+                IpAddr::V4(_) => Ipv6Addr::UNSPECIFIED,
+            };
+            let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + udp_len);
+            packet.extend(0x6000_0000u32.to_be_bytes()); // Version 6, no traffic class or flow
+            packet.extend(len_u16(udp_len).to_be_bytes());
+            packet.push(IPPROTO_UDP);
+            packet.push(TTL);
+            packet.extend(s.octets());
+            packet.extend(d.octets());
+
+            let pseudo_header = [
+                &s.octets()[..],
+                &d.octets()[..],
+                &(udp_len as u32).to_be_bytes(),
+                &[0, 0, 0, IPPROTO_UDP],
+            ]
+            .concat();
+            packet.extend(udp_datagram(
+                payload,
+                src.port(),
+                dst.port(),
+                &pseudo_header,
+            ));
+            packet
+        }
+    }
+}
+
+/// UDP header and payload, with the checksum
+/// computed over the given IP pseudo-header.
+fn udp_datagram(payload: &[u8], src_port: u16, dst_port: u16, pseudo_header: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(UDP_HEADER_LEN + payload.len());
+    datagram.extend(src_port.to_be_bytes());
+    datagram.extend(dst_port.to_be_bytes());
+    datagram.extend(len_u16(UDP_HEADER_LEN + payload.len()).to_be_bytes());
+    datagram.extend(0u16.to_be_bytes()); // Checksum, filled below
+    datagram.extend_from_slice(payload);
+
+    // A computed checksum of 0 is sent as all ones
+    let sum = match checksum(&[pseudo_header, &datagram]) {
+        0 => 0xffff,
+        s => s,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    datagram
+}
+
+/// The Internet checksum (RFC 1071) over several pieces of data,
+/// each of which is padded to an even length.
+fn checksum(pieces: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for piece in pieces {
+        for word in piece.chunks(2) {
+            let hi = word[0] as u32;
+            let lo = *word.get(1).unwrap_or(&0) as u32;
+            sum += hi << 8 | lo;
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+    }
+    !(sum as u16)
+}
+
+/// Lengths in IP and UDP headers are 16 bits;
+/// a datagram we received always fits.
+fn len_u16(len: usize) -> u16 {
+    u16::try_from(len).unwrap_or(u16::MAX)
+}
+
+/// Iterate over the records of a pcap file laid out as we write them
+/// (little-endian, microsecond times, raw IP link type),
+/// giving the capture time and UDP payload of each packet.
+/// Other pcap files, and records which aren't UDP, give an error.
+pub fn read<R: Read>(mut reader: R) -> std::io::Result<PcapRecords<R>> {
+    let mut header = [0u8; GLOBAL_HEADER_LEN];
+    reader.read_exact(&mut header).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => invalid("too short to be a pcap file"),
+        _ => e,
+    })?;
+    let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let link_type = u32::from_le_bytes(header[20..].try_into().unwrap());
+    if magic != MAGIC_MICROS || link_type != LINKTYPE_RAW {
+        return Err(invalid("not a pcap file written by udpcapture"));
+    }
+    Ok(PcapRecords { reader })
+}
+
+pub struct PcapRecords<R> {
+    reader: R,
+}
+
+impl<R: Read> Iterator for PcapRecords<R> {
+    type Item = std::io::Result<(DateTime<Utc>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        // A clean end of file between records is the end of iteration
+        match self.reader.read(&mut header[..1]) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(self.read_record(header))
+    }
+}
+
+impl<R: Read> PcapRecords<R> {
+    fn read_record(
+        &mut self,
+        mut header: [u8; RECORD_HEADER_LEN],
+    ) -> std::io::Result<(DateTime<Utc>, Vec<u8>)> {
+        self.reader.read_exact(&mut header[1..])?;
+        let field = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let captured = DateTime::from_timestamp(field(0) as i64, field(4) * 1000)
+            .ok_or(invalid("capture time out of range"))?;
+        let mut packet = vec![0u8; field(8) as usize];
+        self.reader.read_exact(&mut packet)?;
+        Ok((captured, udp_payload(packet)?))
+    }
+}
+
+/// Strip the IP and UDP headers off a packet.
+fn udp_payload(mut packet: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    let (header_len, protocol) = match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= IPV4_HEADER_LEN => ((packet[0] & 0x0f) as usize * 4, packet[9]),
+        Some(6) if packet.len() >= IPV6_HEADER_LEN => (IPV6_HEADER_LEN, packet[6]),
+        _ => return Err(invalid("record is not an IP packet")),
+    };
+    if protocol != IPPROTO_UDP || packet.len() < header_len + UDP_HEADER_LEN {
+        return Err(invalid("record is not a UDP datagram"));
+    }
+    Ok(packet.split_off(header_len + UDP_HEADER_LEN))
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-02T03:04:05.000006Z
+    fn time() -> DateTime<Utc> {
+        DateTime::from_timestamp(1704164645, 6000).unwrap()
+    }
+
+    #[test]
+    fn global_header_layout() {
+        #[rustfmt::skip]
+        let expected = [
+            0xd4, 0xc3, 0xb2, 0xa1, // Magic, little-endian, microseconds
+            0x02, 0x00, 0x04, 0x00, // Version 2.4
+            0x00, 0x00, 0x00, 0x00, // Time zone offset
+            0x00, 0x00, 0x00, 0x00, // Timestamp accuracy
+            0xff, 0xff, 0x00, 0x00, // Snapshot length 65535
+            0x65, 0x00, 0x00, 0x00, // Link type 101, raw IP
+        ];
+        assert_eq!(global_header(), expected);
+    }
+
+    #[test]
+    fn v4_record_layout() {
+        let src = "10.0.0.1:1234".parse().unwrap();
+        let dst = "10.0.0.2:5678".parse().unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            // Record header
+            0x25, 0x7d, 0x93, 0x65, // Seconds
+            0x06, 0x00, 0x00, 0x00, // Microseconds
+            0x1e, 0x00, 0x00, 0x00, // Captured length, 30
+            0x1e, 0x00, 0x00, 0x00, // Original length, 30
+            // IPv4 header
+            0x45, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x40, 0x00,
+            0x40, 0x11, 0x26, 0xcd, // TTL, UDP, header checksum
+            0x0a, 0x00, 0x00, 0x01,
+            0x0a, 0x00, 0x00, 0x02,
+            // UDP header: ports, length, checksum
+            0x04, 0xd2, 0x16, 0x2e, 0x00, 0x0a, 0x68, 0x6e,
+            b'h', b'i',
+        ];
+        assert_eq!(record(b"hi", time(), src, dst), expected);
+    }
+
+    #[test]
+    fn v6_record_layout() {
+        let src = "[fd00::1]:1234".parse().unwrap();
+        let dst = "[fd00::2]:5678".parse().unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            // Record header
+            0x25, 0x7d, 0x93, 0x65, // Seconds
+            0x06, 0x00, 0x00, 0x00, // Microseconds
+            0x32, 0x00, 0x00, 0x00, // Captured length, 50
+            0x32, 0x00, 0x00, 0x00, // Original length, 50
+            // IPv6 header
+            0x60, 0x00, 0x00, 0x00, // Version, traffic class, flow label
+            0x00, 0x0a, 0x11, 0x40, // Payload length, UDP, hop limit
+            0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+            // UDP header: ports, length, checksum
+            0x04, 0xd2, 0x16, 0x2e, 0x00, 0x0a, 0x82, 0x6c,
+            b'h', b'i',
+        ];
+        assert_eq!(record(b"hi", time(), src, dst), expected);
+    }
+
+    #[test]
+    fn records_read_back() {
+        let v4 = "10.0.0.1:1234".parse().unwrap();
+        let v6 = "[fd00::1]:1234".parse().unwrap();
+        let file = [
+            global_header(),
+            record(b"four", time(), v4, v4),
+            // A v4 sender on a v6 socket
+            record(b"mixed", time(), v4, v6),
+            record(b"six", time(), v6, v6),
+        ]
+        .concat();
+        let records: Vec<_> = read(&file[..]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            records,
+            [
+                (time(), b"four".to_vec()),
+                (time(), b"mixed".to_vec()),
+                (time(), b"six".to_vec())
+            ]
+        );
+        assert!(read(&file[4..]).is_err(), "no magic");
+    }
+}
@@ -0,0 +1,1581 @@
+use super::compress::{Compression, Encoder};
+use super::pcap;
+use crate::crc32::Crc32;
+use chrono::prelude::*;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read, Stdout, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How packets are laid out in the output files.
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Payloads back to back, with nothing in between (.bin)
+    #[default]
+    Raw,
+    /// A pcap capture file, readable by tcpdump and wireshark (.pcap)
+    Pcap,
+    /// Each payload preceded by its capture time and length (.bin); see `read_framed`
+    Framed,
+    /// Text for reading by eye: each packet as a hex dump, like `xxd`'s,
+    /// under a line giving its capture time, sender, and length (.txt)
+    Hexdump,
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Raw | OutputFormat::Framed => ".bin",
+            OutputFormat::Pcap => ".pcap",
+            OutputFormat::Hexdump => ".txt",
+        }
+    }
+}
+
+/// Which file `WriterOptions::latest_symlink` points at.
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum LatestFile {
+    /// The file closed most recently
+    #[default]
+    Closed,
+    /// The file being written, under its .part name, until it is closed
+    Open,
+}
+
+/// Where the data for an open file goes:
+/// straight into the file, or through a compressor.
+/// With --stdout it's all one "file" on stdout instead.
+enum Sink {
+    Plain(BufWriter<File>),
+    Compressed(Encoder),
+    Stdout(BufWriter<Stdout>),
+}
+
+impl Sink {
+    /// Write out anything buffered and finish the file off.
+    /// With `fsync`, also wait until the file is on disk.
+    fn finish(self, fsync: bool) -> std::io::Result<()> {
+        let file = match self {
+            Sink::Plain(f) => f.into_inner().map_err(|e| e.into_error())?,
+            Sink::Compressed(e) => e.finish()?,
+            Sink::Stdout(mut s) => return s.flush(),
+        };
+        if fsync {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::Plain(f) => f.write(buf),
+            Sink::Compressed(e) => e.write(buf),
+            Sink::Stdout(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Plain(f) => f.flush(),
+            Sink::Compressed(e) => e.flush(),
+            Sink::Stdout(s) => s.flush(),
+        }
+    }
+}
+
+/// Where a packet came from and when, for the
+/// packet headers of pcap and framed files.
+#[derive(Clone, Copy)]
+pub struct PacketInfo {
+    /// The sender
+    pub from: SocketAddr,
+    /// The local address it arrived on
+    pub to: SocketAddr,
+    /// When it was received
+    pub captured: DateTime<Utc>,
+}
+
+/// Most names make_file_name tries before giving up,
+/// e.g. if the directory is somehow full of files with the names it wants.
+const MAX_NAME_ATTEMPTS: u32 = 1000;
+
+/// The repeat number after `file_inc` in file names,
+/// or an error if it would overflow.
+fn next_file_inc(file_inc: u32) -> std::io::Result<u32> {
+    file_inc.checked_add(1).ok_or_else(|| {
+        std::io::Error::other(
+            "File counter overflowed; use a --filename-format which changes more often",
+        )
+    })
+}
+
+/// strftime pattern for the time in file names when none is given.
+pub const DEFAULT_TIME_FORMAT: &str = "%Y-%j-%H-%M-%S";
+
+/// Where a FileWriter gets the time from; see `FileWriter::with_clock`.
+pub trait Clock {
+    /// Wall-clock time, for file names, `rotate_every`,
+    /// and when files were opened and closed
+    fn now(&self) -> DateTime<Utc>;
+    /// Monotonic time, for file lifetimes and flushing,
+    /// which mustn't change if the wall clock is stepped
+    fn instant(&self) -> Instant;
+}
+
+/// The system's clocks, which FileWriters use unless told otherwise.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Settings for a FileWriter beyond the file name, size, and lifetime.
+/// The defaults write raw, uncompressed files in the working directory.
+#[derive(Default)]
+pub struct WriterOptions {
+    /// Directory to write files in, created if need be [default: the working directory]
+    pub out_dir: Option<PathBuf>,
+    /// strftime pattern for the time in file names [default: DEFAULT_TIME_FORMAT]
+    pub time_format: Option<String>,
+    /// Replaces the format's usual extension (.bin, .pcap), without the dot
+    pub extension: Option<String>,
+    pub compression: Compression,
+    /// Level to compress at, one of `compression.levels()` [default: the compressor's]
+    pub compression_level: Option<u32>,
+    pub format: OutputFormat,
+    /// Delete the oldest files this writer closed once together they are bigger than this
+    pub max_total_bytes: Option<u64>,
+    /// Delete the oldest files this writer closed once there are more than this many
+    pub max_files: Option<usize>,
+    /// Write the CRC-32 of each file, as it is on disk, to `<file>.crc` before it's closed
+    pub checksum: bool,
+    /// Wait for each file to be on disk when it's closed
+    pub fsync: bool,
+    /// Close files at every multiple of this many seconds since midnight UTC, and at midnight
+    pub rotate_every: Option<u64>,
+    /// Drop packets which alone would go over the maximum file size,
+    /// rather than giving them a file of their own
+    pub drop_oversized: bool,
+    /// Flush buffered data to the open file this often
+    pub flush_interval: Option<Duration>,
+    /// JSON-lines file describing each file once it is closed
+    pub manifest: Option<PathBuf>,
+    /// Keep a symlink here to the newest file, as chosen by `latest_file`
+    pub latest_symlink: Option<PathBuf>,
+    pub latest_file: LatestFile,
+    /// Write everything to stdout instead of files. The base file name
+    /// and anything to do with closing files are ignored.
+    pub stdout: bool,
+}
+
+/// A file the writer has finished with,
+/// as handed back by `FileWriter::maybe_write_data` and `FileWriter::close`.
+#[derive(Clone, Debug)]
+pub struct ClosedFile {
+    /// Its final name
+    pub name: String,
+    /// Its size on disk, after any compression
+    pub bytes: u64,
+    /// How many packets were written to it
+    pub packets: u64,
+    pub opened: DateTime<Utc>,
+    pub closed: DateTime<Utc>,
+}
+
+/// Writes packets to a series of files, named
+/// `<base>_<time>_<N><extension>`, each closed once it is full or old enough.
+/// Files are written under a `.part` name and renamed when closed,
+/// so only complete files ever appear under their final names.
+///
+/// ```no_run
+/// use impish_executables::udpcapture::writer::{FileWriter, WriterOptions};
+/// use std::time::Duration;
+///
+/// // Files of up to 1 MB, each kept open for at most a minute
+/// let mut writer = FileWriter::new(
+///     Some("telemetry".to_string()),
+///     Some(1_000_000),
+///     Some(Duration::from_secs(60)),
+///     WriterOptions::default(),
+/// );
+/// for packet in [&b"one"[..], b"two"] {
+///     if let Some(closed) = writer.maybe_write_data(packet, None)? {
+///         println!("{} is ready", closed.name);
+///     }
+/// }
+/// if let Some(closed) = writer.close()? {
+///     println!("{} is ready, with {} packets", closed.name, closed.packets);
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct FileWriter {
+    base_filename: Option<String>,
+    // Wall-clock time the file was opened, for its name and --rotate-on
+    open_time: Option<DateTime<Utc>>,
+    // Monotonic time the file was opened, for its lifetime,
+    // which mustn't change if the clock is stepped
+    opened_at: Option<Instant>,
+    last_flush: Option<Instant>,
+    // A file closed in a call which then failed, to hand back next call
+    closed_unreported: Option<ClosedFile>,
+    // Packets in the open file, for the manifest
+    packets_in_file: u64,
+    // Of what's been written to the open file, for --checksum
+    crc: Crc32,
+    lifetime: Option<Duration>,
+    file: Option<Sink>,
+    opts: WriterOptions,
+    max_file_size: Option<u64>,
+    filename: String,
+    file_inc: u32,
+    data_written: usize,
+    // Files we have closed and their sizes, oldest first
+    closed_files: VecDeque<(String, u64)>,
+    closed_bytes: u64,
+    clock: Box<dyn Clock + Send>,
+}
+
+impl FileWriter {
+    /// Make a writer of files whose names start with `base_fn`.
+    /// Files are closed once they hold `max_size` bytes,
+    /// or have been open for `lifetime`, whichever comes first;
+    /// with neither, a file is only closed by `opts.rotate_every` or `close`.
+    /// Without a `base_fn` (and without `opts.stdout`) nothing is ever written.
+    /// Nothing is opened until the first packet arrives.
+    pub fn new(
+        base_fn: Option<String>,
+        max_size: Option<u64>,
+        lifetime: Option<Duration>,
+        opts: WriterOptions,
+    ) -> FileWriter {
+        FileWriter {
+            base_filename: base_fn,
+            lifetime,
+            open_time: None,
+            opened_at: None,
+            last_flush: None,
+            closed_unreported: None,
+            packets_in_file: 0,
+            crc: Crc32::default(),
+            file: None,
+            opts,
+            max_file_size: max_size,
+            filename: String::new(),
+            file_inc: 0,
+            data_written: 0,
+            closed_files: VecDeque::new(),
+            closed_bytes: 0,
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Take the time from `clock` rather than the system's clocks,
+    /// e.g. to test rotation without waiting for it.
+    pub fn with_clock(mut self, clock: impl Clock + Send + 'static) -> FileWriter {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Writes the given binary data to a buffered file,
+    /// should that file exist, and should its lifetime exist.
+    /// `info` describes the packet the data came from,
+    /// which goes in the packet headers of pcap and framed files.
+    /// Empty data without `info` writes nothing, but still closes the file if it has expired,
+    /// so call this now and then even when there are no packets.
+    /// Empty data with `info` is a zero-length packet, and is written like any other
+    /// (in raw files that adds no bytes, but it counts towards the file's packets).
+    ///
+    /// If the file lifetime expires, it is closed.
+    /// If the data would take the file over its size limit,
+    /// the file is closed first, so the data starts a new one.
+    /// If the file is not open, it is opened with an appropriate name.
+    /// If the file is open, data is written.
+    /// If the file hits its size limit, it is closed.
+    /// Once a file is closed, the oldest closed files are deleted
+    /// until they all fit in the total size budget.
+    ///
+    /// If a file can't be opened, written, or closed (e.g. the disk is full),
+    /// the file is abandoned, what was written of it left under its .part name,
+    /// and the error returned, naming the file. The data is not written.
+    /// The next call starts a new file; what to do meanwhile is up to the caller.
+    /// A file closed before the error is still complete, and is handed back
+    /// by the next call which succeeds (or `close`).
+    ///
+    /// Returns:
+    ///     Option<ClosedFile>: the file, when it gets closed,
+    ///                         None when it remains open.
+    pub fn maybe_write_data(
+        &mut self,
+        data: &[u8],
+        info: Option<PacketInfo>,
+    ) -> std::io::Result<Option<ClosedFile>> {
+        if self.base_filename.is_none() && !self.opts.stdout {
+            // Don't open a file, ever.
+            return Ok(None);
+        }
+        let mut closed = self.closed_unreported.take();
+        match self.write_data(data, info, &mut closed) {
+            Ok(()) => Ok(closed),
+            Err(e) => {
+                self.closed_unreported = closed;
+                Err(self.abandon_file(e))
+            }
+        }
+    }
+
+    /// The work of maybe_write_data.
+    /// The name of any file closed along the way goes in `closed`.
+    /// It may already hold one if the last call failed, which leaves no file open,
+    /// so only closes of an expired file could take its place; those wait till next time.
+    fn write_data(
+        &mut self,
+        data: &[u8],
+        info: Option<PacketInfo>,
+        closed: &mut Option<ClosedFile>,
+    ) -> std::io::Result<()> {
+        if self.opts.stdout {
+            return self.write_stdout(data, info);
+        }
+        self.flush_if_due()?;
+        if data.is_empty() && info.is_none() {
+            if closed.is_none() {
+                *closed = self.close_if_done()?;
+            }
+            return Ok(());
+        }
+        let info = info.unwrap_or_else(|| unknown_packet(self.clock.now()));
+        let record = self.make_record(data, info);
+
+        let max_size = self.max_file_size.unwrap_or(u64::MAX) as usize;
+        if self.opts.drop_oversized && self.file_header().len() + record.len() > max_size {
+            log::warn!(
+                "Dropped a packet from {} which is too big for --max-file-size ({} bytes)",
+                info.from,
+                record.len()
+            );
+            if closed.is_none() {
+                *closed = self.close_if_done()?;
+            }
+            return Ok(());
+        }
+        // Start a new file rather than go over the size limit,
+        // unless this record would be the first in the file anyway
+        if self.file.is_some()
+            && self.data_written > self.file_header().len()
+            && self.data_written + record.len() > max_size
+        {
+            *closed = self.try_close()?;
+        }
+
+        // Get ready to write data if we can
+        if self.file.is_none() {
+            self.open_time = Some(self.clock.now());
+            self.opened_at = Some(self.clock.instant());
+            self.last_flush = self.opened_at;
+            self.filename = self.make_file_name()?;
+            // Written under a temporary name, so nobody watching
+            // the directory picks the file up half-written
+            let file = File::create(partial_name(&self.filename))?;
+            self.file = Some(match self.opts.compression {
+                Compression::None => Sink::Plain(BufWriter::new(file)),
+                c => Sink::Compressed(Encoder::new(file, c, self.opts.compression_level)?),
+            });
+            self.packets_in_file = 0;
+            self.crc = Crc32::default();
+            self.write_bytes(&self.file_header())?;
+            if let LatestFile::Open = self.opts.latest_file {
+                self.point_latest_at(&partial_name(&self.filename));
+            }
+        }
+        self.write_bytes(&record)?;
+        self.packets_in_file += 1;
+
+        // Only one file can be handed back per call;
+        // if the new one is already full it's closed next time
+        if closed.is_none() {
+            *closed = self.close_if_done()?;
+        }
+        Ok(())
+    }
+
+    /// The work of maybe_write_data with --stdout:
+    /// the one "file" is opened with the first packet and never closed.
+    fn write_stdout(&mut self, data: &[u8], info: Option<PacketInfo>) -> std::io::Result<()> {
+        if data.is_empty() && info.is_none() {
+            return Ok(());
+        }
+        let record = self.make_record(
+            data,
+            info.unwrap_or_else(|| unknown_packet(self.clock.now())),
+        );
+        if self.file.is_none() {
+            self.file = Some(Sink::Stdout(BufWriter::new(std::io::stdout())));
+            self.write_bytes(&self.file_header())?;
+        }
+        self.write_bytes(&record)?;
+        // So whatever reads it sees each packet as it arrives
+        self.file.as_mut().unwrap().flush()
+    }
+
+    /// Give up on the open file after a write error,
+    /// leaving what was written of it under its .part name.
+    /// Returns the error, saying which file it was about.
+    fn abandon_file(&mut self, e: std::io::Error) -> std::io::Error {
+        let target = if self.opts.stdout {
+            "stdout".to_string()
+        } else {
+            partial_name(&self.filename)
+        };
+        if let Some(f) = self.file.take() {
+            // Still end a compressed stream, so what was written can be read
+            let _ = f.finish(false);
+        }
+        self.data_written = 0;
+        self.open_time = None;
+        self.opened_at = None;
+        // Keeps its kind, e.g. BrokenPipe once stdout's reader goes away
+        std::io::Error::new(e.kind(), format!("{target}: {e}"))
+    }
+
+    /// Close the file if it's full or expired.
+    fn close_if_done(&mut self) -> std::io::Result<Option<ClosedFile>> {
+        if self.file_full() || self.expired() {
+            return self.try_close();
+        }
+        Ok(None)
+    }
+
+    /// Hand what's buffered for the open file to the OS every --flush-interval,
+    /// so less is lost if we are killed without closing it.
+    fn flush_if_due(&mut self) -> std::io::Result<()> {
+        let (Some(interval), Some(last)) = (self.opts.flush_interval, self.last_flush) else {
+            return Ok(());
+        };
+        if self.clock.instant().saturating_duration_since(last) < interval {
+            return Ok(());
+        }
+        if let Some(f) = &mut self.file {
+            f.flush()?;
+        }
+        self.last_flush = Some(self.clock.instant());
+        Ok(())
+    }
+
+    /// What goes at the start of each file before any packets.
+    fn file_header(&self) -> Vec<u8> {
+        match self.opts.format {
+            OutputFormat::Pcap => pcap::global_header(),
+            OutputFormat::Raw | OutputFormat::Framed | OutputFormat::Hexdump => Vec::new(),
+        }
+    }
+
+    /// The bytes written to the file for one packet.
+    fn make_record<'a>(&self, data: &'a [u8], info: PacketInfo) -> Cow<'a, [u8]> {
+        match self.opts.format {
+            OutputFormat::Raw => Cow::Borrowed(data),
+            OutputFormat::Pcap => Cow::Owned(pcap::record(data, info.captured, info.from, info.to)),
+            OutputFormat::Framed => {
+                let mut record = frame_header(data, info.captured);
+                record.extend_from_slice(data);
+                Cow::Owned(record)
+            }
+            OutputFormat::Hexdump => Cow::Owned(hexdump(data, info).into_bytes()),
+        }
+    }
+
+    /// Closes the open file, if there is one,
+    /// e.g. when it is full or we are shutting down.
+    /// Errors are handled as for maybe_write_data.
+    ///
+    /// Returns:
+    ///     Option<ClosedFile>: the file closed (or one closed
+    ///                         before an error and not yet handed back),
+    ///                         None when no file was open.
+    pub fn close(&mut self) -> std::io::Result<Option<ClosedFile>> {
+        // A failed call leaves no file open
+        if let Some(closed) = self.closed_unreported.take() {
+            return Ok(Some(closed));
+        }
+        self.try_close().map_err(|e| self.abandon_file(e))
+    }
+
+    fn try_close(&mut self) -> std::io::Result<Option<ClosedFile>> {
+        // Take the File and drop it (immediate close).
+        // A compressed file has to be finished first,
+        // so the name handed back is of a complete file.
+        let Some(f) = self.file.take() else {
+            return Ok(None);
+        };
+        let compressed = matches!(f, Sink::Compressed(_));
+        f.finish(self.opts.fsync)?;
+        if self.opts.stdout {
+            // Not a file anyone can be handed
+            return Ok(None);
+        }
+        if self.opts.checksum {
+            // The encoder writes the compressed bytes straight to the file,
+            // so a compressed file is read back for its checksum
+            if compressed {
+                self.crc = file_crc(&partial_name(&self.filename))?;
+            }
+            self.write_checksum()?;
+        }
+        // Renaming is atomic, so the file appears under its
+        // final name only once it is complete
+        std::fs::rename(partial_name(&self.filename), &self.filename)?;
+        let closed = ClosedFile {
+            name: self.filename.clone(),
+            // The size on disk, which differs from data_written if compressed
+            bytes: std::fs::metadata(&self.filename).map_or(0, |m| m.len()),
+            packets: self.packets_in_file,
+            opened: self.open_time.unwrap(),
+            closed: self.clock.now(),
+        };
+        self.add_to_manifest(&closed);
+        self.point_latest_at(&self.filename);
+        self.data_written = 0;
+        // Clear the open times so
+        // self.expired() behaves correctly
+        self.open_time = None;
+        self.opened_at = None;
+        self.track_closed_file(closed.bytes);
+        Ok(Some(closed))
+    }
+
+    /// Write the open file's checksum to its .crc file, in the layout
+    /// of `sha256sum` and friends, `<crc>  <file name>`, and log it.
+    /// It's written before the file gets its final name,
+    /// so is always there by the time the file is.
+    fn write_checksum(&self) -> std::io::Result<()> {
+        let crc = self.crc.value();
+        let name = Path::new(&self.filename)
+            .file_name()
+            .expect("file names aren't empty")
+            .to_string_lossy();
+        std::fs::write(
+            checksum_name(&self.filename),
+            format!("{crc:08x}  {name}\n"),
+        )?;
+        log::info!("CRC-32 of {} is {crc:08x}", self.filename);
+        Ok(())
+    }
+
+    /// Append a line describing the file just closed to the --manifest.
+    /// Failing to is logged, but doesn't stop the file being handed on.
+    fn add_to_manifest(&self, closed: &ClosedFile) {
+        let Some(manifest) = &self.opts.manifest else {
+            return;
+        };
+        let line = ManifestLine {
+            file: &closed.name,
+            opened: closed.opened.to_rfc3339_opts(SecondsFormat::Micros, true),
+            closed: closed.closed.to_rfc3339_opts(SecondsFormat::Micros, true),
+            bytes: closed.bytes,
+            packets: closed.packets,
+        };
+        let mut line = serde_json::to_string(&line).expect("manifest lines serialize");
+        line.push('\n');
+        // Opened each time so the manifest can be moved away between files.
+        // One write per line keeps lines whole if several processes share it.
+        let result = File::options()
+            .create(true)
+            .append(true)
+            .open(manifest)
+            .and_then(|mut f| f.write_all(line.as_bytes()));
+        if let Err(e) = result {
+            log::warn!(
+                "Cannot add {} to {}: {e}",
+                self.filename,
+                manifest.display()
+            );
+        }
+    }
+
+    /// Point the --latest-symlink at `file`, by its absolute path.
+    /// The new link is made beside the old one and renamed over it,
+    /// so whoever follows the link never finds it missing.
+    /// Failing to is logged, but doesn't stop the file being written or handed on.
+    fn point_latest_at(&self, file: &str) {
+        let Some(link) = &self.opts.latest_symlink else {
+            return;
+        };
+        let mut new_link = link.clone().into_os_string();
+        new_link.push(".new");
+        let result = std::path::absolute(file).and_then(|target| {
+            // Left behind if we were killed partway through last time
+            let _ = std::fs::remove_file(&new_link);
+            std::os::unix::fs::symlink(target, &new_link)?;
+            std::fs::rename(&new_link, link)
+        });
+        if let Err(e) = result {
+            log::warn!("Cannot point {} at {file}: {e}", link.display());
+        }
+    }
+
+    fn track_closed_file(&mut self, size: u64) {
+        /* Remember the file just closed, then delete the oldest
+         * files we made until we are back under --max-total-bytes
+         * and --max-files.
+         * The file just closed is never deleted here,
+         * as it is about to be post-processed.
+         * */
+        if self.opts.max_total_bytes.is_none() && self.opts.max_files.is_none() {
+            return;
+        }
+        self.closed_files.push_back((self.filename.clone(), size));
+        self.closed_bytes += size;
+
+        while self.closed_files.len() > 1 {
+            let limit = if self
+                .opts
+                .max_files
+                .is_some_and(|n| self.closed_files.len() > n)
+            {
+                "--max-files"
+            } else if self
+                .opts
+                .max_total_bytes
+                .is_some_and(|budget| self.closed_bytes > budget)
+            {
+                "--max-total-bytes"
+            } else {
+                break;
+            };
+            let (oldest, size) = self.closed_files.pop_front().unwrap();
+            self.closed_bytes -= size;
+            if self.opts.checksum {
+                // Not worth logging; the file's deletion is
+                let _ = std::fs::remove_file(checksum_name(&oldest));
+            }
+            match std::fs::remove_file(&oldest) {
+                Ok(()) => log::info!("Deleted {oldest} ({size} bytes) to stay under {limit}"),
+                // Post-processing may have moved it already
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => log::warn!("Could not delete {oldest} to stay under {limit}: {e}"),
+            }
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        if let Some(dafile) = &mut self.file {
+            dafile.write_all(bytes)?;
+            if self.opts.checksum {
+                self.crc.update(bytes);
+            }
+            // Manually track how much data we write because calling `stream_position` on
+            // a buffered writer causes the buffer to be flushed.
+            // With compression this counts the bytes before they are compressed.
+            self.data_written += bytes.len();
+        }
+        Ok(())
+    }
+
+    fn make_file_name(&mut self) -> std::io::Result<String> {
+        /* Given the "base" file name stored in the struct
+         * (inside the output directory, if there is one),
+         * construct a .bin (or .pcap, or a custom extension) filename
+         * for output (plus .gz etc. if it's compressed) which contains
+         * the date, as well as a repeat number (in case the
+         * same timestamp contains more than one file).
+         * */
+        let time_format = self
+            .opts
+            .time_format
+            .as_deref()
+            .unwrap_or(DEFAULT_TIME_FORMAT);
+        let time_str = format!("{}", self.open_time.unwrap().format(time_format));
+        // This loop should hopefully only need one iteration,
+        // but if a file of the same name is created by a separate process,
+        // we want to not overwrite that one!
+        // So, keeping the loop and the Path::exists call is a good idea.
+        let base = match &self.opts.out_dir {
+            Some(dir) => {
+                // In case the directory was moved away since we started
+                std::fs::create_dir_all(dir)?;
+                dir.join(self.base_filename.as_ref().unwrap())
+                    .to_string_lossy()
+                    .into_owned()
+            }
+            None => self.base_filename.clone().unwrap(),
+        };
+        let fn_start = format!("{}_{}", &base, &time_str);
+        let extension = match &self.opts.extension {
+            Some(ext) => format!(".{ext}"),
+            None => self.opts.format.extension().to_string(),
+        };
+        let compressed = self.opts.compression.extension();
+
+        // If we are creating a file at the same time as a prior one,
+        // increment the counter regardless of whether or not the _N
+        // version exists
+        if (self.filename.len() >= fn_start.len()) && (fn_start == self.filename[..fn_start.len()])
+        {
+            self.file_inc = next_file_inc(self.file_inc)?;
+        } else {
+            self.file_inc = 0;
+        }
+        for _ in 0..MAX_NAME_ATTEMPTS {
+            let maybe_filename =
+                format!("{}_{}{}{}", &fn_start, self.file_inc, extension, compressed);
+            if !Path::new(&maybe_filename).exists()
+                && !Path::new(&partial_name(&maybe_filename)).exists()
+            {
+                return Ok(maybe_filename);
+            }
+            self.file_inc = next_file_inc(self.file_inc)?;
+        }
+        Err(std::io::Error::other(format!(
+            "Cannot find an unused name for {fn_start}_N{extension}{compressed}: \
+             the {MAX_NAME_ATTEMPTS} names tried all exist"
+        )))
+    }
+
+    fn expired(&self) -> bool {
+        /* Check if the current file has been open
+         * longer than it should have been,
+         * or has crossed a rotation boundary.
+         * */
+        if let (Some(ot), Some(opened_at)) = (&self.open_time, self.opened_at) {
+            let lived_out = self
+                .lifetime
+                .is_some_and(|l| self.clock.instant().saturating_duration_since(opened_at) >= l);
+            // Boundaries are on the wall clock, so this one does follow clock steps
+            let crossed_boundary = self.opts.rotate_every.is_some_and(|period| {
+                rotation_slot(self.clock.now(), period) > rotation_slot(*ot, period)
+            });
+            lived_out || crossed_boundary
+        } else {
+            // File not open; not expired
+            false
+        }
+    }
+
+    fn file_full(&mut self) -> bool {
+        self.data_written >= (self.max_file_size.unwrap_or(u64::MAX) as usize)
+    }
+}
+
+/// Where a packet came from, when we weren't told.
+fn unknown_packet(captured: DateTime<Utc>) -> PacketInfo {
+    PacketInfo {
+        from: (Ipv4Addr::UNSPECIFIED, 0).into(),
+        to: (Ipv4Addr::UNSPECIFIED, 0).into(),
+        captured,
+    }
+}
+
+/// A line of the --manifest, for one closed file.
+#[derive(Serialize)]
+struct ManifestLine<'a> {
+    file: &'a str,
+    opened: String,
+    closed: String,
+    bytes: u64,
+    packets: u64,
+}
+
+/// The name of the file holding a file's checksum.
+fn checksum_name(filename: &str) -> String {
+    format!("{filename}.crc")
+}
+
+/// The CRC-32 of a whole file.
+fn file_crc(filename: &str) -> std::io::Result<Crc32> {
+    let mut file = File::open(filename)?;
+    let mut crc = Crc32::default();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(crc),
+            n => crc.update(&buf[..n]),
+        }
+    }
+}
+
+/// Which stretch of `period` seconds, counted from midnight UTC, `time` falls in,
+/// as its day and the stretch's number within the day.
+/// Days start afresh, so a period which doesn't divide a day still rotates at midnight.
+fn rotation_slot(time: DateTime<Utc>, period: u64) -> (i64, i64) {
+    const DAY: i64 = 24 * 60 * 60;
+    let secs = time.timestamp();
+    (secs.div_euclid(DAY), secs.rem_euclid(DAY) / period as i64)
+}
+
+/// The name a file is written under until it is closed.
+fn partial_name(filename: &str) -> String {
+    format!("{filename}.part")
+}
+
+/// Bytes shown on each line of a hex dump.
+const HEXDUMP_WIDTH: usize = 16;
+
+/// A packet as text, e.g.
+///     --- 2024-04-09T12:00:00.012345Z from 10.0.0.5:4000, 18 bytes
+///     00000000: 6865 6c6c 6f2c 2077 6f72 6c64 0a00 0102  hello, world....
+///     00000010: 0304                                     ..
+fn hexdump(data: &[u8], info: PacketInfo) -> String {
+    let mut text = format!(
+        "--- {} from {}, {} bytes\n",
+        info.captured.to_rfc3339_opts(SecondsFormat::Micros, true),
+        info.from,
+        data.len()
+    );
+    for (i, line) in data.chunks(HEXDUMP_WIDTH).enumerate() {
+        let hex: String = line
+            .chunks(2)
+            .map(|pair| pair.iter().map(|b| format!("{b:02x}")).collect::<String>())
+            .collect::<Vec<String>>()
+            .join(" ");
+        let ascii: String = line
+            .iter()
+            .map(|&b| match b {
+                b' '..=b'~' => b as char,
+                _ => '.',
+            })
+            .collect();
+        // Padded so the text lines up on a short last line
+        let hex_width = HEXDUMP_WIDTH * 2 + HEXDUMP_WIDTH / 2 - 1;
+        text.push_str(&format!(
+            "{:08x}: {hex:hex_width$}  {ascii}\n",
+            i * HEXDUMP_WIDTH
+        ));
+    }
+    text
+}
+
+/* Framed files are a series of records, each laid out as
+ *     (i64 capture time, Unix microseconds) + (u32 payload length) + (payload)
+ * with the integers little-endian.
+ * */
+const FRAME_HEADER_LEN: usize = 8 + 4;
+
+fn frame_header(data: &[u8], captured: DateTime<Utc>) -> Vec<u8> {
+    let mut header = Vec::with_capacity(FRAME_HEADER_LEN);
+    header.extend(captured.timestamp_micros().to_le_bytes());
+    header.extend((data.len() as u32).to_le_bytes());
+    header
+}
+
+/// Iterate over the records of a framed file,
+/// giving the capture time and payload of each packet.
+/// A file which ends partway through a record gives an error.
+pub fn read_framed<R: Read>(reader: R) -> FramedRecords<R> {
+    FramedRecords { reader }
+}
+
+pub struct FramedRecords<R> {
+    reader: R,
+}
+
+impl<R: Read> Iterator for FramedRecords<R> {
+    type Item = std::io::Result<(DateTime<Utc>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        // A clean end of file between records is the end of iteration
+        match self.reader.read(&mut header[..1]) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(self.read_record(header))
+    }
+}
+
+impl<R: Read> FramedRecords<R> {
+    fn read_record(
+        &mut self,
+        mut header: [u8; FRAME_HEADER_LEN],
+    ) -> std::io::Result<(DateTime<Utc>, Vec<u8>)> {
+        self.reader.read_exact(&mut header[1..])?;
+        let micros = i64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..].try_into().unwrap());
+        let captured = DateTime::from_timestamp_micros(micros).ok_or(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "capture time out of range",
+        ))?;
+        let mut data = vec![0u8; len as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok((captured, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framed_records_round_trip() {
+        let packets: Vec<(DateTime<Utc>, Vec<u8>)> = vec![
+            (
+                DateTime::from_timestamp_micros(1_700_000_000_123_456).unwrap(),
+                b"first".to_vec(),
+            ),
+            (
+                DateTime::from_timestamp_micros(1_700_000_001_000_001).unwrap(),
+                vec![0; 1000],
+            ),
+            (
+                DateTime::from_timestamp_micros(1_700_000_002_999_999).unwrap(),
+                b"x".to_vec(),
+            ),
+        ];
+        let total: usize = packets
+            .iter()
+            .map(|(_, d)| FRAME_HEADER_LEN + d.len())
+            .sum();
+
+        let base = std::env::temp_dir().join(format!("udpcapture-framed-{}", std::process::id()));
+        let mut writer = FileWriter::new(
+            Some(base.to_string_lossy().into_owned()),
+            Some(total as u64),
+            None,
+            WriterOptions {
+                format: OutputFormat::Framed,
+                ..Default::default()
+            },
+        );
+        let mut closed = None;
+        for (captured, data) in packets.iter() {
+            assert!(closed.is_none(), "file closed before it was full");
+            let info = PacketInfo {
+                from: (Ipv4Addr::LOCALHOST, 1234).into(),
+                to: (Ipv4Addr::LOCALHOST, 5678).into(),
+                captured: *captured,
+            };
+            closed = writer.maybe_write_data(data, Some(info)).unwrap();
+        }
+        let filename = closed.expect("file should close once full").name;
+
+        let read: Vec<(DateTime<Utc>, Vec<u8>)> = read_framed(File::open(&filename).unwrap())
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(read, packets);
+    }
+
+    #[test]
+    fn files_close_once_full() {
+        let dir = test_dir("full-files");
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            Some(10),
+            None,
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                ..Default::default()
+            },
+        );
+        assert!(
+            writer.close().unwrap().is_none(),
+            "nothing was open to close"
+        );
+
+        let mut closed = Vec::new();
+        for packet in [b"12345", b"67890", b"abcde", b"fghij", b"klmno"] {
+            closed.extend(writer.maybe_write_data(packet, None).unwrap());
+        }
+        closed.extend(writer.close().unwrap());
+        let contents: Vec<Vec<u8>> = closed
+            .iter()
+            .map(|f| std::fs::read(&f.name).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            contents,
+            [
+                b"1234567890".to_vec(),
+                b"abcdefghij".to_vec(),
+                b"klmno".to_vec()
+            ]
+        );
+        assert!(closed.iter().all(|f| f.name.ends_with(".bin")));
+        assert_eq!(
+            closed
+                .iter()
+                .map(|f| (f.bytes, f.packets))
+                .collect::<Vec<_>>(),
+            [(10, 2), (10, 2), (5, 1)]
+        );
+    }
+
+    #[test]
+    fn manifest_lines_describe_closed_files() {
+        let dir = test_dir("manifest");
+        let manifest = dir.join("manifest.jsonl");
+        let mut writer = FileWriter::new(
+            Some("quote\"d".to_string()),
+            Some(10),
+            None,
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                manifest: Some(manifest.clone()),
+                ..Default::default()
+            },
+        );
+        let mut closed = Vec::new();
+        for packet in [b"12345", b"67890", b"abcde"] {
+            closed.extend(writer.maybe_write_data(packet, None).unwrap());
+        }
+        closed.extend(writer.close().unwrap());
+        let lines = std::fs::read_to_string(&manifest).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let lines: Vec<serde_json::Value> = lines
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        for (line, file) in lines.iter().zip(&closed) {
+            assert_eq!(line["file"], file.name.as_str());
+            assert_eq!(line["bytes"], file.bytes);
+            assert_eq!(line["packets"], file.packets);
+            assert_eq!(
+                line["closed"],
+                file.closed.to_rfc3339_opts(SecondsFormat::Micros, true)
+            );
+        }
+    }
+
+    #[test]
+    fn no_base_filename_writes_nothing() {
+        let mut writer = FileWriter::new(None, None, None, WriterOptions::default());
+        assert!(writer.maybe_write_data(b"data", None).unwrap().is_none());
+        assert!(writer.close().unwrap().is_none());
+    }
+
+    #[test]
+    fn write_errors_are_returned_and_recovered_from() {
+        let dir = test_dir("unwritable");
+        // Something in the way of the output directory
+        let out_dir = dir.join("out");
+        std::fs::write(&out_dir, b"").unwrap();
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            None,
+            None,
+            WriterOptions {
+                out_dir: Some(out_dir.clone()),
+                ..Default::default()
+            },
+        );
+        assert!(writer.maybe_write_data(b"lost", None).is_err());
+
+        std::fs::remove_file(&out_dir).unwrap();
+        assert!(writer.maybe_write_data(b"kept", None).unwrap().is_none());
+        let filename = writer.close().unwrap().unwrap().name;
+        let written = std::fs::read(&filename).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written, b"kept");
+    }
+
+    #[test]
+    fn clock_step_does_not_close_file_early() {
+        let base = std::env::temp_dir().join(format!("udpcapture-clock-{}", std::process::id()));
+        let mut writer = FileWriter::new(
+            Some(base.to_string_lossy().into_owned()),
+            None,
+            Some(Duration::from_secs(60)),
+            WriterOptions::default(),
+        );
+        assert!(writer.maybe_write_data(b"first", None).unwrap().is_none());
+
+        // The clock is stepped back an hour after the file is opened:
+        // by wall-clock time it has now been open for an hour
+        let stepped = writer.open_time.unwrap() - chrono::Duration::hours(1);
+        writer.open_time = Some(stepped);
+        assert!(
+            writer.maybe_write_data(b"second", None).unwrap().is_none(),
+            "file closed early after a clock step"
+        );
+
+        // Once the lifetime has really passed, it does close
+        writer.opened_at = Some(Instant::now() - Duration::from_secs(60));
+        let filename = writer
+            .maybe_write_data(&[], None)
+            .unwrap()
+            .expect("file should close once its lifetime is up")
+            .name;
+        assert_eq!(std::fs::read(&filename).unwrap(), b"firstsecond");
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn size_only_files_rotate_on_size_alone() {
+        let dir = test_dir("size-only");
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            Some(10),
+            None,
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                ..Default::default()
+            },
+        );
+        assert!(writer.maybe_write_data(b"12345", None).unwrap().is_none());
+
+        // With no lifetime, a file is never closed for being old
+        writer.opened_at = Some(Instant::now() - Duration::from_secs(60));
+        writer.open_time = Some(writer.open_time.unwrap() - chrono::Duration::days(1));
+        assert!(
+            writer.maybe_write_data(&[], None).unwrap().is_none(),
+            "file without a lifetime closed for its age"
+        );
+
+        // ...only once it is full
+        let filename = writer
+            .maybe_write_data(b"67890", None)
+            .unwrap()
+            .expect("file should close once full")
+            .name;
+        let contents = std::fs::read(&filename).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(contents, b"1234567890");
+    }
+
+    #[test]
+    fn compressed_files_round_trip() {
+        let data: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        for compression in Compression::value_variants() {
+            let dir = test_dir(&format!("compress-{compression:?}"));
+            let mut writer = FileWriter::new(
+                Some("name".to_string()),
+                None,
+                None,
+                WriterOptions {
+                    out_dir: Some(dir.clone()),
+                    compression: *compression,
+                    compression_level: compression.levels().and_then(|l| l.max()),
+                    ..Default::default()
+                },
+            );
+            for chunk in data.chunks(1000) {
+                assert!(writer.maybe_write_data(chunk, None).unwrap().is_none());
+            }
+            let filename = writer.close().unwrap().unwrap().name;
+            assert!(filename.ends_with(&format!(".bin{}", compression.extension())));
+
+            let written = std::fs::read(&filename).unwrap();
+            let read = match compression {
+                Compression::None => written,
+                c => {
+                    assert!(written.len() < data.len(), "{c:?} did not compress");
+                    decompress(c, &written)
+                }
+            };
+            std::fs::remove_dir_all(&dir).unwrap();
+            assert!(read == data, "{compression:?} did not round trip");
+        }
+    }
+
+    /// Decompress the whole of a compressed file's contents.
+    fn decompress(compression: &Compression, data: &[u8]) -> Vec<u8> {
+        let mut read = Vec::new();
+        match compression {
+            Compression::None => read.extend(data),
+            Compression::Gzip => {
+                flate2::read::GzDecoder::new(data)
+                    .read_to_end(&mut read)
+                    .unwrap();
+            }
+            Compression::Zstd => read = zstd::decode_all(data).unwrap(),
+        }
+        read
+    }
+
+    #[test]
+    fn flushing_a_compressed_file_reaches_the_file() {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let dir = test_dir(&format!("compress-flush-{compression:?}"));
+            let clock = TestClock::starting_at(START);
+            let mut writer = FileWriter::new(
+                Some("name".to_string()),
+                None,
+                None,
+                WriterOptions {
+                    out_dir: Some(dir.clone()),
+                    compression,
+                    flush_interval: Some(Duration::from_secs(1)),
+                    ..Default::default()
+                },
+            )
+            .with_clock(clock.clone());
+            writer.maybe_write_data(b"first", None).unwrap();
+            clock.advance(1);
+            writer.maybe_write_data(b"second", None).unwrap();
+
+            // The stream isn't finished, but what was flushed can be read;
+            // zstd's decoder wants a finished frame, so it's read a bit at a time
+            let part = std::fs::read(partial_name(&writer.filename)).unwrap();
+            let mut read = vec![0; 5];
+            match compression {
+                Compression::Gzip => flate2::read::GzDecoder::new(&part[..])
+                    .read_exact(&mut read)
+                    .unwrap(),
+                _ => zstd::Decoder::new(&part[..])
+                    .unwrap()
+                    .read_exact(&mut read)
+                    .unwrap(),
+            }
+            assert_eq!(read, b"first", "{compression:?}");
+
+            let filename = writer.close().unwrap().unwrap().name;
+            let written = std::fs::read(&filename).unwrap();
+            std::fs::remove_dir_all(&dir).unwrap();
+            assert_eq!(decompress(&compression, &written), b"firstsecond");
+        }
+    }
+
+    /// A writer whose file names are all `<dir>/name_fixed_N.bin`.
+    fn fixed_name_writer(dir: &Path) -> FileWriter {
+        FileWriter::new(
+            Some("name".to_string()),
+            None,
+            None,
+            WriterOptions {
+                out_dir: Some(dir.to_path_buf()),
+                time_format: Some("fixed".to_string()),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("udpcapture-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn latest_symlink_follows_the_newest_file() {
+        let dir = test_dir("latest");
+        let link = dir.join("latest");
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            Some(4),
+            None,
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                time_format: Some("fixed".to_string()),
+                latest_symlink: Some(link.clone()),
+                latest_file: LatestFile::Open,
+                ..Default::default()
+            },
+        );
+        let target = || std::fs::read_link(&link).unwrap();
+
+        // Full as soon as it's written, so closed right away
+        let first = writer.maybe_write_data(b"full", None).unwrap();
+        assert_eq!(target(), dir.join("name_fixed_0.bin"));
+        assert_eq!(first.unwrap().name, target().to_str().unwrap());
+        writer.maybe_write_data(b"hi", None).unwrap();
+        assert_eq!(target(), dir.join("name_fixed_1.bin.part"));
+        writer.close().unwrap();
+        assert_eq!(target(), dir.join("name_fixed_1.bin"));
+        assert_eq!(std::fs::read(&link).unwrap(), b"hi");
+        assert!(!dir.join("latest.new").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn max_files_keeps_the_newest() {
+        let dir = test_dir("max-files");
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            Some(1),
+            None,
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                time_format: Some("fixed".to_string()),
+                max_files: Some(2),
+                ..Default::default()
+            },
+        );
+        for packet in [b"a", b"b", b"c", b"d"] {
+            let closed = writer.maybe_write_data(packet, None).unwrap();
+            assert!(closed.is_some(), "a full file should close");
+        }
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(left, ["name_fixed_2.bin", "name_fixed_3.bin"]);
+    }
+
+    #[test]
+    fn existing_file_names_are_skipped() {
+        let dir = test_dir("skip");
+        std::fs::write(dir.join("name_fixed_0.bin"), b"").unwrap();
+        std::fs::write(dir.join("name_fixed_1.bin.part"), b"").unwrap();
+
+        let mut writer = fixed_name_writer(&dir);
+        assert!(writer.maybe_write_data(b"data", None).unwrap().is_none());
+        let filename = writer.close().unwrap().unwrap().name;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(Path::new(&filename), dir.join("name_fixed_2.bin"));
+    }
+
+    #[test]
+    fn directory_full_of_matching_names_gives_up() {
+        let dir = test_dir("full");
+        for i in 0..MAX_NAME_ATTEMPTS {
+            std::fs::write(dir.join(format!("name_fixed_{i}.bin")), b"").unwrap();
+        }
+
+        let mut writer = fixed_name_writer(&dir);
+        let result = writer.maybe_write_data(b"data", None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let e = result.expect_err("should give up rather than loop forever");
+        assert!(e.to_string().contains("Cannot find an unused name"), "{e}");
+    }
+
+    /// A clock which only moves when told to, shared by a test and its writer.
+    #[derive(Clone)]
+    struct TestClock(std::sync::Arc<std::sync::Mutex<(DateTime<Utc>, Instant)>>);
+
+    impl TestClock {
+        fn starting_at(unix_secs: i64) -> TestClock {
+            let now = DateTime::from_timestamp(unix_secs, 0).unwrap();
+            TestClock(std::sync::Arc::new(std::sync::Mutex::new((
+                now,
+                Instant::now(),
+            ))))
+        }
+
+        fn advance(&self, secs: u64) {
+            let mut times = self.0.lock().unwrap();
+            times.0 += Duration::from_secs(secs);
+            times.1 += Duration::from_secs(secs);
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0.lock().unwrap().0
+        }
+
+        fn instant(&self) -> Instant {
+            self.0.lock().unwrap().1
+        }
+    }
+
+    /// The name of a file, without its directory.
+    fn base_name(file: &ClosedFile) -> String {
+        Path::new(&file.name)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // 2023-11-14T22:13:20Z, day 318
+    const START: i64 = 1_700_000_000;
+
+    #[test]
+    fn files_close_when_their_lifetime_is_up() {
+        let dir = test_dir("lifetime");
+        let clock = TestClock::starting_at(START);
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            None,
+            Some(Duration::from_secs(10)),
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                ..Default::default()
+            },
+        )
+        .with_clock(clock.clone());
+
+        assert!(writer.maybe_write_data(b"a", None).unwrap().is_none());
+        clock.advance(9);
+        assert!(writer.maybe_write_data(b"b", None).unwrap().is_none());
+        assert!(
+            writer.maybe_write_data(&[], None).unwrap().is_none(),
+            "closed a second early"
+        );
+        clock.advance(1);
+        let first = writer
+            .maybe_write_data(&[], None)
+            .unwrap()
+            .expect("file should close once its lifetime is up");
+
+        // The next packet starts a file named for the new time
+        assert!(writer.maybe_write_data(b"c", None).unwrap().is_none());
+        let second = writer.close().unwrap().unwrap();
+        let contents = [&first, &second].map(|f| std::fs::read(&f.name).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(base_name(&first), "name_2023-318-22-13-20_0.bin");
+        assert_eq!(base_name(&second), "name_2023-318-22-13-30_0.bin");
+        assert_eq!(contents, [b"ab".to_vec(), b"c".to_vec()]);
+        assert_eq!(first.opened.timestamp(), START);
+        assert_eq!(first.closed.timestamp(), START + 10);
+        assert_eq!(first.packets, 2);
+    }
+
+    #[test]
+    fn files_opened_in_the_same_second_count_up() {
+        let dir = test_dir("same-second");
+        let clock = TestClock::starting_at(START);
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            Some(1),
+            None,
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                ..Default::default()
+            },
+        )
+        .with_clock(clock.clone());
+
+        let mut names = Vec::new();
+        for packet in [b"a", b"b", b"c"] {
+            let closed = writer.maybe_write_data(packet, None).unwrap();
+            names.push(base_name(&closed.expect("a full file should close")));
+        }
+        // The count starts again once the time in the name changes
+        clock.advance(1);
+        let closed = writer.maybe_write_data(b"d", None).unwrap();
+        names.push(base_name(&closed.unwrap()));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            names,
+            [
+                "name_2023-318-22-13-20_0.bin",
+                "name_2023-318-22-13-20_1.bin",
+                "name_2023-318-22-13-20_2.bin",
+                "name_2023-318-22-13-21_0.bin",
+            ]
+        );
+    }
+
+    #[test]
+    fn rotate_every_closes_on_the_boundary() {
+        let dir = test_dir("rotate-every");
+        // 20 seconds into a minute
+        let clock = TestClock::starting_at(START);
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            None,
+            None,
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                rotate_every: Some(60),
+                ..Default::default()
+            },
+        )
+        .with_clock(clock.clone());
+
+        assert!(writer.maybe_write_data(b"a", None).unwrap().is_none());
+        clock.advance(39);
+        assert!(
+            writer.maybe_write_data(&[], None).unwrap().is_none(),
+            "closed before the minute was up"
+        );
+        clock.advance(1);
+        let closed = writer
+            .maybe_write_data(&[], None)
+            .unwrap()
+            .expect("file should close at the top of the minute");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(closed.closed.timestamp() % 60, 0);
+        assert_eq!(closed.packets, 1);
+    }
+
+    #[test]
+    fn rotate_every_counts_from_midnight() {
+        let dir = test_dir("rotate-midnight");
+        // Midnight UTC, which isn't a multiple of 7 seconds since the epoch
+        let midnight = 1_699_920_000;
+        let clock = TestClock::starting_at(midnight + 1);
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            None,
+            None,
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                rotate_every: Some(7),
+                ..Default::default()
+            },
+        )
+        .with_clock(clock.clone());
+
+        assert!(writer.maybe_write_data(b"a", None).unwrap().is_none());
+        clock.advance(5);
+        assert!(writer.maybe_write_data(&[], None).unwrap().is_none());
+        clock.advance(1);
+        let closed = writer
+            .maybe_write_data(&[], None)
+            .unwrap()
+            .expect("file should close 7 seconds after midnight");
+        assert_eq!(closed.closed.timestamp(), midnight + 7);
+
+        // 86400 isn't a multiple of 7 either, but midnight is still a boundary
+        clock.advance(86400 - 7 - 2);
+        assert!(writer.maybe_write_data(b"b", None).unwrap().is_none());
+        clock.advance(2);
+        let closed = writer
+            .maybe_write_data(&[], None)
+            .unwrap()
+            .expect("file should close at midnight");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(closed.closed.timestamp(), midnight + 86400);
+    }
+
+    #[test]
+    fn empty_packets_are_written_but_empty_ticks_are_not() {
+        let dir = test_dir("empty");
+        let mut writer = FileWriter::new(
+            Some("name".to_string()),
+            None,
+            None,
+            WriterOptions {
+                out_dir: Some(dir.clone()),
+                format: OutputFormat::Framed,
+                ..Default::default()
+            },
+        );
+        let captured = DateTime::from_timestamp(START, 0).unwrap();
+        let info = PacketInfo {
+            from: (Ipv4Addr::LOCALHOST, 1234).into(),
+            to: (Ipv4Addr::LOCALHOST, 5678).into(),
+            captured,
+        };
+        for (data, info) in [(&b""[..], Some(info)), (b"", None), (b"x", Some(info))] {
+            assert!(writer.maybe_write_data(data, info).unwrap().is_none());
+        }
+        let closed = writer.close().unwrap().unwrap();
+        let read: Vec<(DateTime<Utc>, Vec<u8>)> = read_framed(File::open(&closed.name).unwrap())
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read, [(captured, Vec::new()), (captured, b"x".to_vec())]);
+        assert_eq!(closed.packets, 2);
+    }
+
+    #[test]
+    fn truncated_framed_record_is_an_error() {
+        let mut bytes = frame_header(b"hello", Utc::now());
+        bytes.extend(b"hel");
+        let mut records = read_framed(&bytes[..]);
+        assert!(records.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,372 @@
+/*
+ * End-to-end tests for command-executor.
+ * Each test starts the real binary on a free port, with
+ * HEADER_STAMPER_PORT pointed at a socket the test listens on.
+ * */
+use std::net::UdpSocket;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+const PAYLOAD_SIZE: usize = 512;
+const PACKET_SIZE: usize = PAYLOAD_SIZE + 4 + 1 + 4 + 4;
+// Sent until the executor answers, so we know it's listening
+const PROBE_CMD: &[u8] = b": ready";
+
+struct Executor {
+    child: Child,
+    port: u16,
+    client: UdpSocket,
+    replies: UdpSocket,
+}
+
+/// A reply put back together from its packets.
+struct Reply {
+    request_id: Option<Vec<u8>>,
+    status_code: u8,
+    cmd: Vec<u8>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    num_packets: usize,
+}
+
+impl Executor {
+    fn start(extra_args: &[&str]) -> Executor {
+        let replies = UdpSocket::bind("127.0.0.1:0").unwrap();
+        replies
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        // Grab a free port for the executor to listen on
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let child = Command::new(env!("CARGO_BIN_EXE_command-executor"))
+            .arg("--port")
+            .arg(port.to_string())
+            .args(extra_args)
+            .env(
+                "HEADER_STAMPER_PORT",
+                replies.local_addr().unwrap().port().to_string(),
+            )
+            .spawn()
+            .expect("command-executor should start");
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let exec = Executor {
+            child,
+            port,
+            client,
+            replies,
+        };
+        exec.wait_until_listening();
+        exec
+    }
+
+    fn wait_until_listening(&self) {
+        self.replies
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        for _ in 0..50 {
+            self.send(PROBE_CMD);
+            if self.recv_packet().is_some() {
+                self.replies
+                    .set_read_timeout(Some(Duration::from_secs(10)))
+                    .unwrap();
+                return;
+            }
+        }
+        panic!("command-executor never started listening");
+    }
+
+    fn send(&self, cmd: &[u8]) {
+        self.client.send_to(cmd, ("127.0.0.1", self.port)).unwrap();
+    }
+
+    fn recv_packet(&self) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 2048];
+        let (n, sender) = self.replies.recv_from(&mut buf).ok()?;
+        // Replies must come from the listening socket:
+        // the header stamper identifies them by source port
+        assert_eq!(sender.port(), self.port);
+        assert_eq!(n, PACKET_SIZE);
+        Some(buf[..n].to_vec())
+    }
+
+    /// Receive the next full reply, skipping any left over
+    /// from start-up probes.
+    fn recv_reply(&self) -> Reply {
+        loop {
+            let mut payload = Vec::new();
+            let mut num_packets = 0;
+            loop {
+                let packet = self.recv_packet().expect("reply should arrive");
+                let trailer = &packet[PAYLOAD_SIZE..];
+                let ordering = u32::from_le_bytes(trailer[5..9].try_into().unwrap());
+                let total = u32::from_le_bytes(trailer[9..13].try_into().unwrap());
+                assert_eq!(ordering as usize, num_packets);
+                payload.extend_from_slice(&packet[..PAYLOAD_SIZE]);
+                num_packets += 1;
+                if ordering + 1 == total {
+                    break;
+                }
+            }
+            let reply = Reply::parse(&payload, num_packets);
+            if reply.cmd != PROBE_CMD {
+                return reply;
+            }
+        }
+    }
+}
+
+/// A streamed reply (`--stream`) put back together from its packets.
+struct Streamed {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    // With --line-mode: (stream id, line number, line)
+    lines: Vec<(u8, u32, Vec<u8>)>,
+    status_code: i32,
+    num_packets: usize,
+}
+
+impl Executor {
+    /// Receive the streamed reply to the command sent with `REQ-ID <id>`,
+    /// skipping packets for any other command.
+    fn recv_streamed(&self, id: &[u8]) -> Streamed {
+        let mut counter = None;
+        let mut streamed = Streamed {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            lines: Vec::new(),
+            status_code: -1,
+            num_packets: 0,
+        };
+        loop {
+            let packet = self.recv_packet().expect("streamed packet should arrive");
+            let trailer = &packet[PAYLOAD_SIZE..];
+            let stream = packet[0];
+            let len = u16::from_le_bytes([packet[1], packet[2]]) as usize;
+            let data = &packet[3..3 + len];
+            // The request id is sent first, and tells us which counter is ours
+            if stream == 3 && data == id {
+                counter = Some(trailer[4]);
+            }
+            if counter != Some(trailer[4]) {
+                continue;
+            }
+            let ordering = u32::from_le_bytes(trailer[5..9].try_into().unwrap());
+            assert_eq!(ordering as usize, streamed.num_packets);
+            streamed.num_packets += 1;
+            match stream {
+                0 => {
+                    let rest = data.strip_prefix(b"finished").expect("finished sentinel");
+                    streamed.status_code = i32::from_le_bytes(rest[..4].try_into().unwrap());
+                    let total = u32::from_le_bytes(trailer[9..13].try_into().unwrap());
+                    assert_eq!(total as usize, streamed.num_packets);
+                    return streamed;
+                }
+                1 => streamed.stdout.extend(data),
+                2 => streamed.stderr.extend(data),
+                3 => {}
+                4 | 5 => {
+                    let number = u32::from_le_bytes(data[..4].try_into().unwrap());
+                    streamed.lines.push((stream, number, data[4..].to_vec()));
+                }
+                other => panic!("unknown stream id {other}"),
+            }
+        }
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Reply {
+    fn parse(payload: &[u8], num_packets: usize) -> Reply {
+        let end = payload.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        let mut payload = &payload[..end];
+        let mut request_id = None;
+        if let Some(rest) = payload.strip_prefix(b"REQ-ID ") {
+            let line_end = rest.iter().position(|b| *b == b'\n').unwrap();
+            request_id = Some(rest[..line_end].to_vec());
+            payload = &rest[line_end + 1..];
+        }
+        let sections: Vec<&[u8]> = payload.split(|b| *b == 0x1D).collect();
+        assert_eq!(sections.len(), 4, "reply should have four sections");
+        Reply {
+            request_id,
+            status_code: sections[0].first().copied().unwrap_or(0),
+            cmd: sections[1].to_vec(),
+            stdout: sections[2].to_vec(),
+            stderr: sections[3].to_vec(),
+            num_packets,
+        }
+    }
+}
+
+#[test]
+fn back_to_back_commands_are_not_dropped() {
+    let exec = Executor::start(&["--no-shell"]);
+    exec.send(b"echo\nfirst");
+    exec.send(b"echo\nsecond");
+
+    // Commands run concurrently, so the replies may come back in either order
+    let mut replies = [exec.recv_reply(), exec.recv_reply()];
+    replies.sort_by(|a, b| a.stdout.cmp(&b.stdout));
+    let expected = [&b"first\n"[..], &b"second\n"[..]];
+    for (reply, expected) in replies.iter().zip(expected) {
+        assert_eq!(reply.status_code, 0);
+        assert_eq!(reply.stdout, expected);
+        assert!(reply.stderr.is_empty());
+        assert_eq!(reply.num_packets, 1);
+    }
+}
+
+#[test]
+fn slow_command_does_not_block_fast_one() {
+    let exec = Executor::start(&["--no-shell", "--max-concurrent", "2"]);
+    exec.send(b"sh\n-c\nsleep 2; echo slow");
+    exec.send(b"echo\nfast");
+
+    let first = exec.recv_reply();
+    let second = exec.recv_reply();
+    assert_eq!(first.stdout, b"fast\n");
+    assert_eq!(second.stdout, b"slow\n");
+}
+
+#[test]
+fn combined_mode_interleaves_stdout_and_stderr() {
+    let exec = Executor::start(&["--no-shell", "--combined"]);
+    exec.send(b"sh\n-c\necho out; echo err 1>&2; echo out again");
+
+    let reply = exec.recv_reply();
+    assert_eq!(reply.status_code, 0);
+    assert_eq!(reply.stdout, b"out\nerr\nout again\n");
+    assert!(reply.stderr.is_empty());
+}
+
+#[test]
+fn signal_killed_command_reports_signal() {
+    let exec = Executor::start(&["--no-shell"]);
+    exec.send(b"sh\n-c\nkill -TERM $$");
+
+    // 128 + SIGTERM, as a shell would report it
+    let reply = exec.recv_reply();
+    assert_eq!(reply.status_code, 128 + 15);
+    assert!(reply.stdout.is_empty());
+}
+
+#[test]
+fn cancel_kills_running_command() {
+    let exec = Executor::start(&["--no-shell"]);
+    exec.send(b"REQ-ID sleeper\nsleep\n30");
+    std::thread::sleep(Duration::from_millis(200));
+
+    exec.send(b"CANCEL sleeper");
+    let cancel = exec.recv_reply();
+    assert_eq!(cancel.status_code, 0);
+    assert_eq!(cancel.stdout, b"cancelled");
+
+    // The killed command still gets its own reply
+    let killed = exec.recv_reply();
+    assert_eq!(killed.request_id.as_deref(), Some(&b"sleeper"[..]));
+    assert_eq!(killed.status_code, 128 + 9);
+
+    exec.send(b"CANCEL sleeper\n");
+    let again = exec.recv_reply();
+    assert_eq!(again.status_code, 1);
+    assert_eq!(again.stderr, b"no-such-request");
+}
+
+#[test]
+fn request_id_is_echoed_back() {
+    let exec = Executor::start(&["--no-shell"]);
+    exec.send(b"REQ-ID 4f1c-a\necho\nwith id");
+    exec.send(b"echo\nwithout id");
+
+    let mut replies = [exec.recv_reply(), exec.recv_reply()];
+    replies.sort_by(|a, b| a.stdout.cmp(&b.stdout));
+    assert_eq!(replies[0].stdout, b"with id\n");
+    assert_eq!(replies[0].cmd, b"echo\nwith id");
+    assert_eq!(replies[0].request_id.as_deref(), Some(&b"4f1c-a"[..]));
+    assert_eq!(replies[1].stdout, b"without id\n");
+    assert_eq!(replies[1].request_id, None);
+}
+
+#[test]
+fn failing_command_reports_status_and_stderr() {
+    let exec = Executor::start(&["--no-shell"]);
+    exec.send(b"sh\n-c\necho partial; echo oops >&2; exit 3");
+
+    let reply = exec.recv_reply();
+    assert_eq!(reply.status_code, 3);
+    assert_eq!(reply.stdout, b"partial\n");
+    assert_eq!(reply.stderr, b"oops\n");
+}
+
+#[test]
+fn large_output_spans_several_packets() {
+    let exec = Executor::start(&["--no-shell"]);
+    exec.send(b"seq\n1\n1000");
+
+    let expected: String = (1..=1000).map(|n| format!("{n}\n")).collect();
+    let reply = exec.recv_reply();
+    assert_eq!(reply.status_code, 0);
+    assert_eq!(reply.stdout, expected.as_bytes());
+    // Status code, cmd, and three separators as well as the output
+    let reply_len = 1 + 1 + b"seq\n1\n1000".len() + 1 + expected.len() + 1;
+    assert_eq!(reply.num_packets, reply_len.div_ceil(PAYLOAD_SIZE));
+    assert!(reply.num_packets > 1);
+}
+
+#[test]
+fn streamed_reply_ends_with_finished_and_status() {
+    let exec = Executor::start(&["--no-shell", "--stream"]);
+    exec.send(b"REQ-ID s1\nsh\n-c\nseq 1 300; echo err >&2; exit 5");
+
+    let streamed = exec.recv_streamed(b"s1");
+    let expected: String = (1..=300).map(|n| format!("{n}\n")).collect();
+    assert_eq!(streamed.stdout, expected.as_bytes());
+    assert_eq!(streamed.stderr, b"err\n");
+    assert_eq!(streamed.status_code, 5);
+    // The request id, at least two packets of output, and the sentinel
+    assert!(streamed.num_packets >= 4);
+}
+
+#[test]
+fn line_mode_sends_each_line_numbered() {
+    let exec = Executor::start(&["--no-shell", "--stream", "--line-mode"]);
+    exec.send(
+        b"REQ-ID l1\nsh\n-c\necho one; echo oops >&2; echo two; head -c 600 /dev/zero | tr '\\0' x",
+    );
+
+    let streamed = exec.recv_streamed(b"l1");
+    assert_eq!(streamed.status_code, 0);
+    let mut stdout: Vec<_> = streamed.lines.iter().filter(|l| l.0 == 4).collect();
+    let stderr: Vec<_> = streamed.lines.iter().filter(|l| l.0 == 5).collect();
+    assert_eq!(stderr, [&(5, 1, b"oops\n".to_vec())]);
+    // 600 bytes with no newline come in two pieces
+    let long = stdout.split_off(2);
+    assert_eq!(
+        stdout,
+        [&(4, 1, b"one\n".to_vec()), &(4, 2, b"two\n".to_vec())]
+    );
+    assert_eq!((long[0].1, long[0].2.len()), (3, 505));
+    assert_eq!((long[1].1, long[1].2.len()), (4, 95));
+    assert!(long.iter().all(|l| l.2.iter().all(|b| *b == b'x')));
+}
+
+#[test]
+fn nul_framing_passes_command_verbatim() {
+    let exec = Executor::start(&["--no-shell", "--framing", "nul"]);
+    // With newline framing, the command would be taken for a second id
+    exec.send(b"REQ-ID n1\0printf\nREQ-ID n2\\nline 2\n");
+
+    let reply = exec.recv_reply();
+    assert_eq!(reply.request_id.as_deref(), Some(&b"n1"[..]));
+    assert_eq!(reply.cmd, b"printf\nREQ-ID n2\\nline 2\n");
+    assert_eq!(reply.stdout, b"REQ-ID n2\nline 2");
+}